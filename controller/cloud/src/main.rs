@@ -1,5 +1,16 @@
 use clap::Parser;
-use color_eyre::{Report, Result};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use controller::auth::{AuthConfig, TlsConfig};
+use controller::broker::BrokerConfig;
+use controller::commit::CommitConfig;
+use controller::history::HistoryConfig;
+use controller::leader::LeaderConfig;
+use controller::locality::{LocalityConfig, LocalityFallback};
+use controller::logs::LogConfig;
+use controller::queue::{OverflowPolicy, QueueConfig};
+use controller::session::{HeartbeatConfig, LeaseConfig};
+use controller::snapshot::SnapshotConfig;
+use std::path::PathBuf;
 use tracing::Level;
 use tracing_subscriber::{filter, prelude::*};
 
@@ -11,6 +22,90 @@ struct Args {
     grpc: String,
     #[clap(short, default_value = "127.0.0.1:1883")]
     mqtt: String,
+    /// Start an embedded MQTT broker listening on this address
+    #[clap(short, long)]
+    broker: Option<String>,
+    /// Namespace to watch, can be repeated. Watch all namespaces if not set
+    #[clap(short, long)]
+    namespace: Vec<String>,
+    /// Label selector of watched Scripts
+    #[clap(long)]
+    script_selector: Option<String>,
+    /// Label selector of watched Devices
+    #[clap(long)]
+    device_selector: Option<String>,
+    /// Run Lease based leader election, the identity is taken from POD_NAME or HOSTNAME
+    #[clap(long)]
+    leader_election: bool,
+    /// Namespace of the leader election Lease
+    #[clap(long, default_value = "default")]
+    lease_namespace: String,
+    /// Label set to "true" on the pod of the leader, the pod must be in the Lease namespace
+    #[clap(long)]
+    leader_label: Option<String>,
+    /// Persist the Reflector to this file and load it at startup
+    #[clap(long)]
+    snapshot: Option<PathBuf>,
+    /// Interval between two Reflector snapshots in seconds
+    #[clap(long, default_value = "30")]
+    snapshot_interval: u64,
+    /// Pending runs kept in the queue, lower priority runs are preempted when it is full
+    #[clap(long, default_value = "100")]
+    queue_capacity: usize,
+    /// Seconds after which a pending run is promoted one priority class, 0 to disable
+    #[clap(long, default_value = "30")]
+    queue_aging: u64,
+    /// What to do when the queue is full: block, drop-oldest, drop-newest or coalesce
+    #[clap(long, default_value = "block")]
+    queue_overflow: OverflowPolicy,
+    /// Queue every trigger as its own run instead of merging triggers of a queued Script
+    #[clap(long)]
+    no_coalesce: bool,
+    /// Where a run goes when no executor on the node of its devices can take it: any, wait or never
+    #[clap(long, default_value = "wait")]
+    locality_fallback: LocalityFallback,
+    /// Seconds a run waits for an executor on the node of its devices with the wait fallback, with never it is rejected after it if none is connected
+    #[clap(long, default_value = "5")]
+    locality_wait: u64,
+    /// Runs kept per Script for the run history api, 0 to disable
+    #[clap(long, default_value = "20")]
+    history_size: usize,
+    /// Console lines of executed runs kept per Script for the run log api, 0 to disable
+    #[clap(long, default_value = "1000")]
+    log_lines: usize,
+    /// Seconds a run may go without a heartbeat of its executor before it is considered lost, 0 for no deadline
+    #[clap(long, default_value = "600")]
+    run_timeout: u64,
+    /// Times a run of an at-least-once Script is sent before it is given up
+    #[clap(long, default_value = "3")]
+    max_attempts: u32,
+    /// Seconds between two heartbeats of an executor connection, 0 to disable
+    #[clap(long, default_value = "10")]
+    heartbeat_interval: u64,
+    /// Heartbeats an executor may miss before it is evicted
+    #[clap(long, default_value = "3")]
+    heartbeat_misses: u32,
+    /// Seconds an AtLeastOnce or OnlyOnce device write waits for the device to report the desired values
+    #[clap(long, default_value = "30")]
+    commit_timeout: u64,
+    /// PEM certificate chain to serve grpc over TLS
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the grpc certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA bundle, executors must present a client certificate signed by it
+    #[clap(long, requires = "tls-cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// Authenticate executors by bearer tokens with the TokenReview api
+    #[clap(long)]
+    token_review: bool,
+    /// Audience the bearer tokens must be valid for, can be repeated
+    #[clap(long)]
+    token_audience: Vec<String>,
+    /// Certificate common name or token user name allowed to connect, can be repeated. Allow any authenticated executor if not set
+    #[clap(long)]
+    allow_identity: Vec<String>,
 }
 
 fn main() -> Result<()> {
@@ -32,8 +127,73 @@ fn main() -> Result<()> {
         webaddr: opt.web.parse()?,
         grpcaddr: opt.grpc.parse()?,
         mqttaddr: opt.mqtt.parse()?,
+        broker: match opt.broker {
+            Some(listen) => Some(BrokerConfig {
+                listen: listen.parse()?,
+                ..Default::default()
+            }),
+            None => None,
+        },
+        namespaces: opt.namespace,
+        script_selector: opt.script_selector,
+        device_selector: opt.device_selector,
+        leader_election: if opt.leader_election {
+            let identity = std::env::var("POD_NAME")
+                .or_else(|_| std::env::var("HOSTNAME"))
+                .wrap_err("Leader election needs POD_NAME or HOSTNAME")?;
+            Some(LeaderConfig {
+                leader_label: opt.leader_label,
+                ..LeaderConfig::new(opt.lease_namespace, identity)
+            })
+        } else {
+            None
+        },
+        snapshot: opt.snapshot.map(|path| SnapshotConfig {
+            path,
+            interval_secs: opt.snapshot_interval,
+        }),
+        queue: QueueConfig {
+            capacity: opt.queue_capacity,
+            aging_secs: opt.queue_aging,
+            overflow: opt.queue_overflow,
+            coalesce: !opt.no_coalesce,
+        },
+        locality: LocalityConfig {
+            fallback: opt.locality_fallback,
+            wait_secs: opt.locality_wait,
+        },
+        history: HistoryConfig {
+            capacity: opt.history_size,
+        },
+        logs: LogConfig {
+            lines: opt.log_lines,
+        },
+        lease: LeaseConfig {
+            duration_secs: opt.run_timeout,
+            max_attempts: opt.max_attempts,
+        },
+        heartbeat: HeartbeatConfig {
+            interval_secs: opt.heartbeat_interval,
+            max_missed: opt.heartbeat_misses,
+        },
+        auth: AuthConfig {
+            tls: opt.tls_cert.zip(opt.tls_key).map(|(cert, key)| TlsConfig {
+                cert,
+                key,
+                client_ca: opt.tls_client_ca,
+            }),
+            token_review: opt.token_review,
+            audiences: opt.token_audience,
+            allowed: opt.allow_identity,
+        },
+        commit: CommitConfig {
+            confirm_secs: opt.commit_timeout,
+            ..Default::default()
+        },
     };
 
+    let embedded = config.broker.is_some();
+
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -41,9 +201,15 @@ fn main() -> Result<()> {
     rt.block_on(async move {
         let mut ctl = controller::controller::Controller::new(config)?;
         let client = kube::Client::try_default().await?;
-        let (schin, _schdevin, schout, store) = ctl.spawn_kubeapi(client.clone(), true);
-        ctl.spawn_webserver(schin, store);
-        ctl.spawn_grpc(client, schout);
+        let (schin, schdevin, schout, store) = ctl.spawn_kubeapi(client.clone(), true);
+        if embedded {
+            #[cfg(feature = "embedded-broker")]
+            ctl.spawn_broker();
+            ctl.spawn_mqtt(schdevin, store.clone());
+        }
+        ctl.spawn_leader_election(client.clone());
+        ctl.spawn_webserver(schin, store.clone());
+        ctl.spawn_grpc(client, schout, store);
         ctl.run().await?;
         Ok::<_, Report>(())
     })?;
@@ -3,7 +3,10 @@ use kube_derive::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::fmt;
-use std::{collections::BTreeMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
 
 // TODO: add printcolumn: https://kubernetes.io/docs/tasks/extend-kubernetes/custom-resources/custom-resource-definitions/#additional-printer-columns
 /// DeviceSpec represents a single device instance. It is an instantation of a device model.
@@ -42,6 +45,21 @@ pub struct DeviceSpec {
     pub node_selector: v1::NodeSelector,
 }
 
+impl Device {
+    /// Reported twin values keyed by property name
+    pub fn reported(&self) -> HashMap<String, String> {
+        let mut result = HashMap::new();
+        if let Some(s) = &self.status {
+            for twin in &s.twins {
+                if let Some(val) = &twin.reported {
+                    result.insert(twin.property_name.to_owned(), val.value.to_owned());
+                }
+            }
+        }
+        result
+    }
+}
+
 impl Display for Device {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         writeln!(
@@ -18,6 +18,19 @@ pub struct DeviceTwinUpdate {
     twin: HashMap<String, MsgTwin>,
 }
 
+impl DeviceTwinUpdate {
+    /// Actual (reported) values keyed by property name
+    pub fn reported_values(&self) -> HashMap<String, String> {
+        self.twin
+            .iter()
+            .filter_map(|(k, t)| {
+                let value = t.actual.as_ref()?.value.clone()?;
+                Some((k.to_owned(), value))
+            })
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct MsgTwin {
     expected: Option<TwinValue>,
@@ -79,5 +92,8 @@ fn mqtt_device_twin_update_type() {
         }
     }"#;
 
-    let _: DeviceTwinUpdate = serde_json::from_str(&msg).unwrap();
+    let update: DeviceTwinUpdate = serde_json::from_str(&msg).unwrap();
+    let reported = update.reported_values();
+    assert_eq!(reported.keys().collect::<Vec<_>>(), vec!["temperature"]);
+    assert_eq!(reported["temperature"], "42");
 }
@@ -26,6 +26,12 @@ pub struct ScriptSpec {
     pub manifest: Manifest,
     /// controller side policy of executing script.
     pub execute_policy: Policy,
+    /// priority class of the script runs
+    #[serde(default)]
+    pub priority: Priority,
+    /// don't start new runs, running runs are cancelled
+    #[serde(default)]
+    pub suspend: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
@@ -39,6 +45,9 @@ pub struct ScriptStatus {
     pub status: i32,
     /// executing message
     pub message: String,
+    /// JSON text of the value returned by main() in the last run
+    #[serde(default)]
+    pub output: Option<String>,
 }
 
 /// A device selector set is a map from names of device or device set used in rule script,
@@ -120,6 +129,22 @@ pub struct Policy {
     pub qos: QosPolicy,
 }
 
+/// Runs of higher priority are dispatched first
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub enum QosPolicy {
     OnlyOnce = 0,
@@ -0,0 +1,254 @@
+//! Authentication of executors
+//!
+//! The grpc server is served over TLS when `tls` is set. An executor is identified by
+//! the common name of its client certificate when `client_ca` is set, or by a bearer
+//! token validated with the TokenReview api, e.g. the token of its ServiceAccount.
+//! Every RPC is authenticated, reviewed tokens are cached for `TOKEN_TTL`.
+
+use color_eyre::{eyre::eyre, Result};
+use dashmap::DashMap;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tonic::transport::{Certificate, ServerTlsConfig};
+use tonic::{Request, Status};
+use tracing::{error, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+const AUTHORIZATION: &str = "authorization";
+const TOKEN_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// serve grpc over TLS
+    pub tls: Option<TlsConfig>,
+    /// validate bearer tokens with the TokenReview api
+    pub token_review: bool,
+    /// audiences a token must be valid for, the api server's default if empty
+    pub audiences: Vec<String>,
+    /// identities allowed to connect, any authenticated identity if empty
+    pub allowed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain of the server
+    pub cert: PathBuf,
+    /// PEM private key of the server
+    pub key: PathBuf,
+    /// PEM CA bundle, executors must present a certificate signed by it if set
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub async fn server_config(&self) -> Result<ServerTlsConfig> {
+        let cert = tokio::fs::read(&self.cert).await?;
+        let key = tokio::fs::read(&self.key).await?;
+        let mut tls =
+            ServerTlsConfig::new().identity(tonic::transport::Identity::from_pem(cert, key));
+        if let Some(ca) = &self.client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(tokio::fs::read(ca).await?));
+        }
+        Ok(tls)
+    }
+}
+
+/// Who is calling the controller
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    /// no authentication is configured
+    Anonymous,
+    /// common name of a verified client certificate
+    Certificate(String),
+    /// user name of a reviewed bearer token
+    Token(String),
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        match self {
+            Identity::Anonymous => "",
+            Identity::Certificate(name) | Identity::Token(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Anonymous => write!(f, "anonymous"),
+            Identity::Certificate(name) => write!(f, "cert:{}", name),
+            Identity::Token(name) => write!(f, "token:{}", name),
+        }
+    }
+}
+
+/// Common name of the subject of a DER certificate
+fn common_name(der: &[u8]) -> Result<String> {
+    let (_, cert) =
+        X509Certificate::from_der(der).map_err(|e| eyre!("Invalid client certificate: {}", e))?;
+    let name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .ok_or_else(|| eyre!("Client certificate has no common name"))?;
+    Ok(name.to_owned())
+}
+
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+}
+
+/// What a request presented to identify its caller
+enum Credential {
+    Identity(Result<Identity, Status>),
+    Token(String),
+}
+
+pub struct Authenticator {
+    config: AuthConfig,
+    client: Client,
+    /// reviewed tokens and when they were reviewed
+    tokens: DashMap<String, (Identity, Instant)>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig, client: Client) -> Self {
+        Authenticator {
+            config,
+            client,
+            tokens: Default::default(),
+        }
+    }
+
+    /// Identify the caller of a RPC
+    pub fn authenticate<T>(
+        &self,
+        request: &Request<T>,
+    ) -> impl Future<Output = Result<Identity, Status>> + Send + '_ {
+        // the request isn't held across the review, a streaming request isn't Sync
+        let credential = self.credential(request);
+        async move {
+            let identity = match credential {
+                Credential::Identity(identity) => identity?,
+                Credential::Token(token) => self.review(&token).await?,
+            };
+            if !self.config.allowed.is_empty()
+                && !self.config.allowed.iter().any(|a| a == identity.name())
+            {
+                warn!(identity = %identity, "Identity is not allowed");
+                return Err(Status::permission_denied(format!(
+                    "{} is not allowed",
+                    identity
+                )));
+            }
+            Ok(identity)
+        }
+    }
+
+    fn credential<T>(&self, request: &Request<T>) -> Credential {
+        // the TLS handshake already verified the chain against `client_ca`
+        if let Some(certs) = request.peer_certs() {
+            if let Some(cert) = certs.first() {
+                return Credential::Identity(
+                    common_name(cert.get_ref())
+                        .map(Identity::Certificate)
+                        .map_err(|e| Status::unauthenticated(e.to_string())),
+                );
+            }
+        }
+        if self.config.token_review {
+            return match bearer_token(request) {
+                Some(token) => Credential::Token(token.to_owned()),
+                None => Credential::Identity(Err(Status::unauthenticated("Missing bearer token"))),
+            };
+        }
+        if self
+            .config
+            .tls
+            .as_ref()
+            .map_or(false, |t| t.client_ca.is_some())
+        {
+            return Credential::Identity(Err(Status::unauthenticated(
+                "Missing client certificate",
+            )));
+        }
+        Credential::Identity(Ok(Identity::Anonymous))
+    }
+
+    async fn review(&self, token: &str) -> Result<Identity, Status> {
+        if let Some(cached) = self.tokens.get(token) {
+            if cached.1.elapsed() < TOKEN_TTL {
+                return Ok(cached.0.clone());
+            }
+        }
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.to_owned()),
+                audiences: (!self.config.audiences.is_empty())
+                    .then(|| self.config.audiences.clone()),
+            },
+            ..Default::default()
+        };
+        let api: Api<TokenReview> = Api::all(self.client.clone());
+        let review = api
+            .create(&PostParams::default(), &review)
+            .await
+            .map_err(|e| {
+                error!(error =? e, "Failed to review token");
+                Status::unavailable("Failed to review token")
+            })?;
+        let status = review.status.unwrap_or_default();
+        if status.authenticated != Some(true) {
+            return Err(Status::unauthenticated(
+                status.error.unwrap_or_else(|| "Invalid token".to_owned()),
+            ));
+        }
+        let name = status
+            .user
+            .and_then(|u| u.username)
+            .ok_or_else(|| Status::unauthenticated("Token has no user name"))?;
+        let identity = Identity::Token(name);
+        self.tokens
+            .retain(|_, (_, reviewed)| reviewed.elapsed() < TOKEN_TTL);
+        self.tokens
+            .insert(token.to_owned(), (identity.clone(), Instant::now()));
+        Ok(identity)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bearer_token() {
+        let mut request = Request::new(());
+        assert_eq!(bearer_token(&request), None);
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION, "Bearer abc ".parse().unwrap());
+        assert_eq!(bearer_token(&request), Some("abc"));
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&request), None);
+        assert_eq!(
+            Identity::Token("system:serviceaccount:ruleengine:executor".to_owned()).to_string(),
+            "token:system:serviceaccount:ruleengine:executor"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Embedded MQTT broker
+//!
+//! For single-box deployments and tests the controller can start its own
+//! broker instead of relying on a separately deployed rumqttd.
+//! The broker itself is only available with the `embedded-broker` feature,
+//! the configuration is always present so that config files stay portable.
+
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+/// Listeners and limits of the embedded broker, see `config/rumqttd.conf`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BrokerConfig {
+    /// address of the MQTT listener
+    pub listen: SocketAddr,
+    /// address of the broker console
+    pub console: SocketAddr,
+    /// directory of the broker commitlog
+    pub dir: PathBuf,
+    pub max_segment_size: usize,
+    pub max_segment_count: usize,
+    pub max_connections: usize,
+    pub connection_timeout_ms: u16,
+    pub max_client_id_len: usize,
+    pub max_payload_size: usize,
+    pub max_inflight_count: u16,
+    pub max_inflight_size: usize,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 1883)),
+            console: SocketAddr::from((Ipv4Addr::LOCALHOST, 3030)),
+            dir: PathBuf::from("/tmp/rumqttd"),
+            max_segment_size: 10240,
+            max_segment_count: 10,
+            max_connections: 10001,
+            connection_timeout_ms: 5000,
+            max_client_id_len: 256,
+            max_payload_size: 5120,
+            max_inflight_count: 200,
+            max_inflight_size: 1024,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Address used by local clients to reach the broker.
+    /// An unspecified listen address is mapped to loopback.
+    pub fn local_addr(&self) -> SocketAddr {
+        let mut addr = self.listen;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        addr
+    }
+}
+
+#[cfg(feature = "embedded-broker")]
+mod embedded {
+    use super::BrokerConfig;
+    use color_eyre::{eyre::eyre, Result};
+    use librumqttd::{Broker, Config};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tracing::{info, trace};
+
+    impl BrokerConfig {
+        /// Translate to the librumqttd configuration, same layout as `config/rumqttd.conf`
+        fn to_librumqttd(&self) -> Result<Config> {
+            let config = serde_json::json!({
+                "id": 0,
+                "router": {
+                    "id": 0,
+                    "dir": self.dir,
+                    "max_segment_size": self.max_segment_size,
+                    "max_segment_count": self.max_segment_count,
+                    "max_connections": self.max_connections,
+                },
+                "servers": {
+                    "1": {
+                        "listen": self.listen,
+                        "next_connection_delay_ms": 1,
+                        "connections": {
+                            "connection_timeout_ms": self.connection_timeout_ms,
+                            "max_client_id_len": self.max_client_id_len,
+                            "throttle_delay_ms": 0,
+                            "max_payload_size": self.max_payload_size,
+                            "max_inflight_count": self.max_inflight_count,
+                            "max_inflight_size": self.max_inflight_size,
+                        }
+                    }
+                },
+                "console": {
+                    "listen": self.console,
+                }
+            });
+            Ok(serde_json::from_value(config)?)
+        }
+    }
+
+    /// Run the embedded broker until it fails.
+    /// The broker is blocking, so it lives on the blocking thread pool.
+    #[tracing::instrument(skip_all)]
+    pub async fn embedded_broker(config: BrokerConfig) -> Result<()> {
+        let broker_config = config.to_librumqttd()?;
+        info!("Embedded MQTT broker listening on {}", config.listen);
+        tokio::task::spawn_blocking(move || {
+            let mut broker = Broker::new(broker_config);
+            broker
+                .start()
+                .map_err(|e| eyre!("Embedded MQTT broker is down: {:?}", e))
+        })
+        .await?
+    }
+
+    /// Wait until the broker accepts TCP connections
+    #[tracing::instrument]
+    pub async fn wait_for_broker(addr: SocketAddr) -> Result<()> {
+        const RETRY: u32 = 50;
+        for _ in 0..RETRY {
+            match tokio::net::TcpStream::connect(addr).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    trace!(error =? e, "Embedded MQTT broker is not ready");
+                    tokio::time::sleep(Duration::from_millis(100)).await
+                }
+            }
+        }
+        Err(eyre!("Embedded MQTT broker {} is not ready", addr))
+    }
+}
+
+#[cfg(feature = "embedded-broker")]
+pub use embedded::{embedded_broker, wait_for_broker};
+
+#[cfg(all(test, feature = "embedded-broker"))]
+mod test {
+    use super::*;
+    use crate::api::mqtt::{DEVICE_ETPREFIX, TWIN_ETUPDATE_RESULT_SUFFIX};
+    use crate::scheduler::{test::device, Reflector};
+    use crate::trigger::mqtt::{logger_hook, mqtt_client, test::twin_update, trigger_hook};
+    use rumqttc::{AsyncClient, MqttOptions, QoS};
+
+    #[tokio::test]
+    async fn test_embedded_broker() {
+        const DEVICE_NAME: &str = "test_name";
+
+        let config = BrokerConfig {
+            listen: "127.0.0.1:11883".parse().unwrap(),
+            console: "127.0.0.1:13030".parse().unwrap(),
+            dir: std::env::temp_dir().join("ruleengine-test-broker"),
+            ..Default::default()
+        };
+        let addr = config.local_addr();
+        tokio::spawn(async move { embedded_broker(config).await });
+        wait_for_broker(addr).await.unwrap();
+
+        let store = std::sync::Arc::new(Reflector::default());
+        store.add_device(&device(DEVICE_NAME, &[("temperature", "20")]));
+        let (tx, rx) = flume::bounded(3);
+        let sync_hooks = vec![trigger_hook(store, tx), logger_hook()];
+        tokio::spawn(async move {
+            mqtt_client(addr.ip().to_string(), addr.port(), Vec::new(), sync_hooks).await
+        });
+        // wait for the trigger client to subscribe
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let options = MqttOptions::new("test_embedded_broker", addr.ip().to_string(), addr.port());
+        let (client, mut eventloop) = AsyncClient::new(options, 3);
+        tokio::spawn(async move {
+            loop {
+                eventloop.poll().await.unwrap();
+            }
+        });
+        client
+            .publish(
+                format!("{DEVICE_ETPREFIX}{DEVICE_NAME}{TWIN_ETUPDATE_RESULT_SUFFIX}"),
+                QoS::AtMostOnce,
+                false,
+                twin_update("temperature", "21"),
+            )
+            .await
+            .unwrap();
+
+        let ri = rx.recv_async().await.unwrap().device;
+        assert_eq!(ri.name, DEVICE_NAME);
+    }
+}
@@ -0,0 +1,119 @@
+//! Cancellation of runs
+//!
+//! Runs are cancelled through the web api, or when their Script is deleted or suspended.
+//! The session manager removes matching runs from the queue and asks executors to stop
+//! matching running runs, which are then reported with status `Cancelled`.
+
+use crate::api::Script;
+use crate::scheduler::{ManagerMsg, ResourceIndex};
+use color_eyre::Result;
+use flume::{Receiver, Sender};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelTarget {
+    /// one run, by run id
+    Run(String),
+    /// every run of a Script
+    Script(ResourceIndex<Script>),
+    /// one queued run, by script id, it isn't stopped once dispatched
+    Queued(u32),
+}
+
+impl CancelTarget {
+    /// Whether a running run is cancelled
+    pub fn matches(&self, run_id: &str, namespace: &str, name: &str) -> bool {
+        match self {
+            CancelTarget::Run(id) => id == run_id,
+            CancelTarget::Script(idx) => idx.namespace == namespace && idx.name == name,
+            CancelTarget::Queued(_) => false,
+        }
+    }
+
+    /// Whether a queued run is cancelled
+    pub fn matches_queued(&self, msg: &ManagerMsg) -> bool {
+        match self {
+            CancelTarget::Queued(id) => msg.run.script_id == *id,
+            _ => self.matches(&msg.run.run_id, &msg.namespace, &msg.name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CancelRequest {
+    pub target: CancelTarget,
+    /// reported as the status message of cancelled runs
+    pub reason: String,
+    /// receives the number of cancelled runs
+    pub reply: Option<oneshot::Sender<usize>>,
+}
+
+/// Channel of cancellations to the session manager
+#[derive(Debug, Clone)]
+pub struct Cancels {
+    tx: Sender<CancelRequest>,
+    rx: Receiver<CancelRequest>,
+}
+
+impl Default for Cancels {
+    fn default() -> Self {
+        let (tx, rx) = flume::unbounded();
+        Cancels { tx, rx }
+    }
+}
+
+impl Cancels {
+    /// Cancel runs, returns the number of runs queued or running
+    pub async fn cancel(&self, target: CancelTarget, reason: String) -> Result<usize> {
+        let (reply, cancelled) = oneshot::channel();
+        self.tx
+            .send_async(CancelRequest {
+                target,
+                reason,
+                reply: Some(reply),
+            })
+            .await?;
+        Ok(cancelled.await?)
+    }
+
+    /// Cancel runs without waiting for the result
+    pub fn issue(&self, target: CancelTarget, reason: String) {
+        let _ = self.tx.send(CancelRequest {
+            target,
+            reason,
+            reply: None,
+        });
+    }
+
+    pub(crate) fn requests(&self) -> Receiver<CancelRequest> {
+        self.rx.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proto::server_message::run_script::manifest::ScriptType;
+
+    #[test]
+    fn test_cancel_target() {
+        let run = CancelTarget::Run("abc".to_owned());
+        assert!(run.matches("abc", "default", "a"));
+        assert!(!run.matches("abd", "default", "a"));
+        let script = CancelTarget::Script(ResourceIndex {
+            namespace: "default".to_owned(),
+            name: "a".to_owned(),
+            api: Default::default(),
+        });
+        assert!(script.matches("abc", "default", "a"));
+        assert!(!script.matches("abc", "other", "a"));
+        assert!(!script.matches("abc", "default", "b"));
+        let mut msg = crate::queue::test::msg("a", ScriptType::Js);
+        msg.run.script_id = 3;
+        assert!(script.matches_queued(&msg));
+        assert!(CancelTarget::Queued(3).matches_queued(&msg));
+        assert!(!CancelTarget::Queued(4).matches_queued(&msg));
+        // dispatched runs are not cancelled by script id
+        assert!(!CancelTarget::Queued(3).matches("", "default", "a"));
+    }
+}
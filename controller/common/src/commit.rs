@@ -0,0 +1,315 @@
+//! Delivery guarantees of device writes
+//!
+//! An AtMostOnce commit is written once. An AtLeastOnce commit is written again every
+//! `retry_secs` until the device reports the desired values or `confirm_secs` pass.
+//! An OnlyOnce commit is written once per idempotency key and confirmed the same way,
+//! a retried commit gets the outcome of the first one.
+//!
+//! A batch commit writes several devices with one guarantee. All devices are validated
+//! before the first write, a failed write restores the devices written before it.
+
+use crate::api::device::{DeviceStatus, Twin, TwinProperty};
+use crate::api::Device;
+use dashmap::DashMap;
+use proto::update_device_result::Outcome;
+use proto::update_devices::DeviceDesired;
+use proto::{UpdateDeviceResult, UpdateDevicesResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+use tonic::Status;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommitConfig {
+    /// seconds to wait for the device to report the desired values
+    pub confirm_secs: u64,
+    /// seconds between two writes of an unconfirmed at-least-once commit
+    pub retry_secs: u64,
+    /// seconds an idempotency key is remembered
+    pub dedup_secs: u64,
+}
+
+impl Default for CommitConfig {
+    fn default() -> Self {
+        CommitConfig {
+            confirm_secs: 30,
+            retry_secs: 5,
+            dedup_secs: 600,
+        }
+    }
+}
+
+impl CommitConfig {
+    pub fn confirm(&self) -> Duration {
+        Duration::from_secs(self.confirm_secs)
+    }
+
+    pub fn retry(&self) -> Duration {
+        Duration::from_secs(self.retry_secs.max(1))
+    }
+}
+
+/// Outcome of a write that isn't waited for
+pub fn sent() -> UpdateDeviceResult {
+    UpdateDeviceResult {
+        outcome: Outcome::Sent as i32,
+        pending: Vec::new(),
+    }
+}
+
+/// Desired properties the device doesn't report yet, sorted
+pub fn pending(device: Option<&Device>, desired: &HashMap<String, String>) -> Vec<String> {
+    let reported = device.map(Device::reported).unwrap_or_default();
+    let mut pending: Vec<String> = desired
+        .iter()
+        .filter(|(k, v)| reported.get(*k) != Some(*v))
+        .map(|(k, _)| k.clone())
+        .collect();
+    pending.sort_unstable();
+    pending
+}
+
+/// Set desired values of a device, keeping its reported values and the twins of other
+/// properties
+pub fn set_desired(device: &mut Device, desired: &HashMap<String, String>) {
+    let twins = &mut device
+        .status
+        .get_or_insert_with(|| DeviceStatus { twins: Vec::new() })
+        .twins;
+    for (property, value) in desired {
+        match twins.iter_mut().find(|t| &t.property_name == property) {
+            Some(twin) => twin.desired = TwinProperty::new(value.clone()),
+            None => twins.push(Twin {
+                property_name: property.clone(),
+                desired: TwinProperty::new(value.clone()),
+                reported: None,
+            }),
+        }
+    }
+}
+
+/// Desired values of `properties` before a write, `None` for properties without a twin
+pub fn previous_desired(
+    device: Option<&Device>,
+    properties: impl IntoIterator<Item = String>,
+) -> HashMap<String, Option<String>> {
+    let twins = device
+        .and_then(|d| d.status.as_ref())
+        .map(|s| s.twins.as_slice())
+        .unwrap_or_default();
+    properties
+        .into_iter()
+        .map(|property| {
+            let desired = twins
+                .iter()
+                .find(|t| t.property_name == property)
+                .map(|t| t.desired.value.clone());
+            (property, desired)
+        })
+        .collect()
+}
+
+/// Put back desired values from `previous_desired`. A twin added by the write is removed
+/// unless the device reported a value for it meanwhile.
+pub fn restore_desired(device: &mut Device, previous: &HashMap<String, Option<String>>) {
+    let twins = match device.status.as_mut() {
+        Some(status) => &mut status.twins,
+        None => return,
+    };
+    for (property, value) in previous {
+        match value {
+            Some(value) => {
+                if let Some(twin) = twins.iter_mut().find(|t| &t.property_name == property) {
+                    twin.desired = TwinProperty::new(value.clone());
+                }
+            }
+            None => twins.retain_mut(|t| {
+                if &t.property_name != property {
+                    return true;
+                }
+                t.desired = TwinProperty::new(String::new());
+                t.reported.is_some()
+            }),
+        }
+    }
+}
+
+/// Check a batch before anything is written: every device appears once, is selected by
+/// the Script's `writeSelector` and is known
+pub fn validate(
+    batch: &[DeviceDesired],
+    writable: &HashSet<&str>,
+    known: impl Fn(&str) -> bool,
+) -> Result<(), Status> {
+    if batch.is_empty() {
+        return Err(Status::invalid_argument("Empty batch commit"));
+    }
+    let mut seen = HashSet::new();
+    for device in batch {
+        if !seen.insert(device.name.as_str()) {
+            return Err(Status::invalid_argument(format!(
+                "Device {} is committed twice",
+                device.name
+            )));
+        }
+        if !writable.contains(device.name.as_str()) {
+            return Err(Status::permission_denied(format!(
+                "Device {} isn't writable",
+                device.name
+            )));
+        }
+        if !known(&device.name) {
+            return Err(Status::not_found(format!(
+                "Device {} not found",
+                device.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+type Once<T> = Arc<OnceCell<T>>;
+type Outcomes<T> = DashMap<(String, String), (Instant, Once<T>)>;
+
+/// Outcomes of only-once commits by (run id, idempotency key)
+#[derive(Debug, Default)]
+pub struct Commits {
+    config: CommitConfig,
+    outcomes: Outcomes<UpdateDeviceResult>,
+    batches: Outcomes<UpdateDevicesResult>,
+}
+
+impl Commits {
+    pub fn new(config: CommitConfig) -> Self {
+        Commits {
+            config,
+            outcomes: Default::default(),
+            batches: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CommitConfig {
+        &self.config
+    }
+
+    /// Outcome of a keyed commit, it is empty until the first commit with the key finished
+    pub fn once(&self, run_id: &str, key: &str) -> Once<UpdateDeviceResult> {
+        self.remember(&self.outcomes, run_id, key)
+    }
+
+    /// Outcome of a keyed batch commit
+    pub fn once_batch(&self, run_id: &str, key: &str) -> Once<UpdateDevicesResult> {
+        self.remember(&self.batches, run_id, key)
+    }
+
+    fn remember<T>(&self, outcomes: &Outcomes<T>, run_id: &str, key: &str) -> Once<T> {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(self.config.dedup_secs);
+        outcomes.retain(|_, (seen, _)| now.saturating_duration_since(*seen) < ttl);
+        outcomes
+            .entry((run_id.to_owned(), key.to_owned()))
+            .or_insert_with(|| (now, Default::default()))
+            .1
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheduler::test::device;
+
+    #[test]
+    fn test_pending() {
+        let dev = device("switch", &[("power", "on"), ("level", "3")]);
+        let desired = HashMap::from([
+            ("power".to_owned(), "on".to_owned()),
+            ("level".to_owned(), "4".to_owned()),
+            ("mode".to_owned(), "auto".to_owned()),
+        ]);
+        assert_eq!(pending(Some(&dev), &desired), vec!["level", "mode"]);
+        assert_eq!(pending(None, &desired).len(), 3);
+    }
+
+    #[test]
+    fn test_set_desired() {
+        let mut dev = device("switch", &[("power", "off"), ("level", "3")]);
+        let desired = HashMap::from([
+            ("power".to_owned(), "on".to_owned()),
+            ("mode".to_owned(), "auto".to_owned()),
+        ]);
+        set_desired(&mut dev, &desired);
+        let twins = &dev.status.as_ref().unwrap().twins;
+        assert_eq!(twins.len(), 3);
+        assert_eq!(twins[0].desired.value, "on");
+        assert_eq!(twins[0].reported.as_ref().unwrap().value, "off");
+        assert_eq!(twins[1].desired.value, "");
+        assert_eq!(twins[2].property_name, "mode");
+        assert_eq!(twins[2].desired.value, "auto");
+        assert_eq!(dev.reported().len(), 2);
+    }
+
+    #[test]
+    fn test_restore_desired() {
+        let mut dev = device("switch", &[("power", "off"), ("level", "3")]);
+        let desired = HashMap::from([
+            ("power".to_owned(), "on".to_owned()),
+            ("mode".to_owned(), "auto".to_owned()),
+        ]);
+        let previous = previous_desired(Some(&dev), desired.keys().cloned());
+        assert_eq!(previous["power"].as_deref(), Some(""));
+        assert_eq!(previous["mode"], None);
+        set_desired(&mut dev, &desired);
+        // reported meanwhile, kept by the rollback
+        dev.status.as_mut().unwrap().twins[1].reported = Some(TwinProperty::new("4".to_owned()));
+        restore_desired(&mut dev, &previous);
+        let twins = &dev.status.as_ref().unwrap().twins;
+        assert_eq!(twins.len(), 2);
+        assert_eq!(twins[0].desired.value, "");
+        assert_eq!(twins[0].reported.as_ref().unwrap().value, "off");
+        assert_eq!(twins[1].reported.as_ref().unwrap().value, "4");
+    }
+
+    #[tokio::test]
+    async fn test_only_once() {
+        let commits = Commits::default();
+        let first = commits.once("run", "1");
+        let result = UpdateDeviceResult {
+            outcome: Outcome::Confirmed as i32,
+            pending: Vec::new(),
+        };
+        first.set(result.clone()).unwrap();
+        assert_eq!(commits.once("run", "1").get(), Some(&result));
+        assert!(commits.once("run", "2").get().is_none());
+        assert!(commits.once("other", "1").get().is_none());
+        assert!(commits.once_batch("run", "1").get().is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let desired = |name: &str| DeviceDesired {
+            name: name.to_owned(),
+            desired: HashMap::from([("power".to_owned(), "on".to_owned())]),
+        };
+        let writable = HashSet::from(["switch", "lamp", "fan"]);
+        let known = |name: &str| name != "fan";
+        assert!(validate(&[desired("switch"), desired("lamp")], &writable, known).is_ok());
+        let code = |batch: &[DeviceDesired]| validate(batch, &writable, known).unwrap_err().code();
+        assert_eq!(code(&[]), tonic::Code::InvalidArgument);
+        assert_eq!(
+            code(&[desired("switch"), desired("switch")]),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            code(&[desired("switch"), desired("heater")]),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            code(&[desired("switch"), desired("fan")]),
+            tonic::Code::NotFound
+        );
+    }
+}
@@ -1,17 +1,32 @@
 use crate::api::{Device, Script};
-use crate::scheduler::{trigger, ManagerMsg, Reflector, ResourceIndex, Scheduler};
-use crate::session::SessionManager;
+use crate::auth::AuthConfig;
+use crate::broker::BrokerConfig;
+use crate::cancel::Cancels;
+use crate::commit::CommitConfig;
+use crate::drain::Drains;
+use crate::history::{HistoryConfig, RunHistory};
+use crate::id::ScriptIDGenerator;
+use crate::leader::{leader_election, LeaderConfig};
+use crate::locality::LocalityConfig;
+use crate::logs::{LogConfig, ScriptLogs};
+use crate::queue::{QueueConfig, RunQueue};
+use crate::quota::{Quotas, QUOTA_CONFIGMAP};
+use crate::scheduler::{trigger, DeviceTrigger, ManagerMsg, Reflector, Scheduler, ScriptTrigger};
+use crate::session::{HeartbeatConfig, LeaseConfig, SessionConfig, SessionManager};
+use crate::snapshot::{persist_snapshot, read_snapshot, SnapshotConfig};
 use color_eyre::Result;
 use flume::{Receiver, Sender};
 use futures::StreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
 use kube::{api::ListParams, Api, Client};
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tracing::{error, info};
+use tracing::{error, info, trace};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ControllerState {
@@ -42,27 +57,101 @@ pub async fn wait_for_stop(rx: &mut watch::Receiver<ControllerState>) {
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub webaddr: SocketAddr,
     pub grpcaddr: SocketAddr,
     pub mqttaddr: SocketAddr,
+    /// Start an embedded MQTT broker, `mqttaddr` is ignored when set
+    #[serde(default)]
+    pub broker: Option<BrokerConfig>,
+    /// Namespaces to watch, watch all namespaces if empty
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Label selector of watched Scripts
+    #[serde(default)]
+    pub script_selector: Option<String>,
+    /// Label selector of watched Devices
+    #[serde(default)]
+    pub device_selector: Option<String>,
+    /// Run leader election, this replica is always the leader if not set
+    #[serde(default)]
+    pub leader_election: Option<LeaderConfig>,
+    /// Persist the reflector for warm restart
+    #[serde(default)]
+    pub snapshot: Option<SnapshotConfig>,
+    /// Pending run queue between the scheduler and executors
+    #[serde(default)]
+    pub queue: QueueConfig,
+    /// Dispatch runs to executors on the node of the Script's devices
+    #[serde(default)]
+    pub locality: LocalityConfig,
+    /// Runs kept per Script for the run history api
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Console lines kept per Script for the run log api
+    #[serde(default)]
+    pub logs: LogConfig,
+    /// Deadlines of dispatched runs
+    #[serde(default)]
+    pub lease: LeaseConfig,
+    /// Heartbeats of executor connections
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    /// TLS and authentication of executors
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Confirmation of device writes
+    #[serde(default)]
+    pub commit: CommitConfig,
 }
 
 pub struct Controller {
     pub controller_tasks: Vec<JoinHandle<()>>,
     state: watch::Sender<ControllerState>,
     state_rx: watch::Receiver<ControllerState>,
+    leader: Option<watch::Sender<bool>>,
+    leader_rx: watch::Receiver<bool>,
+    election_task: Option<JoinHandle<()>>,
+    runs: Runs,
     config: Config,
 }
 
+/// Run state shared by the scheduler, the session manager and the web server
+#[derive(Debug, Clone)]
+pub struct Runs {
+    pub queue: Arc<RunQueue>,
+    pub quotas: Arc<Quotas>,
+    pub history: Arc<RunHistory>,
+    pub logs: Arc<ScriptLogs>,
+    /// ids of dispatched runs, lost runs are sent again with a new id
+    pub script_ids: Arc<ScriptIDGenerator>,
+    /// runs to cancel, served by the session manager
+    pub cancels: Cancels,
+    /// executors to list or pause, served by the session manager
+    pub drains: Drains,
+}
+
 impl Controller {
     pub fn new(config: Config) -> Result<Controller> {
         let (tx, rx) = tokio::sync::watch::channel(ControllerState::Init);
+        let (leader_tx, leader_rx) = watch::channel(config.leader_election.is_none());
         Ok(Controller {
             controller_tasks: Vec::new(),
             state: tx,
             state_rx: rx,
+            leader: Some(leader_tx),
+            leader_rx,
+            election_task: None,
+            runs: Runs {
+                queue: Arc::new(RunQueue::new(config.queue.clone())),
+                quotas: Default::default(),
+                history: Arc::new(RunHistory::new(config.history.clone())),
+                logs: Arc::new(ScriptLogs::new(config.logs.clone())),
+                script_ids: Default::default(),
+                cancels: Default::default(),
+                drains: Default::default(),
+            },
             config,
         })
     }
@@ -78,6 +167,10 @@ impl Controller {
         }
         self.stop();
         time::sleep(time::Duration::from_millis(100)).await;
+        if let Some(election) = self.election_task.take() {
+            // give the election a chance to release the Lease
+            let _ = time::timeout(time::Duration::from_secs(2), election).await;
+        }
         self.kill_all();
         Ok(())
     }
@@ -117,27 +210,74 @@ impl Controller {
         client: Client,
         is_cloud: bool,
     ) -> (
-        Sender<ResourceIndex<Script>>,
-        Sender<ResourceIndex<Device>>,
+        Sender<ScriptTrigger>,
+        Sender<DeviceTrigger>,
         Receiver<ManagerMsg>,
         Arc<Reflector>,
     ) {
         use crate::trigger::kubeapi::*;
         let reflector_store = Arc::new(Reflector::default());
 
+        // warm restart from the last snapshot
+        if let Some(config) = self.config.snapshot.clone() {
+            if let Err(e) =
+                read_snapshot(&config.path).and_then(|s| reflector_store.load_snapshot(s))
+            {
+                error!(error =? e, path =? config.path, "Failed to load Reflector snapshot");
+            }
+            let reflector_clone = reflector_store.clone();
+            self.spawn(async move { persist_snapshot(reflector_clone, config).await });
+        }
+
         // Scheduler
-        let (schin_tx, schin_rx) = flume::bounded(10);
+        let (schin_tx, schin_rx) = flume::bounded::<ScriptTrigger>(10);
         let (schout_tx, schout_rx) = flume::bounded(10);
         let reflector_clone = reflector_store.clone();
+        let leader = self.leader_rx.clone();
+        let queue = self.runs.queue.clone();
+        let quotas = self.runs.quotas.clone();
+        let script_ids = self.runs.script_ids.clone();
+        let event_client = client.clone();
         self.spawn(async move {
             let mut in_rx = schin_rx.into_stream();
-            let mut scheduler = Scheduler::new(reflector_clone);
-            while let Some(index) = in_rx.next().await {
-                info!("Triger new script to run: {:?}", index);
-                match scheduler.lookup(index) {
-                    Ok(msg) => schout_tx.send(msg)?,
-                    Err(e) => error!(error =? e, "Scheduler throw a error"),
+            let mut scheduler = Scheduler::new(reflector_clone.clone(), script_ids);
+            while let Some(trigger) = in_rx.next().await {
+                if !*leader.borrow() {
+                    trace!(trigger =? trigger, "Not the leader, ignore trigger");
+                    continue;
                 }
+                let suspended = reflector_clone
+                    .script_store
+                    .get(&trigger.script)
+                    .map_or(false, |s| s.spec.suspend);
+                if suspended {
+                    info!(script =? trigger.script, "Script is suspended, ignore trigger");
+                    continue;
+                }
+                info!("Triger new script to run: {:?}", trigger);
+                let script = trigger.script.clone();
+                let msg = match scheduler.lookup(trigger) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!(error =? e, "Scheduler throw a error");
+                        continue;
+                    }
+                };
+                // only runs that get queued are charged, and a trigger merged
+                // into a queued run doesn't count against the quota
+                if !queue.would_coalesce(&script.name, &script.namespace) {
+                    let queued = queue.queued_in(&script.namespace);
+                    if let Err(throttled) = quotas.admit(&script.namespace, queued, Instant::now())
+                    {
+                        info!(script =? script, reason = %throttled.message(), "Trigger throttled");
+                        let found = reflector_clone.script_store.get(&script).map(|s| s.clone());
+                        if let Some(s) = found {
+                            quotas.report(&event_client, &s, throttled).await;
+                        }
+                        continue;
+                    }
+                }
+                schout_tx.send_async(msg).await?;
             }
             Ok(())
         });
@@ -148,87 +288,179 @@ impl Controller {
         let schin_tx_clone = schin_tx.clone();
         self.spawn(async move { trigger(reflector_clone, schdevin_rx, schin_tx_clone).await });
 
-        // script reflector
-        let mut script_async_hooks = Vec::new();
-        let script_sync_hooks = vec![logger_hook()];
-        let script_api: Api<Script> = Api::all(client.clone());
+        // one script and one device reflector for each watched namespace
+        let scopes: Vec<Option<String>> = if self.config.namespaces.is_empty() {
+            vec![None]
+        } else {
+            self.config.namespaces.iter().cloned().map(Some).collect()
+        };
+        for namespace in scopes {
+            // script reflector
+            let mut script_async_hooks = Vec::new();
+            let script_sync_hooks = vec![logger_hook()];
+            let script_api: Api<Script> = scoped_api(client.clone(), namespace.as_deref());
+            let script_lp = list_params(self.config.script_selector.as_deref());
 
-        // device reflector
-        let mut device_async_hooks = Vec::new();
-        let device_sync_hooks = vec![logger_hook()];
-        let device_api: Api<Device> = Api::all(client);
+            // device reflector
+            let mut device_async_hooks = Vec::new();
+            let device_sync_hooks = vec![logger_hook()];
+            let device_api: Api<Device> = scoped_api(client.clone(), namespace.as_deref());
+            let device_lp = list_params(self.config.device_selector.as_deref());
 
-        // device_hook for device reflector
-        let (device_tx, device_rx) = flume::bounded(3);
+            // device_hook for device reflector, triggers scripts on reported twin changes
+            let (device_tx, device_rx) = flume::bounded(3);
+            let device_trigger = is_cloud.then(|| schdevin_tx.clone());
+            let reflector_clone = reflector_store.clone();
+            let scope = namespace.clone();
+            self.spawn(async move {
+                device_hook(device_rx, reflector_clone, scope, device_trigger).await
+            });
+            device_async_hooks.push(device_tx);
 
-        if is_cloud {
-            let device_rx = device_rx.clone();
-            let schdevin_tx = schdevin_tx.clone();
-            self.spawn(async move { trigger_hook(device_rx, schdevin_tx).await });
-        }
+            // namespace quotas
+            let quota_api: Api<ConfigMap> = scoped_api(client.clone(), namespace.as_deref());
+            let quota_lp =
+                ListParams::default().fields(&format!("metadata.name={}", QUOTA_CONFIGMAP));
+            let quota_hooks = vec![
+                quota_hook(self.runs.quotas.clone(), namespace.clone()),
+                logger_hook(),
+            ];
+            self.spawn(
+                async move { reflector(quota_api, quota_lp, Vec::new(), quota_hooks).await },
+            );
 
-        let reflector_clone = reflector_store.clone();
-        self.spawn(async move { device_hook(device_rx, reflector_clone).await });
-        device_async_hooks.push(device_tx);
+            // script_hook for device reflector
+            let (script_tx, script_rx) = flume::bounded(3);
+            let reflector_clone = reflector_store.clone();
+            let scope = namespace.clone();
+            let cancels = self.runs.cancels.clone();
+            self.spawn(
+                async move { script_hook(script_rx, reflector_clone, scope, cancels).await },
+            );
+            script_async_hooks.push(script_tx);
 
-        // script_hook for device reflector
-        let (script_tx, script_rx) = flume::bounded(3);
-        let reflector_clone = reflector_store.clone();
-        self.spawn(async move { script_hook(script_rx, reflector_clone).await });
-        script_async_hooks.push(script_tx);
+            // script reflector
+            self.spawn(async move {
+                reflector(script_api, script_lp, script_async_hooks, script_sync_hooks).await
+            });
 
-        // script reflector
-        self.spawn(async move {
-            reflector(
-                script_api,
-                ListParams::default(),
-                script_async_hooks,
-                script_sync_hooks,
-            )
-            .await
-        });
-
-        // device reflector
-        self.spawn(async move {
-            reflector(
-                device_api,
-                ListParams::default(),
-                device_async_hooks,
-                device_sync_hooks,
-            )
-            .await
-        });
+            // device reflector
+            self.spawn(async move {
+                reflector(device_api, device_lp, device_async_hooks, device_sync_hooks).await
+            });
+        }
 
         (schin_tx, schdevin_tx, schout_rx, reflector_store)
     }
 
-    pub fn spawn_mqtt(&mut self, scheduler: Sender<ResourceIndex<Device>>) {
+    /// Spawn the embedded MQTT broker if it is configured
+    #[cfg(feature = "embedded-broker")]
+    pub fn spawn_broker(&mut self) {
+        if let Some(config) = self.config.broker.clone() {
+            self.spawn(async move { crate::broker::embedded_broker(config).await });
+        }
+    }
+
+    pub fn spawn_mqtt(&mut self, scheduler: Sender<DeviceTrigger>, store: Arc<Reflector>) {
         use crate::trigger::mqtt::*;
-        let sync_hooks = vec![trigger_hook(scheduler), logger_hook()];
+        let sync_hooks = vec![trigger_hook(store, scheduler), logger_hook()];
         let async_hooks = Vec::new();
-        let host = self.config.mqttaddr.ip().to_string();
-        let port = self.config.mqttaddr.port();
-        self.spawn(async move { mqtt_client(host, port, async_hooks, sync_hooks).await });
+        let addr = match &self.config.broker {
+            Some(broker) => broker.local_addr(),
+            None => self.config.mqttaddr,
+        };
+        let embedded = self.config.broker.is_some();
+        let host = addr.ip().to_string();
+        let port = addr.port();
+        self.spawn(async move {
+            #[cfg(feature = "embedded-broker")]
+            if embedded {
+                crate::broker::wait_for_broker(addr).await?;
+            }
+            #[cfg(not(feature = "embedded-broker"))]
+            if embedded {
+                return Err(color_eyre::eyre::eyre!(
+                    "Embedded MQTT broker is configured, but the controller is built without `embedded-broker` feature"
+                ));
+            }
+            mqtt_client(host, port, async_hooks, sync_hooks).await
+        });
     }
 
-    pub fn spawn_webserver(
-        &mut self,
-        scheduler: Sender<ResourceIndex<Script>>,
-        store: Arc<Reflector>,
-    ) {
+    pub fn spawn_webserver(&mut self, scheduler: Sender<ScriptTrigger>, store: Arc<Reflector>) {
         use crate::server::*;
         let addr = self.config.webaddr;
-        self.spawn(async move { web_server(scheduler, store, addr).await });
+        let leader = self.leader_rx.clone();
+        let runs = self.runs.clone();
+        self.spawn(async move { web_server(scheduler, store, runs, leader, addr).await });
+    }
+
+    /// Spawn the leader election if it is configured
+    pub fn spawn_leader_election(&mut self, client: Client) {
+        let config = match &self.config.leader_election {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let leader = match self.leader.take() {
+            Some(leader) => leader,
+            None => return,
+        };
+        let mut state = self.state_rx.clone();
+        let handle = tokio::spawn(async move {
+            wait_for_init(&mut state).await;
+            if let Err(e) = leader_election(client, config, leader, state).await {
+                error!(error =? e, "Leader election is down!");
+            }
+        });
+        self.election_task = Some(handle)
     }
 
-    pub fn spawn_grpc(&mut self, client: Client, scheduler: Receiver<ManagerMsg>) {
+    pub fn spawn_grpc(
+        &mut self,
+        client: Client,
+        scheduler: Receiver<ManagerMsg>,
+        store: Arc<Reflector>,
+    ) {
         let addr = self.config.grpcaddr;
         let mut state = self.state_rx.clone();
+        let leader = self.leader_rx.clone();
+        let runs = self.runs.clone();
+        let config = SessionConfig {
+            locality: self.config.locality.clone(),
+            lease: self.config.lease.clone(),
+            heartbeat: self.config.heartbeat.clone(),
+            auth: self.config.auth.clone(),
+            commit: self.config.commit.clone(),
+        };
+        let tls = self.config.auth.tls.clone();
         let handle = tokio::spawn(async move {
             wait_for_init(&mut state).await;
-            let mgr = SessionManager::new(client, scheduler, state);
-            if let Err(e) = crate::server::grpc_server(addr, mgr).await {
-                error!(error =? e, "Grpc server is down!");
+            let mgr = SessionManager::new(client, store, scheduler, runs, config, state, leader);
+            let dispatch = mgr.dispatch();
+            let reclaim = mgr.reclaim_expired();
+            let stranded = mgr.expire_stranded();
+            let cancel = mgr.cancel();
+            let drain = mgr.drain();
+            tokio::select! {
+                Err(e) = crate::server::grpc_server(addr, mgr, tls) => {
+                    error!(error =? e, "Grpc server is down!");
+                }
+                Err(e) = dispatch => {
+                    error!(error =? e, "Scheduler is down!");
+                }
+                Err(e) = reclaim => {
+                    error!(error =? e, "Run lease reaper is down!");
+                }
+                Err(e) = stranded => {
+                    error!(error =? e, "Stranded run reaper is down!");
+                }
+                Err(e) = cancel => {
+                    error!(error =? e, "Run cancellation is down!");
+                }
+                Err(e) = drain => {
+                    error!(error =? e, "Executor draining is down!");
+                }
+                else => {}
             }
         });
         self.controller_tasks.push(handle)
@@ -261,5 +493,8 @@ impl Controller {
         for j in &self.controller_tasks {
             j.abort()
         }
+        if let Some(j) = &self.election_task {
+            j.abort()
+        }
     }
 }
@@ -0,0 +1,79 @@
+//! Draining of executors
+//!
+//! A paused executor finishes its running runs but gets no new ones. Executors are paused
+//! and resumed through the web api, an executor drains itself with `ClientCode::Drain`
+//! before it exits, e.g. on SIGTERM during a rolling restart.
+
+use crate::session::ExecutorState;
+use color_eyre::Result;
+use flume::{Receiver, Sender};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+/// A connected executor as listed by the web api
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutorSummary {
+    pub id: u32,
+    pub addr: String,
+    pub identity: String,
+    pub node: String,
+    pub protocol: String,
+    /// `Init`, `Ready`, `Pause`, `Draining` or `Disconnect`
+    pub state: String,
+    /// runs executing on the executor
+    pub running: usize,
+    pub credits: u32,
+    pub max_job: u32,
+}
+
+#[derive(Debug)]
+pub enum DrainRequest {
+    List(oneshot::Sender<Vec<ExecutorSummary>>),
+    /// receives the state of the executor before the request, none if it isn't connected
+    Pause {
+        executor: u32,
+        paused: bool,
+        reply: oneshot::Sender<Option<ExecutorState>>,
+    },
+}
+
+/// Channel of executor requests to the session manager
+#[derive(Debug, Clone)]
+pub struct Drains {
+    tx: Sender<DrainRequest>,
+    rx: Receiver<DrainRequest>,
+}
+
+impl Default for Drains {
+    fn default() -> Self {
+        let (tx, rx) = flume::unbounded();
+        Drains { tx, rx }
+    }
+}
+
+impl Drains {
+    /// Connected executors, by id
+    pub async fn list(&self) -> Result<Vec<ExecutorSummary>> {
+        let (reply, executors) = oneshot::channel();
+        self.tx.send_async(DrainRequest::List(reply)).await?;
+        Ok(executors.await?)
+    }
+
+    /// Pause or resume dispatching runs to an executor
+    pub async fn pause(&self, executor: u32, paused: bool) -> Result<Option<ExecutorState>> {
+        let (reply, state) = oneshot::channel();
+        self.tx
+            .send_async(DrainRequest::Pause {
+                executor,
+                paused,
+                reply,
+            })
+            .await?;
+        Ok(state.await?)
+    }
+
+    pub(crate) fn requests(&self) -> Receiver<DrainRequest> {
+        self.rx.clone()
+    }
+}
@@ -0,0 +1,291 @@
+//! Bounded history of the runs of each Script
+//!
+//! A run is recorded when it is dispatched to an executor or rejected before that,
+//! and completed with the status reported by the executor.
+//! Only the last `capacity` runs of a Script are kept.
+
+use crate::auth::Identity;
+use crate::id::{ExecutorID, RunID};
+use crate::scheduler::{ManagerMsg, TriggerCause};
+use k8s_openapi::chrono::Utc;
+use proto::script_status::ScriptStatusCode;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// runs kept per Script, 0 to disable
+    pub capacity: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig { capacity: 20 }
+    }
+}
+
+/// Desired values written by a run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceWrite {
+    /// resource name of the device
+    pub device: String,
+    pub desired: HashMap<String, String>,
+}
+
+/// Outcome of a run, serialized as `Running` or the name of the `ScriptStatusCode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunResult {
+    Running,
+    Done(ScriptStatusCode),
+}
+
+impl Serialize for RunResult {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RunResult::Running => serializer.serialize_str("Running"),
+            RunResult::Done(code) => serializer.collect_str(&format_args!("{:?}", code)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRecord {
+    pub run_id: RunID,
+    pub script_id: u32,
+    /// a lost run is sent again as a new attempt of the same run
+    pub attempt: u32,
+    pub name: String,
+    pub namespace: String,
+    pub causes: Vec<TriggerCause>,
+    /// executor the run was dispatched to
+    pub executor: Option<u32>,
+    /// who that executor authenticated as, only it may report on the attempt
+    #[serde(skip)]
+    pub identity: Option<Identity>,
+    /// unix ms timestamp the run left the controller
+    pub dispatched: i64,
+    /// unix ms timestamp reported by the executor
+    pub start: Option<i64>,
+    /// executing time in us
+    pub duration: Option<u32>,
+    pub result: RunResult,
+    pub message: String,
+    pub writes: Vec<DeviceWrite>,
+    /// value returned by main()
+    pub output: Option<serde_json::Value>,
+    /// the attempt was lost and the run sent again
+    pub retried: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct RunHistory {
+    config: HistoryConfig,
+    /// (namespace, name) to runs, oldest first
+    runs: Mutex<HashMap<(String, String), VecDeque<RunRecord>>>,
+}
+
+impl RunHistory {
+    pub fn new(config: HistoryConfig) -> Self {
+        RunHistory {
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), VecDeque<RunRecord>>> {
+        self.runs.lock().expect("RunHistory poisoned")
+    }
+
+    fn record(
+        &self,
+        msg: &ManagerMsg,
+        executor: Option<(ExecutorID, &Identity)>,
+        result: RunResult,
+        message: String,
+    ) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let record = RunRecord {
+            run_id: msg.run.run_id.clone().into(),
+            script_id: msg.run.script_id,
+            attempt: msg.attempts,
+            name: msg.name.clone(),
+            namespace: msg.namespace.clone(),
+            causes: msg.causes.clone(),
+            executor: executor.map(|(id, _)| id.into()),
+            identity: executor.map(|(_, identity)| identity.clone()),
+            dispatched: Utc::now().timestamp_millis(),
+            start: None,
+            duration: None,
+            result,
+            message,
+            writes: Vec::new(),
+            output: None,
+            retried: false,
+        };
+        let mut runs = self.lock();
+        let script = runs
+            .entry((msg.namespace.clone(), msg.name.clone()))
+            .or_default();
+        script.push_back(record);
+        while script.len() > self.config.capacity {
+            script.pop_front();
+        }
+    }
+
+    /// Record a run sent to an executor
+    pub fn dispatched(&self, msg: &ManagerMsg, executor: ExecutorID, identity: &Identity) {
+        self.record(
+            msg,
+            Some((executor, identity)),
+            RunResult::Running,
+            String::new(),
+        )
+    }
+
+    /// Record a run that never reached an executor
+    pub fn rejected(&self, msg: &ManagerMsg, code: ScriptStatusCode, message: String) {
+        self.record(msg, None, RunResult::Done(code), message)
+    }
+
+    /// Update an attempt, `script_id` is unique within the controller process
+    fn update(&self, namespace: &str, name: &str, script_id: u32, f: impl FnOnce(&mut RunRecord)) {
+        let mut runs = self.lock();
+        let record = runs
+            .get_mut(&(namespace.to_owned(), name.to_owned()))
+            .and_then(|s| s.iter_mut().rev().find(|r| r.script_id == script_id));
+        if let Some(record) = record {
+            f(record)
+        }
+    }
+
+    /// Record desired values written by a running run
+    pub fn write(&self, namespace: &str, name: &str, script_id: u32, write: DeviceWrite) {
+        self.update(namespace, name, script_id, |r| r.writes.push(write))
+    }
+
+    /// Mark a lost attempt as replaced by a new attempt
+    pub fn retried(&self, namespace: &str, name: &str, script_id: u32) {
+        self.update(namespace, name, script_id, |r| r.retried = true)
+    }
+
+    /// Complete a run with the status reported by the executor
+    pub fn finished(
+        &self,
+        namespace: &str,
+        name: &str,
+        script_id: u32,
+        status: &crate::api::script::ScriptStatus,
+    ) {
+        let code = ScriptStatusCode::from_i32(status.status).unwrap_or(ScriptStatusCode::Unknown);
+        self.update(namespace, name, script_id, |r| {
+            r.start = Some(status.last_run);
+            r.duration = Some(status.elapsed_time);
+            r.result = RunResult::Done(code);
+            r.message = status.message.clone();
+            r.output = status
+                .output
+                .as_deref()
+                .and_then(|o| serde_json::from_str(o).ok());
+        })
+    }
+
+    /// Runs of a Script, newest first
+    pub fn list(&self, namespace: &str, name: &str) -> Vec<RunRecord> {
+        self.lock()
+            .get(&(namespace.to_owned(), name.to_owned()))
+            .map(|s| s.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Latest attempt of a run
+    pub fn get(&self, run_id: &str) -> Option<RunRecord> {
+        self.lock()
+            .values()
+            .flatten()
+            .filter(|r| r.run_id.as_str() == run_id)
+            .last()
+            .cloned()
+    }
+
+    /// Attempt sent with `script_id`
+    pub fn attempt(&self, script_id: u32) -> Option<RunRecord> {
+        self.lock()
+            .values()
+            .flatten()
+            .find(|r| r.script_id == script_id)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::queue::test::msg;
+    use proto::server_message::run_script::manifest::ScriptType;
+
+    fn run(name: &str, run_id: &str) -> ManagerMsg {
+        let mut m = msg(name, ScriptType::Js);
+        m.run.run_id = run_id.to_owned();
+        m.run.script_id = run_id.parse().unwrap();
+        m
+    }
+
+    #[test]
+    fn test_history() {
+        let history = RunHistory::new(HistoryConfig { capacity: 2 });
+        let anonymous = &Identity::Anonymous;
+        history.dispatched(&run("a", "1"), ExecutorID::default(), anonymous);
+        history.rejected(
+            &run("a", "2"),
+            ScriptStatusCode::NoExecutor,
+            "no".to_owned(),
+        );
+        history.dispatched(&run("a", "3"), ExecutorID::default(), anonymous);
+        history.dispatched(&run("b", "4"), ExecutorID::default(), anonymous);
+
+        let write = DeviceWrite {
+            device: "switch".to_owned(),
+            desired: HashMap::from([("power".to_owned(), "on".to_owned())]),
+        };
+        history.write("default", "a", 3, write.clone());
+        let status = crate::api::script::ScriptStatus {
+            last_run: 1000,
+            elapsed_time: 20,
+            status: ScriptStatusCode::Ok as i32,
+            message: String::new(),
+            output: Some(r#"{"on":true}"#.to_owned()),
+        };
+        history.finished("default", "a", 3, &status);
+
+        let runs = history.list("default", "a");
+        let ids: Vec<String> = runs.iter().map(|r| r.run_id.to_string()).collect();
+        assert_eq!(ids, vec!["3", "2"]);
+        assert_eq!(runs[0].result, RunResult::Done(ScriptStatusCode::Ok));
+        assert_eq!(runs[0].writes, vec![write]);
+        assert_eq!(runs[0].output, Some(serde_json::json!({ "on": true })));
+        assert_eq!(
+            runs[1].result,
+            RunResult::Done(ScriptStatusCode::NoExecutor)
+        );
+        assert_eq!(
+            serde_json::to_value(&runs[1]).unwrap()["result"],
+            "NoExecutor"
+        );
+        assert!(history.get("1").is_none());
+        assert_eq!(history.get("4").unwrap().name, "b");
+        assert_eq!(history.attempt(2).unwrap().message, "no");
+        assert_eq!(
+            history.attempt(4).unwrap().identity.as_ref(),
+            Some(anonymous)
+        );
+        assert_eq!(history.attempt(2).unwrap().identity, None);
+        assert!(!history.attempt(4).unwrap().retried);
+        history.retried("default", "b", 4);
+        assert!(history.attempt(4).unwrap().retried);
+    }
+}
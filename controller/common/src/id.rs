@@ -1,4 +1,6 @@
+use k8s_openapi::chrono::Utc;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(
@@ -29,6 +31,62 @@ impl ScriptIDGenerator {
     }
 }
 
+/// Identifier of a run, unique across controller restarts.
+/// `ScriptID` only identifies a run within one controller process.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RunID(String);
+
+impl RunID {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RunID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<RunID> for String {
+    fn from(val: RunID) -> String {
+        val.0
+    }
+}
+
+impl From<String> for RunID {
+    fn from(val: String) -> Self {
+        RunID(val)
+    }
+}
+
+/// Run ids are `<epoch>-<counter>`, the epoch is taken from the start time and a random
+/// number, so every generator has its own epoch
+#[derive(Debug)]
+pub struct RunIDGenerator {
+    epoch: String,
+    inner: IDGenerator,
+}
+
+impl Default for RunIDGenerator {
+    fn default() -> Self {
+        RunIDGenerator {
+            epoch: format!(
+                "{:x}{:08x}",
+                Utc::now().timestamp_millis(),
+                rand::random::<u32>()
+            ),
+            inner: IDGenerator::default(),
+        }
+    }
+}
+
+impl RunIDGenerator {
+    pub fn gen(&self) -> RunID {
+        RunID(format!("{}-{}", self.epoch, self.inner.gen().0))
+    }
+}
+
 #[derive(
     Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
 )]
@@ -40,6 +98,12 @@ impl From<ExecutorID> for u32 {
     }
 }
 
+impl From<u32> for ExecutorID {
+    fn from(val: u32) -> Self {
+        ExecutorID(ID(val))
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct ExecutorIDGenerator {
     inner: IDGenerator,
@@ -76,3 +140,18 @@ impl IDGenerator {
         ID(self.0.fetch_add(1, Ordering::SeqCst))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_id() {
+        let first = RunIDGenerator::default();
+        let a = first.gen();
+        assert_ne!(a, first.gen());
+        // a restarted controller has a new generator
+        let restarted = RunIDGenerator::default();
+        assert_ne!(a, restarted.gen());
+    }
+}
@@ -0,0 +1,257 @@
+//! Bidirectional index between Scripts and the Devices they select
+
+use crate::api::{Device, Script};
+use crate::scheduler::ResourceIndex;
+use kube::Resource;
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+type DeviceSet = HashSet<ResourceIndex<Device>>;
+type ScriptSet = HashSet<ResourceIndex<Script>>;
+
+/// Devices selected by a Script
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelectedDevices {
+    /// devices in `read_selector`
+    pub read: DeviceSet,
+    /// devices in `write_selector`
+    pub write: DeviceSet,
+}
+
+impl From<&Script> for SelectedDevices {
+    fn from(script: &Script) -> Self {
+        let namespace = script.meta().namespace.clone().unwrap();
+        let select = |names: &Option<HashMap<String, String>>| -> DeviceSet {
+            names
+                .iter()
+                .flat_map(|map| map.values())
+                .map(|name| ResourceIndex {
+                    namespace: namespace.clone(),
+                    name: name.clone(),
+                    api: PhantomData,
+                })
+                .collect()
+        };
+        SelectedDevices {
+            read: select(&script.spec.read_selector.match_names),
+            write: select(&script.spec.write_selector.match_names),
+        }
+    }
+}
+
+/// Script to Device and Device to Script index.
+/// Every update keeps both directions consistent, and empty sets are never kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelectorIndex {
+    /// Script to selected devices
+    scripts: HashMap<ResourceIndex<Script>, SelectedDevices>,
+    /// Device to Scripts reading it
+    readers: HashMap<ResourceIndex<Device>, ScriptSet>,
+    /// Device to Scripts writing it
+    writers: HashMap<ResourceIndex<Device>, ScriptSet>,
+}
+
+impl SelectorIndex {
+    /// Build the index from scratch
+    pub fn from_scripts<'a>(scripts: impl IntoIterator<Item = &'a Script>) -> Self {
+        let mut index = SelectorIndex::default();
+        for s in scripts {
+            index.insert(s.into(), s.into());
+        }
+        index
+    }
+
+    /// Insert or replace the devices selected by a Script
+    pub fn insert(&mut self, script: ResourceIndex<Script>, selected: SelectedDevices) {
+        self.remove(&script);
+        for dev in &selected.read {
+            self.readers
+                .entry(dev.clone())
+                .or_default()
+                .insert(script.clone());
+        }
+        for dev in &selected.write {
+            self.writers
+                .entry(dev.clone())
+                .or_default()
+                .insert(script.clone());
+        }
+        self.scripts.insert(script, selected);
+    }
+
+    /// Remove a Script, return the devices it selected
+    pub fn remove(&mut self, script: &ResourceIndex<Script>) -> Option<SelectedDevices> {
+        let selected = self.scripts.remove(script)?;
+        Self::unlink(&mut self.readers, &selected.read, script);
+        Self::unlink(&mut self.writers, &selected.write, script);
+        Some(selected)
+    }
+
+    fn unlink(
+        map: &mut HashMap<ResourceIndex<Device>, ScriptSet>,
+        devices: &DeviceSet,
+        script: &ResourceIndex<Script>,
+    ) {
+        for dev in devices {
+            if let Some(set) = map.get_mut(dev) {
+                set.remove(script);
+                if set.is_empty() {
+                    map.remove(dev);
+                }
+            }
+        }
+    }
+
+    /// Devices selected by a Script
+    pub fn selected(&self, script: &ResourceIndex<Script>) -> Option<&SelectedDevices> {
+        self.scripts.get(script)
+    }
+
+    /// Scripts reading a device
+    pub fn readers(&self, device: &ResourceIndex<Device>) -> Vec<ResourceIndex<Script>> {
+        self.readers
+            .get(device)
+            .map(|s| s.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Scripts writing a device
+    pub fn writers(&self, device: &ResourceIndex<Device>) -> Vec<ResourceIndex<Script>> {
+        self.writers
+            .get(device)
+            .map(|s| s.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// All indexed Scripts
+    pub fn scripts(&self) -> impl Iterator<Item = &ResourceIndex<Script>> {
+        self.scripts.keys()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::scheduler::Reflector;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    const DEVICES: &[&str] = &["dht11", "switch", "heater", "fan", "lamp"];
+    const SCRIPTS: &[&str] = &["a", "b", "c", "d"];
+
+    pub(crate) fn script(name: &str, read: &[&str], write: &[&str]) -> Script {
+        let names = |devices: &[&str]| -> HashMap<String, String> {
+            devices
+                .iter()
+                .map(|d| (format!("{d}-alias"), d.to_string()))
+                .collect()
+        };
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "hit.edu.cn/v1alpha1",
+            "kind": "Script",
+            "metadata": { "name": name, "namespace": "default" },
+            "spec": {
+                "readSelector": { "matchNames": names(read) },
+                "writeSelector": { "matchNames": names(write) },
+                "env": {},
+                "manifest": { "scriptType": "Js", "name": "test", "version": "0.1_beta1" },
+                "executePolicy": {
+                    "readChange": true,
+                    "webhook": true,
+                    "cron": "",
+                    "qos": "AtMostOnce",
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn random_devices(rng: &mut SmallRng) -> Vec<&'static str> {
+        DEVICES
+            .iter()
+            .copied()
+            .filter(|_| rng.gen_bool(0.4))
+            .collect()
+    }
+
+    fn random_script(rng: &mut SmallRng) -> Script {
+        let name = SCRIPTS[rng.gen_range(0..SCRIPTS.len())];
+        let read = random_devices(rng);
+        let write = random_devices(rng);
+        script(name, &read, &write)
+    }
+
+    fn assert_consistent(reflector: &Reflector) {
+        let scripts: Vec<Script> = reflector
+            .script_store
+            .iter()
+            .map(|s| s.value().clone())
+            .collect();
+        let index = reflector.index();
+        assert_eq!(*index, SelectorIndex::from_scripts(&scripts));
+        assert_eq!(index.scripts.len(), scripts.len());
+        for (script, selected) in &index.scripts {
+            for dev in &selected.read {
+                assert!(index.readers[dev].contains(script));
+            }
+            for dev in &selected.write {
+                assert!(index.writers[dev].contains(script));
+            }
+        }
+        for (dev, set) in &index.readers {
+            assert!(!set.is_empty());
+            for s in set {
+                assert!(index.scripts[s].read.contains(dev));
+            }
+        }
+        for (dev, set) in &index.writers {
+            assert!(!set.is_empty());
+            for s in set {
+                assert!(index.scripts[s].write.contains(dev));
+            }
+        }
+    }
+
+    #[test]
+    fn test_selector_change() {
+        let reflector = Reflector::default();
+        let dht11 = ResourceIndex {
+            namespace: "default".to_owned(),
+            name: "dht11".to_owned(),
+            api: PhantomData,
+        };
+        reflector.add_script(&script("a", &["dht11"], &["switch"]));
+        assert_eq!(reflector.index().readers(&dht11).len(), 1);
+        reflector.add_script(&script("a", &["fan"], &["switch"]));
+        assert!(reflector.index().readers(&dht11).is_empty());
+        reflector.remove_script(&script("a", &[], &[]));
+        assert_eq!(*reflector.index(), SelectorIndex::default());
+    }
+
+    #[test]
+    fn test_random_events() {
+        for seed in 0..64 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let reflector = Reflector::default();
+            for _ in 0..200 {
+                match rng.gen_range(0..4) {
+                    0 | 1 => reflector.add_script(&random_script(&mut rng)),
+                    2 => {
+                        let name = SCRIPTS[rng.gen_range(0..SCRIPTS.len())];
+                        reflector.remove_script(&script(name, &[], &[]))
+                    }
+                    _ => {
+                        let mut list = HashMap::new();
+                        for _ in 0..rng.gen_range(0..SCRIPTS.len()) {
+                            let s = random_script(&mut rng);
+                            list.insert(ResourceIndex::from(&s), s);
+                        }
+                        let list: Vec<_> = list.into_values().collect();
+                        reflector.restart_script(None, &list);
+                        assert_eq!(reflector.script_store.len(), list.len());
+                    }
+                }
+                assert_consistent(&reflector);
+            }
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! Lease based leader election
+//!
+//! Every controller replica keeps its Reflector warm, but only the holder of the Lease
+//! triggers scripts and serves executor `run` streams. With `leader_label` the leader
+//! labels its pod, so a Service selecting the label routes traffic to the leader only.
+
+use crate::controller::{wait_for_stop, ControllerState};
+use color_eyre::Result;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::{Patch, PatchParams, PostParams};
+use kube::{Api, Client};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{error, info, trace, warn};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeaderConfig {
+    /// name of the Lease resource
+    pub lease_name: String,
+    /// namespace of the Lease resource
+    pub lease_namespace: String,
+    /// identity of this replica, usually the pod name
+    pub identity: String,
+    /// how long a standby waits before taking over a Lease that isn't renewed
+    pub lease_duration_secs: u64,
+    /// how long the leader retries renewing before it gives up leadership
+    pub renew_deadline_secs: u64,
+    /// interval between two attempts to acquire or renew the Lease
+    pub retry_period_secs: u64,
+    /// label set to "true" on the pod `identity` in `lease_namespace` while it leads
+    pub leader_label: Option<String>,
+}
+
+impl LeaderConfig {
+    pub fn new(lease_namespace: String, identity: String) -> Self {
+        LeaderConfig {
+            lease_name: "ruleengine-controller".to_owned(),
+            lease_namespace,
+            identity,
+            lease_duration_secs: 15,
+            renew_deadline_secs: 10,
+            retry_period_secs: 2,
+            leader_label: None,
+        }
+    }
+}
+
+/// Leadership as seen from the results of acquiring or renewing the Lease
+#[derive(Debug, Default)]
+struct Election {
+    last_renew: Option<Instant>,
+}
+
+impl Election {
+    /// Record an attempt, return whether this replica leads after it.
+    /// Failed renewals keep leadership until `renew_deadline` passed since the last success.
+    fn observe(&mut self, result: &Result<bool>, now: Instant, renew_deadline: Duration) -> bool {
+        match result {
+            Ok(true) => {
+                if self.last_renew.is_none() {
+                    info!("Became the leader");
+                }
+                self.last_renew = Some(now);
+            }
+            Ok(false) => {
+                if self.last_renew.take().is_some() {
+                    warn!("Lease is taken by another replica");
+                }
+            }
+            Err(e) => {
+                error!(error =? e, "Failed to acquire or renew the Lease");
+                if matches!(self.last_renew, Some(t) if now.saturating_duration_since(t) > renew_deadline)
+                {
+                    warn!("Failed to renew the Lease before deadline");
+                    self.last_renew = None;
+                }
+            }
+        }
+        self.last_renew.is_some()
+    }
+}
+
+/// Wait until this replica is (`true`) or isn't (`false`) the leader
+#[tracing::instrument(skip_all)]
+pub async fn wait_for_leader(rx: &mut watch::Receiver<bool>, leading: bool) {
+    loop {
+        if *rx.borrow_and_update() == leading {
+            return;
+        }
+        if rx.changed().await.is_err() {
+            // election is gone, the state never changes again
+            futures::future::pending::<()>().await
+        }
+    }
+}
+
+/// Run the election until the controller stops, publish leadership to `leader`.
+/// The Lease is released on stop, so a standby takes over without waiting for expiry.
+#[tracing::instrument(skip_all, fields(identity = %config.identity))]
+pub async fn leader_election(
+    client: Client,
+    config: LeaderConfig,
+    leader: watch::Sender<bool>,
+    mut state: watch::Receiver<ControllerState>,
+) -> Result<()> {
+    let api: Api<Lease> = Api::namespaced(client.clone(), &config.lease_namespace);
+    let pods: Api<Pod> = Api::namespaced(client, &config.lease_namespace);
+    let retry_period = Duration::from_secs(config.retry_period_secs);
+    let renew_deadline = Duration::from_secs(config.renew_deadline_secs);
+    let mut election = Election::default();
+    // the label is written again after a failure
+    let mut labeled = None;
+    loop {
+        let result = try_acquire_or_renew(&api, &config).await;
+        let leading = election.observe(&result, Instant::now(), renew_deadline);
+        if *leader.borrow() != leading {
+            leader.send_replace(leading);
+        }
+        if labeled != Some(leading) && label_pod(&pods, &config, leading).await {
+            labeled = Some(leading);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(retry_period) => {}
+            _ = wait_for_stop(&mut state) => break,
+        }
+    }
+
+    if *leader.borrow() {
+        leader.send_replace(false);
+        label_pod(&pods, &config, false).await;
+        if let Err(e) = release(&api, &config).await {
+            error!(error =? e, "Failed to release the Lease");
+        }
+    }
+    Ok(())
+}
+
+/// Set the leader label of this replica's pod, return `false` if it failed
+async fn label_pod(api: &Api<Pod>, config: &LeaderConfig, leading: bool) -> bool {
+    let label = match &config.leader_label {
+        Some(label) => label,
+        None => return true,
+    };
+    let patch = serde_json::json!({
+        "metadata": { "labels": { label: leading.to_string() } }
+    });
+    match api
+        .patch(
+            &config.identity,
+            &PatchParams::default(),
+            &Patch::Merge(&patch),
+        )
+        .await
+    {
+        Ok(_) => true,
+        Err(e) => {
+            error!(error =? e, label = %label, leading, "Failed to label the pod");
+            false
+        }
+    }
+}
+
+fn now() -> MicroTime {
+    MicroTime(Utc::now())
+}
+
+fn is_expired(spec: &LeaseSpec, now: &MicroTime) -> bool {
+    match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(renew), Some(duration)) => {
+            renew.0 + chrono::Duration::seconds(duration as i64) < now.0
+        }
+        _ => true,
+    }
+}
+
+/// Take or renew the Lease for this replica, return `false` if another replica holds it
+fn acquire(spec: &mut LeaseSpec, config: &LeaderConfig, now: MicroTime) -> bool {
+    let holder = spec.holder_identity.as_deref().unwrap_or("");
+    if holder == config.identity {
+        spec.renew_time = Some(now);
+    } else if holder.is_empty() || is_expired(spec, &now) {
+        trace!(holder, "Take over the Lease");
+        spec.holder_identity = Some(config.identity.clone());
+        spec.acquire_time = Some(now.clone());
+        spec.renew_time = Some(now);
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+    } else {
+        return false;
+    }
+    spec.lease_duration_seconds = Some(config.lease_duration_secs as i32);
+    true
+}
+
+/// Return `true` if this replica holds the Lease after the attempt
+async fn try_acquire_or_renew(api: &Api<Lease>, config: &LeaderConfig) -> Result<bool> {
+    let pp = PostParams::default();
+    let mut lease = match api.get(&config.lease_name).await {
+        Ok(lease) => lease,
+        Err(kube::Error::Api(e)) if e.code == 404 => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(config.lease_name.clone()),
+                    namespace: Some(config.lease_namespace.clone()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(config.identity.clone()),
+                    acquire_time: Some(now()),
+                    renew_time: Some(now()),
+                    lease_duration_seconds: Some(config.lease_duration_secs as i32),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return match api.create(&pp, &lease).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if !acquire(
+        lease.spec.get_or_insert_with(Default::default),
+        config,
+        now(),
+    ) {
+        return Ok(false);
+    }
+    // `replace` keeps the resourceVersion, so concurrent updates conflict
+    match api.replace(&config.lease_name, &pp, &lease).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn release(api: &Api<Lease>, config: &LeaderConfig) -> Result<()> {
+    let mut lease = api.get(&config.lease_name).await?;
+    if let Some(spec) = lease.spec.as_mut() {
+        if spec.holder_identity.as_deref() == Some(config.identity.as_str()) {
+            spec.holder_identity = None;
+            spec.renew_time = None;
+            api.replace(&config.lease_name, &PostParams::default(), &lease)
+                .await?;
+            info!("Released the Lease");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    fn at(secs: i64) -> MicroTime {
+        MicroTime(Utc::now() + chrono::Duration::seconds(secs))
+    }
+
+    #[test]
+    fn test_acquire() {
+        let config = LeaderConfig::new("default".to_owned(), "a".to_owned());
+        let mut spec = LeaseSpec::default();
+        assert!(acquire(&mut spec, &config, at(0)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+        assert_eq!(spec.lease_transitions, Some(1));
+        assert_eq!(spec.lease_duration_seconds, Some(15));
+
+        // another replica can't take a Lease renewed in time
+        let other = LeaderConfig::new("default".to_owned(), "b".to_owned());
+        assert!(!acquire(&mut spec, &other, at(10)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_renew() {
+        let config = LeaderConfig::new("default".to_owned(), "a".to_owned());
+        let mut spec = LeaseSpec::default();
+        assert!(acquire(&mut spec, &config, at(0)));
+        let acquired = spec.acquire_time.clone();
+        assert!(acquire(&mut spec, &config, at(10)));
+        assert_eq!(spec.acquire_time, acquired);
+        assert_eq!(spec.lease_transitions, Some(1));
+        // renewed at 10, so still held at 20
+        let other = LeaderConfig::new("default".to_owned(), "b".to_owned());
+        assert!(!acquire(&mut spec, &other, at(20)));
+    }
+
+    #[test]
+    fn test_lose_lease() {
+        let config = LeaderConfig::new("default".to_owned(), "a".to_owned());
+        let mut spec = LeaseSpec::default();
+        assert!(acquire(&mut spec, &config, at(0)));
+        // not renewed for longer than the lease duration, a standby takes over
+        let other = LeaderConfig::new("default".to_owned(), "b".to_owned());
+        assert!(acquire(&mut spec, &other, at(16)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("b"));
+        assert_eq!(spec.lease_transitions, Some(2));
+        assert!(!acquire(&mut spec, &config, at(17)));
+
+        let deadline = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut election = Election::default();
+        assert!(election.observe(&Ok(true), start, deadline));
+        // failed renewals keep leadership until the deadline
+        let failed = Err(eyre!("apiserver unreachable"));
+        assert!(election.observe(&failed, start + Duration::from_secs(5), deadline));
+        assert!(!election.observe(&failed, start + Duration::from_secs(11), deadline));
+        assert!(election.observe(&Ok(true), start + Duration::from_secs(12), deadline));
+        // the Lease was taken by another replica
+        assert!(!election.observe(&Ok(false), start + Duration::from_secs(13), deadline));
+    }
+}
@@ -1,7 +1,20 @@
 pub mod api;
+pub mod auth;
+pub mod broker;
+pub mod cancel;
+pub mod commit;
 pub mod controller;
+pub mod drain;
+pub mod history;
 pub mod id;
+pub mod index;
+pub mod leader;
+pub mod locality;
+pub mod logs;
+pub mod queue;
+pub mod quota;
 pub mod scheduler;
 pub mod server;
 pub mod session;
+pub mod snapshot;
 pub mod trigger;
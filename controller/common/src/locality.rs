@@ -0,0 +1,149 @@
+//! Node-local dispatch
+//!
+//! Executors report the node they run on. A run is preferably dispatched to an
+//! executor on a node selected by the `nodeSelector` of the Script's devices.
+
+use k8s_openapi::api::core::v1::{NodeSelector, NodeSelectorRequirement};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Label holding the node name, KubeEdge devices also select nodes by an empty key
+const HOSTNAME_LABEL: &str = "kubernetes.io/hostname";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocalityConfig {
+    /// where a run goes when no executor on its devices' node can take it
+    pub fallback: LocalityFallback,
+    /// seconds a run waits for a local executor with `LocalityFallback::Wait`,
+    /// with `LocalityFallback::Never` a run is rejected after it while none is connected
+    pub wait_secs: u64,
+}
+
+impl Default for LocalityConfig {
+    fn default() -> Self {
+        LocalityConfig {
+            fallback: LocalityFallback::Wait,
+            wait_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocalityFallback {
+    /// run anywhere when no local executor has a free job slot
+    Any,
+    /// wait `wait_secs` for a local executor, then run anywhere
+    Wait,
+    /// only run on local executors
+    Never,
+}
+
+impl std::str::FromStr for LocalityFallback {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(LocalityFallback::Any),
+            "wait" => Ok(LocalityFallback::Wait),
+            "never" => Ok(LocalityFallback::Never),
+            _ => Err("Unknown locality fallback"),
+        }
+    }
+}
+
+/// Node an executor runs on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub name: String,
+    pub labels: HashMap<String, String>,
+}
+
+impl NodeInfo {
+    fn label(&self, key: &str) -> Option<&str> {
+        match key {
+            "" | HOSTNAME_LABEL if !self.name.is_empty() => Some(&self.name),
+            _ => self.labels.get(key).map(|v| v.as_str()),
+        }
+    }
+
+    fn matches_requirement(&self, req: &NodeSelectorRequirement, value: Option<&str>) -> bool {
+        let values = req.values.as_deref().unwrap_or_default();
+        match req.operator.as_str() {
+            "In" => value.map_or(false, |v| values.iter().any(|x| x == v)),
+            "NotIn" => value.map_or(true, |v| values.iter().all(|x| x != v)),
+            "Exists" => value.is_some(),
+            "DoesNotExist" => value.is_none(),
+            // Gt and Lt never select a node here
+            _ => false,
+        }
+    }
+
+    /// Whether the node is selected, terms are ORed and requirements of a term are ANDed
+    pub fn matches(&self, selector: &NodeSelector) -> bool {
+        selector.node_selector_terms.iter().any(|term| {
+            let exprs = term.match_expressions.as_deref().unwrap_or_default();
+            let fields = term.match_fields.as_deref().unwrap_or_default();
+            if exprs.is_empty() && fields.is_empty() {
+                return false;
+            }
+            exprs
+                .iter()
+                .all(|req| self.matches_requirement(req, self.label(&req.key)))
+                && fields.iter().all(|req| {
+                    let value = (req.key == "metadata.name").then(|| self.name.as_str());
+                    self.matches_requirement(req, value)
+                })
+        })
+    }
+
+    /// Whether the node is selected by any of the device selectors
+    pub fn local_to(&self, selectors: &[NodeSelector]) -> bool {
+        selectors.iter().any(|s| self.matches(s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn selector(value: serde_json::Value) -> NodeSelector {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_node_selector() {
+        let node = NodeInfo {
+            name: "edge-1".to_owned(),
+            labels: HashMap::from([("where".to_owned(), "node2".to_owned())]),
+        };
+        // KubeEdge style
+        let kubeedge = selector(serde_json::json!({
+            "nodeSelectorTerms": [{
+                "matchExpressions": [{ "key": "", "operator": "In", "values": ["edge-1"] }]
+            }]
+        }));
+        assert!(node.matches(&kubeedge));
+        assert!(!NodeInfo::default().matches(&kubeedge));
+
+        let labels = selector(serde_json::json!({
+            "nodeSelectorTerms": [
+                { "matchFields": [{ "key": "metadata.name", "operator": "In", "values": ["edge-2"] }] },
+                { "matchExpressions": [
+                    { "key": "where", "operator": "In", "values": ["node2"] },
+                    { "key": "gpu", "operator": "DoesNotExist" }
+                ] }
+            ]
+        }));
+        assert!(node.matches(&labels));
+        let other = NodeInfo {
+            name: "edge-2".to_owned(),
+            ..Default::default()
+        };
+        assert!(other.matches(&labels));
+
+        let empty = selector(serde_json::json!({ "nodeSelectorTerms": [{}] }));
+        assert!(!node.matches(&empty));
+        assert!(node.local_to(&[empty, kubeedge]));
+    }
+}
@@ -0,0 +1,179 @@
+//! Console output of runs
+//!
+//! Executors stream the console output of a run with `push_logs` while it executes.
+//! The last `lines` lines of each Script are kept in memory, new lines are
+//! broadcast so a run can be tailed.
+
+use dashmap::DashSet;
+use k8s_openapi::chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// console lines kept per Script, 0 to disable
+    pub lines: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { lines: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogLine {
+    /// increases with every line received by the controller
+    pub seq: u64,
+    pub run_id: String,
+    pub script_id: u32,
+    /// unix ms timestamp
+    pub time: i64,
+    /// 0 trace, 1 debug, 2 info, 3 warn, 4 error
+    pub level: u32,
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            0 => "TRACE",
+            1 => "DEBUG",
+            2 => "INFO",
+            3 => "WARN",
+            _ => "ERROR",
+        };
+        match Utc.timestamp_millis_opt(self.time).single() {
+            Some(time) => write!(f, "{} {:5} {}", time.to_rfc3339(), level, self.message),
+            None => write!(f, "{} {:5} {}", self.time, level, self.message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LogEvent {
+    Line(Arc<LogLine>),
+    /// the executor finished streaming the run
+    Closed(String),
+}
+
+#[derive(Debug)]
+pub struct ScriptLogs {
+    config: LogConfig,
+    /// (namespace, name) to lines, oldest first
+    lines: Mutex<HashMap<(String, String), VecDeque<Arc<LogLine>>>>,
+    /// runs whose executor is streaming logs
+    open: DashSet<String>,
+    seq: AtomicU64,
+    events: broadcast::Sender<LogEvent>,
+}
+
+impl ScriptLogs {
+    pub fn new(config: LogConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        ScriptLogs {
+            config,
+            lines: Default::default(),
+            open: Default::default(),
+            seq: Default::default(),
+            events,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), VecDeque<Arc<LogLine>>>> {
+        self.lines.lock().expect("ScriptLogs poisoned")
+    }
+
+    pub fn open(&self, run_id: &str) {
+        self.open.insert(run_id.to_owned());
+    }
+
+    pub fn close(&self, run_id: &str) {
+        self.open.remove(run_id);
+        let _ = self.events.send(LogEvent::Closed(run_id.to_owned()));
+    }
+
+    pub fn is_open(&self, run_id: &str) -> bool {
+        self.open.contains(run_id)
+    }
+
+    /// Keep a line of a Script, `seq` is assigned here
+    pub fn push(&self, namespace: &str, name: &str, mut line: LogLine) {
+        if self.config.lines == 0 {
+            return;
+        }
+        line.seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let line = Arc::new(line);
+        {
+            let mut lines = self.lock();
+            let script = lines
+                .entry((namespace.to_owned(), name.to_owned()))
+                .or_default();
+            script.push_back(line.clone());
+            while script.len() > self.config.lines {
+                script.pop_front();
+            }
+        }
+        let _ = self.events.send(LogEvent::Line(line));
+    }
+
+    /// Kept lines of a run, oldest first
+    pub fn run(&self, run_id: &str) -> Vec<Arc<LogLine>> {
+        self.lock()
+            .values()
+            .flatten()
+            .filter(|l| l.run_id == run_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(run_id: &str, message: &str) -> LogLine {
+        LogLine {
+            seq: 0,
+            run_id: run_id.to_owned(),
+            script_id: 1,
+            time: 0,
+            level: 2,
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        let logs = ScriptLogs::new(LogConfig { lines: 2 });
+        let mut events = logs.subscribe();
+        logs.open("a");
+        logs.push("default", "script", line("a", "one"));
+        logs.push("default", "script", line("a", "two"));
+        logs.push("default", "script", line("b", "three"));
+        logs.push("default", "other", line("c", "four"));
+        logs.close("a");
+
+        let run: Vec<_> = logs.run("a").iter().map(|l| l.message.clone()).collect();
+        assert_eq!(run, vec!["two"]);
+        assert_eq!(logs.run("b")[0].seq, 3);
+        assert!(!logs.is_open("a"));
+        assert!(matches!(events.try_recv(), Ok(LogEvent::Line(l)) if l.message == "one"));
+        assert_eq!(
+            line("a", "hello").to_string(),
+            "1970-01-01T00:00:00+00:00 INFO  hello"
+        );
+    }
+}
@@ -0,0 +1,701 @@
+//! Pending runs waiting for an executor
+//!
+//! Runs are kept in one queue per script type and priority class, an executor
+//! only takes runs of the script types it can execute.
+//! Higher priority runs are taken first, a pending run is promoted one class
+//! every `aging_secs` so low priority work is not starved.
+//! An executor may also pass over runs that are better served by another
+//! executor, such runs are checked again every `RECHECK`.
+//! A trigger of a Script that is already queued is merged into the queued run.
+//! When the queue is full a queued run of lower priority is preempted,
+//! otherwise the overflow policy applies.
+
+use crate::api::script::Priority;
+use crate::scheduler::{ManagerMsg, TriggerCause};
+use proto::server_message::run_script::manifest::ScriptType;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often a waiting executor looks again at runs it passed over
+const RECHECK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// pending runs kept in the queue
+    pub capacity: usize,
+    /// a pending run is promoted one priority class per this many seconds, 0 to disable
+    pub aging_secs: u64,
+    /// what to do when the queue is full and nothing can be preempted
+    pub overflow: OverflowPolicy,
+    /// merge triggers of a Script into its queued run
+    pub coalesce: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: 100,
+            aging_secs: 30,
+            overflow: OverflowPolicy::Block,
+            coalesce: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// wait for free space, triggers are held back
+    Block,
+    /// drop the oldest queued run not above the new run's priority
+    DropOldest,
+    /// drop the new run
+    DropNewest,
+    /// merge into a queued run of the same Script, drop the new run if there is none
+    Coalesce,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            _ => Err("Unknown overflow policy"),
+        }
+    }
+}
+
+/// Why a run left the queue without being dispatched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// a run of higher priority took its place
+    Preempted,
+    /// the queue was full
+    Overflow,
+}
+
+/// A pending run as shown by the queue api
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedRun {
+    pub run_id: String,
+    pub script_id: u32,
+    pub name: String,
+    pub namespace: String,
+    pub priority: Priority,
+    pub script_type: String,
+    pub causes: Vec<TriggerCause>,
+    /// time spent in the queue in ms
+    pub age: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RunQueue {
+    config: QueueConfig,
+    pending: Mutex<Pending>,
+    /// a run was queued
+    notify: Notify,
+    /// a run left the queue
+    space: Notify,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// arrival order across all queues
+    seq: u64,
+    enqueued: Instant,
+    msg: ManagerMsg,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    seq: u64,
+    queues: HashMap<(ScriptType, Priority), VecDeque<Entry>>,
+}
+
+impl Pending {
+    fn len(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum()
+    }
+
+    /// Remove the newest run of the lowest priority class below `priority`,
+    /// runs are compared by their `effective` priority class after aging
+    fn preempt(&mut self, priority: u64, effective: impl Fn(&Entry) -> u64) -> Option<ManagerMsg> {
+        let (_, seq) = self
+            .queues
+            .values()
+            .flatten()
+            .map(|e| (effective(e), e.seq))
+            .filter(|(p, _)| *p < priority)
+            .min_by_key(|(p, seq)| (*p, Reverse(*seq)))?;
+        self.remove(seq)
+    }
+
+    /// Remove the oldest run not above `priority` after aging
+    fn drop_oldest(
+        &mut self,
+        priority: u64,
+        effective: impl Fn(&Entry) -> u64,
+    ) -> Option<ManagerMsg> {
+        let seq = self
+            .queues
+            .values()
+            .flatten()
+            .filter(|e| effective(e) <= priority)
+            .map(|e| e.seq)
+            .min()?;
+        self.remove(seq)
+    }
+
+    fn remove(&mut self, seq: u64) -> Option<ManagerMsg> {
+        let (key, index) = self.queues.iter().find_map(|(key, q)| {
+            let index = q.iter().position(|e| e.seq == seq)?;
+            Some((*key, index))
+        })?;
+        let queue = self.queues.get_mut(&key)?;
+        let entry = queue.remove(index);
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        }
+        entry.map(|e| e.msg)
+    }
+
+    /// Merge `msg` into a queued run of the same Script, the queued run keeps its place
+    fn coalesce(&mut self, msg: ManagerMsg) -> Result<(), ManagerMsg> {
+        let queued = self
+            .queues
+            .values_mut()
+            .flatten()
+            .find(|e| e.msg.same_script(&msg));
+        match queued {
+            Some(entry) => {
+                entry.msg.merge(msg);
+                Ok(())
+            }
+            None => Err(msg),
+        }
+    }
+}
+
+impl RunQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        RunQueue {
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().expect("RunQueue poisoned")
+    }
+
+    /// Priority class after aging
+    fn effective(&self, priority: Priority, enqueued: Instant, now: Instant) -> u64 {
+        let promoted = match self.config.aging_secs {
+            0 => 0,
+            secs => now.saturating_duration_since(enqueued).as_secs() / secs,
+        };
+        priority as u64 + promoted
+    }
+
+    /// Queue a run and return the run dropped to make room, if any.
+    /// With `OverflowPolicy::Block` this waits until the queue has space.
+    pub async fn push(&self, mut msg: ManagerMsg) -> Option<(ManagerMsg, Dropped)> {
+        loop {
+            // register before checking, so a pop in between is not missed
+            let space = self.space.notified();
+            match self.try_push(msg, Instant::now()) {
+                Ok(dropped) => return dropped,
+                Err(m) => msg = m,
+            }
+            space.await;
+        }
+    }
+
+    /// Queue a run without waiting, the run is given back if the queue is full
+    /// and the policy is `OverflowPolicy::Block`
+    fn try_push(
+        &self,
+        msg: ManagerMsg,
+        enqueued: Instant,
+    ) -> Result<Option<(ManagerMsg, Dropped)>, ManagerMsg> {
+        let dropped = {
+            let mut pending = self.lock();
+            let msg = if self.config.coalesce {
+                match pending.coalesce(msg) {
+                    Ok(()) => return Ok(None),
+                    Err(msg) => msg,
+                }
+            } else {
+                msg
+            };
+            // queued runs keep the priority they aged to, the new run has none yet
+            let priority = msg.priority as u64;
+            let effective = |e: &Entry| self.effective(e.msg.priority, e.enqueued, enqueued);
+            let dropped = if pending.len() < self.config.capacity {
+                None
+            } else if let Some(preempted) = pending.preempt(priority, effective) {
+                Some((preempted, Dropped::Preempted))
+            } else {
+                match self.config.overflow {
+                    OverflowPolicy::Block => return Err(msg),
+                    OverflowPolicy::DropNewest => return Ok(Some((msg, Dropped::Overflow))),
+                    OverflowPolicy::DropOldest => match pending.drop_oldest(priority, effective) {
+                        Some(oldest) => Some((oldest, Dropped::Overflow)),
+                        None => return Ok(Some((msg, Dropped::Overflow))),
+                    },
+                    OverflowPolicy::Coalesce => {
+                        return Ok(pending.coalesce(msg).err().map(|m| (m, Dropped::Overflow)))
+                    }
+                }
+            };
+            pending.seq += 1;
+            let seq = pending.seq;
+            let mut msg = msg;
+            msg.queued();
+            pending
+                .queues
+                .entry((msg.script_type(), msg.priority))
+                .or_default()
+                .push_back(Entry { seq, enqueued, msg });
+            dropped
+        };
+        self.notify.notify_waiters();
+        Ok(dropped)
+    }
+
+    /// Take the run of any of `types` with the highest priority after aging,
+    /// the oldest run wins a tie
+    pub fn try_pop(&self, types: &[ScriptType]) -> Option<ManagerMsg> {
+        self.try_pop_with(types, |_, _| true)
+    }
+
+    /// Like `try_pop`, but only take runs `eligible` accepts given their time in the queue
+    pub fn try_pop_with(
+        &self,
+        types: &[ScriptType],
+        eligible: impl Fn(&ManagerMsg, Duration) -> bool,
+    ) -> Option<ManagerMsg> {
+        let now = Instant::now();
+        let mut pending = self.lock();
+        let (key, index) = pending
+            .queues
+            .iter()
+            .filter(|((ty, _), _)| types.contains(ty))
+            .filter_map(|(key, q)| {
+                let (index, entry) = q
+                    .iter()
+                    .enumerate()
+                    .find(|(_, e)| eligible(&e.msg, now.saturating_duration_since(e.enqueued)))?;
+                Some((key, index, entry))
+            })
+            .max_by_key(|(key, _, e)| (self.effective(key.1, e.enqueued, now), Reverse(e.seq)))
+            .map(|(key, index, _)| (*key, index))?;
+        let queue = pending.queues.get_mut(&key)?;
+        let entry = queue.remove(index);
+        if queue.is_empty() {
+            pending.queues.remove(&key);
+        }
+        self.space.notify_waiters();
+        entry.map(|e| e.msg)
+    }
+
+    /// Wait for a run of any of `types`
+    pub async fn pop(&self, types: &[ScriptType]) -> ManagerMsg {
+        self.pop_with(types, || |_: &ManagerMsg, _| true).await
+    }
+
+    /// Wait for a run of any of `types` that the predicate made by `eligible` accepts.
+    /// The predicate is made again on every check before the queue is locked, so it can
+    /// hold a snapshot of other shared state.
+    pub async fn pop_with<F>(&self, types: &[ScriptType], eligible: impl Fn() -> F) -> ManagerMsg
+    where
+        F: Fn(&ManagerMsg, Duration) -> bool,
+    {
+        loop {
+            // register before checking, so a push in between is not missed
+            let notified = self.notify.notified();
+            if let Some(msg) = self.try_pop_with(types, eligible()) {
+                return msg;
+            }
+            // eligibility changes with time and with other executors
+            let _ = tokio::time::timeout(RECHECK, notified).await;
+        }
+    }
+
+    /// Let waiting executors look at the queue again
+    pub fn wake(&self) {
+        self.notify.notify_waiters();
+    }
+
+    /// Remove all pending runs of a script type
+    pub fn drain(&self, ty: ScriptType) -> Vec<ManagerMsg> {
+        let mut pending = self.lock();
+        let keys: Vec<_> = pending
+            .queues
+            .keys()
+            .filter(|(t, _)| *t == ty)
+            .copied()
+            .collect();
+        let mut entries: Vec<Entry> = keys
+            .iter()
+            .filter_map(|key| pending.queues.remove(key))
+            .flatten()
+            .collect();
+        entries.sort_by_key(|e| e.seq);
+        self.space.notify_waiters();
+        entries.into_iter().map(|e| e.msg).collect()
+    }
+
+    /// Remove the pending runs matching `pred`, in arrival order
+    pub fn cancel_where(&self, pred: impl Fn(&ManagerMsg) -> bool) -> Vec<ManagerMsg> {
+        self.take_where(|e| pred(&e.msg))
+    }
+
+    /// Remove the pending runs `pred` gives up on given their time in the queue, in arrival order
+    pub fn expire_where(&self, pred: impl Fn(&ManagerMsg, Duration) -> bool) -> Vec<ManagerMsg> {
+        let now = Instant::now();
+        self.take_where(|e| pred(&e.msg, now.saturating_duration_since(e.enqueued)))
+    }
+
+    fn take_where(&self, pred: impl Fn(&Entry) -> bool) -> Vec<ManagerMsg> {
+        let mut pending = self.lock();
+        let mut entries = Vec::new();
+        for queue in pending.queues.values_mut() {
+            let (matched, kept): (Vec<Entry>, Vec<Entry>) = queue.drain(..).partition(&pred);
+            *queue = kept.into();
+            entries.extend(matched);
+        }
+        pending.queues.retain(|_, q| !q.is_empty());
+        if !entries.is_empty() {
+            self.space.notify_waiters();
+        }
+        entries.sort_by_key(|e| e.seq);
+        entries.into_iter().map(|e| e.msg).collect()
+    }
+
+    /// Pending runs in arrival order
+    pub fn list(&self) -> Vec<QueuedRun> {
+        let now = Instant::now();
+        let pending = self.lock();
+        let mut entries: Vec<&Entry> = pending.queues.values().flatten().collect();
+        entries.sort_by_key(|e| e.seq);
+        entries
+            .into_iter()
+            .map(|e| QueuedRun {
+                run_id: e.msg.run.run_id.clone(),
+                script_id: e.msg.run.script_id,
+                name: e.msg.name.clone(),
+                namespace: e.msg.namespace.clone(),
+                priority: e.msg.priority,
+                script_type: format!("{:?}", e.msg.script_type()),
+                causes: e.msg.causes.clone(),
+                age: now.saturating_duration_since(e.enqueued).as_millis() as u64,
+            })
+            .collect()
+    }
+
+    /// Pending runs of a namespace
+    pub fn queued_in(&self, namespace: &str) -> usize {
+        self.lock()
+            .queues
+            .values()
+            .flatten()
+            .filter(|e| e.msg.namespace == namespace)
+            .count()
+    }
+
+    /// Whether a new trigger of the Script would be merged into its queued run
+    pub fn would_coalesce(&self, name: &str, namespace: &str) -> bool {
+        self.config.coalesce
+            && self
+                .lock()
+                .queues
+                .values()
+                .flatten()
+                .any(|e| e.msg.name == name && e.msg.namespace == namespace)
+    }
+
+    /// Script types with pending runs
+    pub fn script_types(&self) -> Vec<ScriptType> {
+        let mut types: Vec<ScriptType> = self.lock().queues.keys().map(|(ty, _)| *ty).collect();
+        types.sort_unstable();
+        types.dedup();
+        types
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use proto::server_message::{run_script::Manifest, RunScript};
+    use std::time::Duration;
+
+    pub(crate) fn msg(name: &str, ty: ScriptType) -> ManagerMsg {
+        ManagerMsg {
+            run: RunScript {
+                manifest: Some(Manifest {
+                    script_type: ty as i32,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            name: name.to_owned(),
+            namespace: "default".to_owned(),
+            priority: Priority::Normal,
+            causes: vec![TriggerCause::Webhook],
+            node_selectors: Vec::new(),
+            attempts: 0,
+            reply: None,
+        }
+    }
+
+    fn prio(name: &str, priority: Priority) -> ManagerMsg {
+        ManagerMsg {
+            priority,
+            ..msg(name, ScriptType::Js)
+        }
+    }
+
+    fn push(queue: &RunQueue, msg: ManagerMsg) -> Option<(ManagerMsg, Dropped)> {
+        queue.try_push(msg, Instant::now()).unwrap()
+    }
+
+    fn bounded(capacity: usize, overflow: OverflowPolicy) -> RunQueue {
+        RunQueue::new(QueueConfig {
+            capacity,
+            aging_secs: 0,
+            overflow,
+            coalesce: false,
+        })
+    }
+
+    #[test]
+    fn test_script_type_queues() {
+        let queue = RunQueue::default();
+        push(&queue, msg("a", ScriptType::Wasm));
+        push(&queue, msg("b", ScriptType::Js));
+        push(&queue, msg("c", ScriptType::Wasm));
+        assert_eq!(queue.try_pop(&[ScriptType::Js]).unwrap().name, "b");
+        assert!(queue.try_pop(&[ScriptType::Js]).is_none());
+        assert_eq!(
+            queue
+                .try_pop(&[ScriptType::Js, ScriptType::Wasm])
+                .unwrap()
+                .name,
+            "a"
+        );
+        assert_eq!(queue.drain(ScriptType::Wasm).len(), 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_pop_eligible() {
+        let queue = RunQueue::default();
+        push(&queue, msg("remote", ScriptType::Js));
+        push(&queue, msg("local", ScriptType::Js));
+        let local = |m: &ManagerMsg, _: Duration| m.name == "local";
+        assert_eq!(
+            queue.try_pop_with(&[ScriptType::Js], local).unwrap().name,
+            "local"
+        );
+        assert!(queue.try_pop_with(&[ScriptType::Js], local).is_none());
+        let waited = |_: &ManagerMsg, age: Duration| age >= Duration::from_secs(60);
+        assert!(queue.try_pop_with(&[ScriptType::Js], waited).is_none());
+        assert!(queue.expire_where(waited).is_empty());
+        assert_eq!(queue.expire_where(|_, _| true)[0].name, "remote");
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pop_wait() {
+        let queue = std::sync::Arc::new(RunQueue::default());
+        let q = queue.clone();
+        let handle = tokio::spawn(async move { q.pop(&[ScriptType::Js]).await });
+        tokio::task::yield_now().await;
+        queue.push(msg("a", ScriptType::Wasm)).await;
+        queue.push(msg("b", ScriptType::Js)).await;
+        assert_eq!(handle.await.unwrap().name, "b");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_priority_and_aging() {
+        let queue = RunQueue::default();
+        let now = Instant::now();
+        for (name, priority) in [
+            ("batch", Priority::Low),
+            ("normal", Priority::Normal),
+            ("safety", Priority::High),
+        ] {
+            queue.try_push(prio(name, priority), now).unwrap();
+        }
+        let types = [ScriptType::Js];
+        assert_eq!(queue.try_pop(&types).unwrap().name, "safety");
+        assert_eq!(queue.try_pop(&types).unwrap().name, "normal");
+        assert_eq!(queue.try_pop(&types).unwrap().name, "batch");
+
+        // a starving low priority run catches up with new high priority runs
+        let old = now - Duration::from_secs(2 * queue.config.aging_secs);
+        queue
+            .try_push(prio("starving", Priority::Low), old)
+            .unwrap();
+        push(&queue, prio("safety", Priority::High));
+        assert_eq!(queue.try_pop(&types).unwrap().name, "starving");
+        assert_eq!(queue.try_pop(&types).unwrap().name, "safety");
+    }
+
+    #[test]
+    fn test_preempt() {
+        let queue = bounded(2, OverflowPolicy::DropNewest);
+        assert!(push(&queue, prio("batch-1", Priority::Low)).is_none());
+        assert!(push(&queue, prio("batch-2", Priority::Low)).is_none());
+        let (preempted, reason) = push(&queue, prio("safety", Priority::High)).unwrap();
+        assert_eq!(
+            (preempted.name.as_str(), reason),
+            ("batch-2", Dropped::Preempted)
+        );
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.try_pop(&[ScriptType::Js]).unwrap().name, "safety");
+
+        // a low priority run aged to high isn't preempted by a normal one
+        let queue = RunQueue::new(QueueConfig {
+            capacity: 1,
+            overflow: OverflowPolicy::DropNewest,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let old = now - Duration::from_secs(2 * queue.config.aging_secs);
+        queue
+            .try_push(prio("starving", Priority::Low), old)
+            .unwrap();
+        let (dropped, reason) = queue
+            .try_push(prio("normal", Priority::Normal), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (dropped.name.as_str(), reason),
+            ("normal", Dropped::Overflow)
+        );
+        assert_eq!(queue.try_pop(&[ScriptType::Js]).unwrap().name, "starving");
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        let queue = bounded(2, OverflowPolicy::DropNewest);
+        push(&queue, msg("a", ScriptType::Js));
+        push(&queue, msg("b", ScriptType::Js));
+        let (dropped, _) = push(&queue, msg("c", ScriptType::Js)).unwrap();
+        assert_eq!(dropped.name, "c");
+
+        let queue = bounded(2, OverflowPolicy::DropOldest);
+        push(&queue, msg("a", ScriptType::Js));
+        push(&queue, msg("b", ScriptType::Js));
+        let (dropped, _) = push(&queue, msg("c", ScriptType::Js)).unwrap();
+        assert_eq!(dropped.name, "a");
+        // higher priority runs are never dropped for lower ones
+        let queue = bounded(1, OverflowPolicy::DropOldest);
+        push(&queue, prio("safety", Priority::High));
+        let (dropped, _) = push(&queue, prio("batch", Priority::Low)).unwrap();
+        assert_eq!(dropped.name, "batch");
+
+        let queue = bounded(1, OverflowPolicy::Coalesce);
+        push(&queue, msg("a", ScriptType::Js));
+        assert!(push(&queue, msg("a", ScriptType::Js)).is_none());
+        assert_eq!(queue.len(), 1);
+        assert!(push(&queue, msg("b", ScriptType::Js)).is_some());
+
+        let queue = bounded(1, OverflowPolicy::Block);
+        push(&queue, msg("a", ScriptType::Js));
+        assert!(queue
+            .try_push(msg("b", ScriptType::Js), Instant::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_coalesce_triggers() {
+        let device = |name: &str, changed: &str| {
+            let mut m = msg("a", ScriptType::Js);
+            m.causes = vec![TriggerCause::Device {
+                name: name.to_owned(),
+                changed: [changed.to_owned()].into(),
+            }];
+            m
+        };
+        let queue = RunQueue::default();
+        let (reply, run_ids) = flume::unbounded();
+        let mut first = device("dht11", "temperature");
+        first.run.script_id = 1;
+        first.run.run_id = "1-1".to_owned();
+        first.reply = Some(reply.clone());
+        push(&queue, first);
+        push(&queue, msg("b", ScriptType::Js));
+        let mut merged = device("dht11", "humidity");
+        merged.run.run_id = "1-3".to_owned();
+        merged.reply = Some(reply);
+        push(&queue, merged);
+        push(&queue, device("switch", "power"));
+        assert_eq!(queue.len(), 2);
+        // a merged trigger is served by the queued run
+        assert_eq!(run_ids.drain().collect::<Vec<_>>(), vec!["1-1", "1-1"]);
+
+        let run = queue.try_pop(&[ScriptType::Js]).unwrap();
+        assert_eq!(run.run.script_id, 1);
+        let triggers: Vec<_> = run
+            .run
+            .triggers
+            .iter()
+            .map(|t| (t.device.as_str(), t.changed.clone()))
+            .collect();
+        assert_eq!(
+            triggers,
+            vec![
+                (
+                    "dht11",
+                    vec!["humidity".to_owned(), "temperature".to_owned()]
+                ),
+                ("switch", vec!["power".to_owned()]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_and_cancel() {
+        let queue = std::sync::Arc::new(bounded(1, OverflowPolicy::Block));
+        let mut a = msg("a", ScriptType::Js);
+        a.run.script_id = 1;
+        queue.push(a).await;
+        let q = queue.clone();
+        let blocked = tokio::spawn(async move { q.push(msg("b", ScriptType::Js)).await });
+        tokio::task::yield_now().await;
+        assert_eq!(queue.len(), 1);
+
+        assert!(queue.cancel_where(|m| m.run.script_id == 2).is_empty());
+        assert_eq!(queue.cancel_where(|m| m.run.script_id == 1)[0].name, "a");
+        assert!(blocked.await.unwrap().is_none());
+        let list = queue.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].name, "b");
+
+        assert!(queue.cancel_where(|m| m.name == "a").is_empty());
+        let cancelled = queue.cancel_where(|m| m.name == "b");
+        assert_eq!(cancelled.len(), 1);
+        assert!(queue.is_empty());
+    }
+}
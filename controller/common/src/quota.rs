@@ -0,0 +1,252 @@
+//! Per-namespace run quotas
+//!
+//! A ConfigMap named `ruleengine-quota` limits the runs of the Scripts in its namespace,
+//! with the data keys `runsPerMinute`, `maxConcurrent` and `maxQueued`.
+//! A missing key or 0 means unlimited.
+//! Triggers over the rate or queue limit are dropped by the scheduler and reported
+//! as events on the Script, runs over the concurrency limit wait in the queue.
+
+use crate::api::Script;
+use crate::scheduler::ResourceIndex;
+use color_eyre::{eyre::eyre, Result};
+use dashmap::DashMap;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{Client, Resource, ResourceExt};
+use kube_runtime::events::{Event, EventType, Recorder, Reporter};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// Name of the quota ConfigMap in each namespace
+pub const QUOTA_CONFIGMAP: &str = "ruleengine-quota";
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NamespaceQuota {
+    /// runs admitted per minute
+    pub runs_per_minute: u32,
+    /// runs executing at the same time
+    pub max_concurrent: u32,
+    /// runs waiting in the queue
+    pub max_queued: u32,
+}
+
+impl TryFrom<&ConfigMap> for NamespaceQuota {
+    type Error = color_eyre::Report;
+
+    fn try_from(cm: &ConfigMap) -> Result<Self> {
+        let get = |key: &str| -> Result<u32> {
+            match cm.data.as_ref().and_then(|d| d.get(key)) {
+                Some(v) => v
+                    .trim()
+                    .parse()
+                    .map_err(|e| eyre!("Invalid quota {}: {:?}, {}", key, v, e)),
+                None => Ok(0),
+            }
+        };
+        Ok(NamespaceQuota {
+            runs_per_minute: get("runsPerMinute")?,
+            max_concurrent: get("maxConcurrent")?,
+            max_queued: get("maxQueued")?,
+        })
+    }
+}
+
+/// Why a trigger was not admitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttled {
+    Rate(u32),
+    Queued(u32),
+}
+
+impl Throttled {
+    pub fn message(&self) -> String {
+        match self {
+            Throttled::Rate(limit) => {
+                format!("Namespace exceeds its quota of {} runs per minute", limit)
+            }
+            Throttled::Queued(limit) => {
+                format!("Namespace exceeds its quota of {} queued runs", limit)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Quotas {
+    quotas: DashMap<String, NamespaceQuota>,
+    /// admission times within the last minute
+    admitted: DashMap<String, VecDeque<Instant>>,
+    /// last throttle event of a Script, at most one event per minute is published
+    reported: DashMap<ResourceIndex<Script>, Instant>,
+}
+
+impl Quotas {
+    pub fn get(&self, namespace: &str) -> NamespaceQuota {
+        self.quotas.get(namespace).map(|q| *q).unwrap_or_default()
+    }
+
+    pub fn set(&self, namespace: &str, quota: NamespaceQuota) {
+        self.quotas.insert(namespace.to_owned(), quota);
+    }
+
+    pub fn remove(&self, namespace: &str) {
+        self.quotas.remove(namespace);
+        self.admitted.remove(namespace);
+    }
+
+    /// Apply a quota ConfigMap, an invalid one removes the quota
+    pub fn apply(&self, cm: &ConfigMap) {
+        let namespace = cm.namespace().unwrap_or_default();
+        match NamespaceQuota::try_from(cm) {
+            Ok(quota) => self.set(&namespace, quota),
+            Err(e) => {
+                warn!(error =? e, namespace = %namespace, "Ignore invalid quota");
+                self.remove(&namespace)
+            }
+        }
+    }
+
+    /// Replace the quotas in `namespace` (all namespaces if `None`) with a re-list
+    pub fn restart(&self, namespace: Option<&str>, cms: &[ConfigMap]) {
+        self.quotas
+            .retain(|ns, _| namespace.map_or(false, |scope| scope != ns));
+        for cm in cms {
+            self.apply(cm);
+        }
+    }
+
+    /// Admit a new run of `namespace`, which has `queued` runs waiting
+    pub fn admit(&self, namespace: &str, queued: usize, now: Instant) -> Result<(), Throttled> {
+        let quota = self.get(namespace);
+        if quota.max_queued != 0 && queued >= quota.max_queued as usize {
+            return Err(Throttled::Queued(quota.max_queued));
+        }
+        if quota.runs_per_minute == 0 {
+            return Ok(());
+        }
+        let mut admitted = self.admitted.entry(namespace.to_owned()).or_default();
+        while admitted
+            .front()
+            .map_or(false, |t| now.saturating_duration_since(*t) >= WINDOW)
+        {
+            admitted.pop_front();
+        }
+        if admitted.len() >= quota.runs_per_minute as usize {
+            return Err(Throttled::Rate(quota.runs_per_minute));
+        }
+        admitted.push_back(now);
+        Ok(())
+    }
+
+    /// Whether a run of `namespace` may start while `running` of its runs execute
+    pub fn may_start(&self, namespace: &str, running: usize) -> bool {
+        let quota = self.get(namespace);
+        quota.max_concurrent == 0 || running < quota.max_concurrent as usize
+    }
+
+    /// Whether a throttle event of the Script should be published now
+    fn should_report(&self, script: &ResourceIndex<Script>, now: Instant) -> bool {
+        match self.reported.get_mut(script) {
+            Some(last) if now.saturating_duration_since(*last) < WINDOW => false,
+            Some(mut last) => {
+                *last = now;
+                true
+            }
+            None => {
+                self.reported.insert(script.clone(), now);
+                true
+            }
+        }
+    }
+
+    /// Publish a `Throttled` event on the Script
+    pub async fn report(&self, client: &Client, script: &Script, throttled: Throttled) {
+        let idx = ResourceIndex::from(script);
+        if !self.should_report(&idx, Instant::now()) {
+            return;
+        }
+        let reporter = Reporter {
+            controller: "ruleengine".to_owned(),
+            instance: None,
+        };
+        let recorder = Recorder::new(client.clone(), reporter, script.object_ref(&()));
+        let event = Event {
+            type_: EventType::Warning,
+            reason: "Throttled".to_owned(),
+            note: Some(throttled.message()),
+            action: "Trigger".to_owned(),
+            secondary: None,
+        };
+        if let Err(e) = recorder.publish(event).await {
+            error!(error =? e, script =? idx, "Failed to publish throttle event");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn config_map(data: &[(&str, &str)]) -> ConfigMap {
+        let mut cm = ConfigMap::default();
+        cm.metadata.name = Some(QUOTA_CONFIGMAP.to_owned());
+        cm.metadata.namespace = Some("tenant".to_owned());
+        cm.data = Some(
+            data.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        );
+        cm
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        let quota = NamespaceQuota::try_from(&config_map(&[
+            ("runsPerMinute", "60"),
+            ("maxQueued", " 5 "),
+        ]))
+        .unwrap();
+        assert_eq!(
+            quota,
+            NamespaceQuota {
+                runs_per_minute: 60,
+                max_concurrent: 0,
+                max_queued: 5,
+            }
+        );
+        assert!(NamespaceQuota::try_from(&config_map(&[("maxConcurrent", "-1")])).is_err());
+
+        let quotas = Quotas::default();
+        quotas.apply(&config_map(&[("maxConcurrent", "2")]));
+        assert!(quotas.may_start("tenant", 1));
+        assert!(!quotas.may_start("tenant", 2));
+        assert!(quotas.may_start("other", 100));
+        quotas.restart(Some("tenant"), &[]);
+        assert!(quotas.may_start("tenant", 2));
+    }
+
+    #[test]
+    fn test_admit() {
+        let quotas = Quotas::default();
+        quotas.set(
+            "tenant",
+            NamespaceQuota {
+                runs_per_minute: 2,
+                max_concurrent: 0,
+                max_queued: 3,
+            },
+        );
+        let now = Instant::now();
+        assert!(quotas.admit("tenant", 0, now).is_ok());
+        assert!(quotas.admit("tenant", 0, now).is_ok());
+        assert_eq!(quotas.admit("tenant", 0, now), Err(Throttled::Rate(2)));
+        assert_eq!(quotas.admit("tenant", 3, now), Err(Throttled::Queued(3)));
+        assert!(quotas.admit("other", 100, now).is_ok());
+        // the window slides
+        assert!(quotas.admit("tenant", 0, now + WINDOW).is_ok());
+    }
+}
@@ -1,19 +1,26 @@
+use crate::api::device::{DeviceStatus, Twin, TwinProperty};
+use crate::api::script::Priority;
 use crate::api::{Device, Script};
-use crate::id::ScriptIDGenerator;
+use crate::id::{RunIDGenerator, ScriptIDGenerator};
+use crate::index::{SelectedDevices, SelectorIndex};
 use color_eyre::{eyre::eyre, Result};
 use dashmap::{DashMap, DashSet};
 use flume::{Receiver, Sender};
+use k8s_openapi::api::core::v1::NodeSelector;
 use kube::Resource;
 use proto::server_message::{
-    run_script::{Manifest as ProtoManifest, ReadDevice, WriteDevice},
+    run_script::{
+        manifest::ScriptType as ProtoScriptType, trigger::Source as TriggerSource,
+        Manifest as ProtoManifest, ReadDevice, Trigger as ProtoTrigger, WriteDevice,
+    },
     RunScript,
 };
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tracing::{debug, info, trace};
 
 #[derive(Deserialize, Clone)]
@@ -61,6 +68,143 @@ where
     }
 }
 
+/// A device whose reported twin changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceTrigger {
+    pub device: ResourceIndex<Device>,
+    /// twin properties whose reported value changed, empty if unknown
+    pub changed: BTreeSet<String>,
+}
+
+impl From<ResourceIndex<Device>> for DeviceTrigger {
+    fn from(device: ResourceIndex<Device>) -> Self {
+        DeviceTrigger {
+            device,
+            changed: BTreeSet::new(),
+        }
+    }
+}
+
+/// Why a script run was requested
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TriggerCause {
+    /// reported twin of a readable device changed
+    Device {
+        name: String,
+        changed: BTreeSet<String>,
+    },
+    /// webhook was called
+    Webhook,
+}
+
+impl From<&TriggerCause> for ProtoTrigger {
+    fn from(cause: &TriggerCause) -> Self {
+        match cause {
+            TriggerCause::Device { name, changed } => ProtoTrigger {
+                source: TriggerSource::Device as i32,
+                device: name.clone(),
+                changed: changed.iter().cloned().collect(),
+            },
+            TriggerCause::Webhook => ProtoTrigger {
+                source: TriggerSource::Webhook as i32,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Merge trigger causes, changes of the same device are united
+pub fn merge_causes(causes: &mut Vec<TriggerCause>, new: impl IntoIterator<Item = TriggerCause>) {
+    for cause in new {
+        let merged = causes.iter_mut().any(|c| match (c, &cause) {
+            (
+                TriggerCause::Device { name, changed },
+                TriggerCause::Device {
+                    name: new_name,
+                    changed: new_changed,
+                },
+            ) if name == new_name => {
+                changed.extend(new_changed.iter().cloned());
+                true
+            }
+            (TriggerCause::Webhook, TriggerCause::Webhook) => true,
+            _ => false,
+        });
+        if !merged {
+            causes.push(cause);
+        }
+    }
+}
+
+/// A request to run a script
+#[derive(Debug, Clone)]
+pub struct ScriptTrigger {
+    pub script: ResourceIndex<Script>,
+    pub cause: TriggerCause,
+    /// told the id of the run serving the trigger once it is queued,
+    /// dropped if the trigger is rejected
+    pub reply: Option<Sender<String>>,
+}
+
+/// Names of twin properties whose reported value differs between two versions of a device
+pub fn reported_changes(old: &Device, new: &Device) -> BTreeSet<String> {
+    let old = old.reported();
+    let new = new.reported();
+    let mut changed: BTreeSet<String> = new
+        .iter()
+        .filter(|(k, v)| old.get(*k) != Some(*v))
+        .map(|(k, _)| k.to_owned())
+        .collect();
+    changed.extend(old.keys().filter(|k| !new.contains_key(*k)).cloned());
+    changed
+}
+
+/// Copy the reported values `old` learned over MQTT into `live`, a device from the watch,
+/// unless the watch reported a new value since. Returns the overrides still in effect.
+fn keep_reported(
+    live: &mut Device,
+    old: &Device,
+    overrides: HashMap<String, Option<Twin>>,
+) -> HashMap<String, Option<Twin>> {
+    let reported =
+        |twin: Option<&Twin>| twin.and_then(|t| t.reported.as_ref().map(|r| r.value.clone()));
+    let twin = |dev: &Device, property: &str| {
+        dev.status
+            .as_ref()
+            .and_then(|s| s.twins.iter().find(|t| t.property_name == property))
+            .cloned()
+    };
+    let mut kept = HashMap::new();
+    for (property, watched) in overrides {
+        let fresh = twin(live, &property);
+        if reported(fresh.as_ref()) != reported(watched.as_ref()) {
+            continue;
+        }
+        let learned = match twin(old, &property).and_then(|t| t.reported) {
+            Some(learned) => learned,
+            None => continue,
+        };
+        let status = live
+            .status
+            .get_or_insert_with(|| DeviceStatus { twins: Vec::new() });
+        match status
+            .twins
+            .iter_mut()
+            .find(|t| t.property_name == property)
+        {
+            Some(t) => t.reported = Some(learned),
+            None => status.twins.push(Twin {
+                property_name: property.clone(),
+                desired: TwinProperty::new(String::new()),
+                reported: Some(learned),
+            }),
+        }
+        kept.insert(property, fresh);
+    }
+    kept
+}
+
 pub trait RunScriptLookup {
     fn lookup_script(&mut self, index: &ResourceIndex<Script>) -> Result<Script>;
     fn lookup_device(&mut self, index: &ResourceIndex<Device>) -> Result<Device>;
@@ -70,34 +214,93 @@ pub trait RunScriptLookup {
     ) -> Result<Vec<ResourceIndex<Script>>>;
     fn lookup_readable(&mut self, script: &Script) -> Result<HashMap<String, ReadDevice>>;
     fn lookup_writable(&mut self, script: &Script) -> Result<HashMap<String, WriteDevice>>;
+    /// Node selectors of the devices the Script reads or writes
+    fn lookup_node_selectors(&mut self, script: &Script) -> Result<Vec<NodeSelector>>;
 }
 
+#[derive(Debug, Clone)]
 pub struct ManagerMsg {
     pub run: RunScript,
     pub name: String,
     pub namespace: String,
+    pub priority: Priority,
+    /// triggers served by this run
+    pub causes: Vec<TriggerCause>,
+    /// nodes of the Script's devices, executors there are preferred
+    pub node_selectors: Vec<NodeSelector>,
+    /// times the run was sent to an executor
+    pub attempts: u32,
+    /// told the run id once the run is queued
+    pub reply: Option<Sender<String>>,
+}
+
+impl ManagerMsg {
+    pub fn script_type(&self) -> ProtoScriptType {
+        self.run
+            .manifest
+            .as_ref()
+            .map(|m| m.script_type())
+            .unwrap_or(ProtoScriptType::Js)
+    }
+
+    /// Whether both runs are of the same Script
+    pub fn same_script(&self, other: &ManagerMsg) -> bool {
+        self.name == other.name && self.namespace == other.namespace
+    }
+
+    /// Merge a later run of the same Script into this one.
+    /// This run keeps its ids and takes the newer device snapshot.
+    pub fn merge(&mut self, later: ManagerMsg) {
+        let script_id = self.run.script_id;
+        let run_id = std::mem::take(&mut self.run.run_id);
+        self.run = later.run;
+        self.run.script_id = script_id;
+        self.run.run_id = run_id;
+        merge_causes(&mut self.causes, later.causes);
+        self.run.triggers = self.causes.iter().map(Into::into).collect();
+        if let Some(reply) = later.reply {
+            let _ = reply.send(self.run.run_id.clone());
+        }
+    }
+
+    /// Tell the trigger waiting for the run id, the run is queued
+    pub fn queued(&mut self) {
+        if let Some(reply) = self.reply.take() {
+            let _ = reply.send(self.run.run_id.clone());
+        }
+    }
 }
 
 pub struct Scheduler<T: RunScriptLookup + Send> {
     lookup_impl: T,
-    script_idgen: ScriptIDGenerator,
+    script_idgen: Arc<ScriptIDGenerator>,
+    run_idgen: RunIDGenerator,
 }
 
 impl<T: RunScriptLookup + Send + 'static> Scheduler<T> {
-    pub fn new(lookup: T) -> Self {
+    /// `script_idgen` is shared with the session manager, which re-dispatches lost runs
+    pub fn new(lookup: T, script_idgen: Arc<ScriptIDGenerator>) -> Self {
         Scheduler {
             lookup_impl: lookup,
-            script_idgen: ScriptIDGenerator::default(),
+            script_idgen,
+            run_idgen: RunIDGenerator::default(),
         }
     }
-    pub fn lookup(&mut self, index: ResourceIndex<Script>) -> Result<ManagerMsg> {
+    pub fn lookup(&mut self, trigger: ScriptTrigger) -> Result<ManagerMsg> {
+        let ScriptTrigger {
+            script: index,
+            cause,
+            reply,
+        } = trigger;
         trace!(script =? index, "lookup new script");
         let script = self.lookup_impl.lookup_script(&index)?;
         let readable = self.lookup_impl.lookup_readable(&script)?;
         let writable = self.lookup_impl.lookup_writable(&script)?;
+        let node_selectors = self.lookup_impl.lookup_node_selectors(&script)?;
         let name = script.meta().name.clone().unwrap();
         let namespace = script.meta().namespace.clone().unwrap();
         let env = script.spec.env;
+        let priority = script.spec.priority;
         let run = RunScript {
             script_id: self.script_idgen.gen().into(),
             manifest: Some(ProtoManifest {
@@ -110,90 +313,202 @@ impl<T: RunScriptLookup + Send + 'static> Scheduler<T> {
             writable,
             env,
             default_qos: script.spec.execute_policy.qos as i32,
+            triggers: vec![(&cause).into()],
+            run_id: self.run_idgen.gen().into(),
         };
         trace!(run =? run, "lookup result");
         Ok(ManagerMsg {
             run,
             name,
             namespace,
+            priority,
+            causes: vec![cause],
+            node_selectors,
+            attempts: 0,
+            reply,
         })
     }
 }
 
-/// Map of Device index to Script Set
-pub type SelectorMap = DashMap<ResourceIndex<Device>, DashSet<ResourceIndex<Script>>>;
 pub type Store<K> = DashMap<ResourceIndex<K>, K>;
 
-#[derive(Debug, Clone, Default)]
+/// Whether a resource is in the scope of a watch on `namespace`
+fn in_scope<K>(namespace: Option<&str>, idx: &ResourceIndex<K>) -> bool {
+    namespace.map_or(true, |ns| ns == idx.namespace)
+}
+
+#[derive(Debug, Default)]
 pub struct Reflector {
-    pub selector_map: SelectorMap,
+    /// Lock order: `selector_index` before `script_store`
+    pub selector_index: RwLock<SelectorIndex>,
     pub device_store: Store<Device>,
     pub script_store: Store<Script>,
+    /// Devices loaded from a snapshot and not yet confirmed by the watch
+    pub stale_devices: DashSet<ResourceIndex<Device>>,
+    /// Scripts loaded from a snapshot and not yet confirmed by the watch
+    pub stale_scripts: DashSet<ResourceIndex<Script>>,
+    /// Twins whose reported value was learned over MQTT, by property, with the twin the watch
+    /// saw, `None` if it had none. Kept until the watch reports a new value.
+    pub mqtt_reported: DashMap<ResourceIndex<Device>, HashMap<String, Option<Twin>>>,
 }
 
 impl Reflector {
-    pub fn add_device(&self, dev: &Device) {
-        let idx = dev.into();
-        self.device_store.insert(idx, dev.clone());
+    /// Store the device and return the twin properties whose reported value changed.
+    /// Return `None` if the device was not known before.
+    /// Values learned over MQTT stay until the watch reports a new value, so a re-list of
+    /// an older state is not a change.
+    pub fn add_device(&self, dev: &Device) -> Option<BTreeSet<String>> {
+        let idx: ResourceIndex<Device> = dev.into();
+        self.stale_devices.remove(&idx);
+        let mut live = dev.clone();
+        if let Some((_, overrides)) = self.mqtt_reported.remove(&idx) {
+            if let Some(old) = self.device_store.get(&idx) {
+                let kept = keep_reported(&mut live, &old, overrides);
+                if !kept.is_empty() {
+                    self.mqtt_reported.insert(idx.clone(), kept);
+                }
+            }
+        }
+        self.device_store
+            .insert(idx, live.clone())
+            .map(|old| reported_changes(&old, &live))
     }
     pub fn remove_device(&self, dev: &Device) {
         let idx = dev.into();
+        self.stale_devices.remove(&idx);
+        self.mqtt_reported.remove(&idx);
         if self.device_store.remove(&idx).is_none() {
             tracing::warn!(device =? dev, "Reflector want to remove nonexsit Device")
         }
     }
-    pub fn restart_device(&self, dev: &[Device]) {
+    /// Replace all devices in `namespace` (all namespaces if `None`) with a re-list and return
+    /// the known devices whose reported twin changed.
+    /// Devices seen for the first time are not reported, so a resync never triggers on them.
+    pub fn restart_device(&self, namespace: Option<&str>, dev: &[Device]) -> Vec<DeviceTrigger> {
+        let fresh: HashSet<ResourceIndex<Device>> = dev.iter().map(Into::into).collect();
+        self.device_store.retain(|k, _| {
+            if !in_scope(namespace, k) || fresh.contains(k) {
+                true
+            } else {
+                self.stale_devices.remove(k);
+                self.mqtt_reported.remove(k);
+                false
+            }
+        });
+        let mut result = Vec::new();
         for d in dev {
-            self.add_device(d);
+            match self.add_device(d) {
+                Some(changed) if !changed.is_empty() => result.push(DeviceTrigger {
+                    device: d.into(),
+                    changed,
+                }),
+                _ => {}
+            }
         }
+        result
     }
     pub fn add_script(&self, script: &Script) {
         let idx: ResourceIndex<Script> = script.into();
+        let selected = SelectedDevices::from(script);
+        debug!(script =? idx, devices =? selected);
+        let mut index = self.index_mut();
+        self.stale_scripts.remove(&idx);
         self.script_store.insert(idx.clone(), script.clone());
-        let devices = self.get_selected_by_name(script);
-        debug!(script =? idx, devices =? devices);
-        for dev in devices {
-            let set = self.selector_map.get(&dev);
-            match set {
-                None => {
-                    let new_set = DashSet::new();
-                    new_set.insert(script.into());
-                    self.selector_map.insert(dev, new_set);
-                }
-                Some(set) => {
-                    set.insert(script.into());
-                }
-            }
-        }
+        index.insert(idx, selected);
     }
     pub fn remove_script(&self, script: &Script) {
         let idx = script.into();
-        for set in self.selector_map.iter() {
-            set.remove(&idx);
-        }
+        let mut index = self.index_mut();
+        index.remove(&idx);
+        self.stale_scripts.remove(&idx);
         if self.script_store.remove(&idx).is_none() {
             tracing::warn!(script =? script, "Reflector want to remove nonexsit Script")
         }
     }
-    pub fn restart_script(&self, scripts: &[Script]) {
+    /// Replace all scripts in `namespace` (all namespaces if `None`) with a re-list
+    pub fn restart_script(&self, namespace: Option<&str>, scripts: &[Script]) {
+        let fresh: HashSet<ResourceIndex<Script>> = scripts.iter().map(Into::into).collect();
+        let mut index = self.index_mut();
+        self.script_store.retain(|k, _| {
+            if !in_scope(namespace, k) || fresh.contains(k) {
+                true
+            } else {
+                index.remove(k);
+                self.stale_scripts.remove(k);
+                false
+            }
+        });
         for s in scripts {
-            self.add_script(s);
+            let idx: ResourceIndex<Script> = s.into();
+            self.stale_scripts.remove(&idx);
+            self.script_store.insert(idx.clone(), s.clone());
+            index.insert(idx, s.into());
         }
     }
-    fn get_selected_by_name(&self, script: &Script) -> Vec<ResourceIndex<Device>> {
-        let mut result = Vec::new();
-        let mut idx = ResourceIndex {
-            name: String::new(),
-            namespace: script.meta().namespace.clone().unwrap(),
-            api: PhantomData,
+    /// Devices with this name in any of the watched namespaces
+    pub fn devices_named(&self, name: &str) -> Vec<ResourceIndex<Device>> {
+        self.device_store
+            .iter()
+            .filter(|d| d.key().name == name)
+            .map(|d| d.key().clone())
+            .collect()
+    }
+    /// Update reported twin values of a known device, e.g. from MQTT twin update results.
+    /// Returns the properties whose reported value changed.
+    pub fn report_device(
+        &self,
+        idx: &ResourceIndex<Device>,
+        reported: HashMap<String, String>,
+    ) -> BTreeSet<String> {
+        let mut changed = BTreeSet::new();
+        let mut dev = match self.device_store.get_mut(idx) {
+            Some(dev) => dev,
+            None => {
+                trace!(device =? idx, "Reported values of unknown device");
+                return changed;
+            }
         };
-        if let Some(map) = &script.spec.read_selector.match_names {
-            for name in map.values() {
-                idx.name = name.clone();
-                result.push(idx.clone())
+        let status = dev
+            .status
+            .get_or_insert_with(|| DeviceStatus { twins: Vec::new() });
+        let mut overrides = self.mqtt_reported.entry(idx.clone()).or_default();
+        for (property, value) in reported {
+            match status
+                .twins
+                .iter_mut()
+                .find(|t| t.property_name == property)
+            {
+                Some(twin) if twin.reported.as_ref().map(|r| &r.value) == Some(&value) => continue,
+                Some(twin) => {
+                    overrides
+                        .entry(property.clone())
+                        .or_insert_with(|| Some(twin.clone()));
+                    twin.reported = Some(TwinProperty::new(value));
+                }
+                None => {
+                    overrides.entry(property.clone()).or_insert(None);
+                    status.twins.push(Twin {
+                        property_name: property.clone(),
+                        desired: TwinProperty::new(String::new()),
+                        reported: Some(TwinProperty::new(value)),
+                    })
+                }
             }
+            changed.insert(property);
         }
-        result
+        changed
+    }
+
+    /// Read the Script and Device index
+    pub fn index(&self) -> RwLockReadGuard<'_, SelectorIndex> {
+        self.selector_index
+            .read()
+            .expect("Reflector selector index poisoned")
+    }
+    fn index_mut(&self) -> RwLockWriteGuard<'_, SelectorIndex> {
+        self.selector_index
+            .write()
+            .expect("Reflector selector index poisoned")
     }
 }
 
@@ -216,14 +531,7 @@ impl RunScriptLookup for Arc<Reflector> {
         &mut self,
         device: &ResourceIndex<Device>,
     ) -> Result<Vec<ResourceIndex<Script>>> {
-        let set = self
-            .selector_map
-            .get(device)
-            .ok_or_else(|| eyre!("Device: {:?} not found in store", device))?
-            .iter()
-            .map(|m| m.clone())
-            .collect();
-        Ok(set)
+        Ok(self.index().readers(device))
     }
 
     fn lookup_readable(&mut self, script: &Script) -> Result<HashMap<String, ReadDevice>> {
@@ -240,20 +548,12 @@ impl RunScriptLookup for Arc<Reflector> {
                 } else {
                     continue;
                 };
-                let mut status = HashMap::new();
-                if let Some(s) = &dev.status {
-                    trace!(s=?s);
-                    for twin in &s.twins {
-                        if let Some(val) = &twin.reported {
-                            status.insert(twin.property_name.to_owned(), val.value.to_owned());
-                        }
-                    }
-                }
+                trace!(s =? dev.status);
                 result.insert(
                     k.to_owned(),
                     ReadDevice {
                         name: v.to_owned(),
-                        status,
+                        status: dev.reported(),
                     },
                 );
             }
@@ -269,22 +569,172 @@ impl RunScriptLookup for Arc<Reflector> {
         }
         Ok(result)
     }
+
+    fn lookup_node_selectors(&mut self, script: &Script) -> Result<Vec<NodeSelector>> {
+        let selected = SelectedDevices::from(script);
+        let mut result: Vec<NodeSelector> = Vec::new();
+        for idx in selected.read.union(&selected.write) {
+            if let Some(dev) = self.device_store.get(idx) {
+                if !result.contains(&dev.spec.node_selector) {
+                    result.push(dev.spec.node_selector.clone());
+                }
+            }
+        }
+        Ok(result)
+    }
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn trigger(
     store: Arc<Reflector>,
-    device: Receiver<ResourceIndex<Device>>,
-    script: Sender<ResourceIndex<Script>>,
+    device: Receiver<DeviceTrigger>,
+    script: Sender<ScriptTrigger>,
 ) -> Result<()> {
     loop {
-        let idx = device.recv_async().await?;
-        info!(device =? idx, "map trigger got new device");
-        if let Some(scripts) = store.selector_map.get(&idx) {
-            for s in scripts.iter() {
-                info!(script =? *s, "map trigger new script");
-                script.send_async(s.clone()).await?;
-            }
+        let DeviceTrigger {
+            device: idx,
+            changed,
+        } = device.recv_async().await?;
+        info!(device =? idx, changed =? changed, "map trigger got new device");
+        let scripts = store.index().readers(&idx);
+        for s in scripts {
+            info!(script =? s, "map trigger new script");
+            let cause = TriggerCause::Device {
+                name: idx.name.clone(),
+                changed: changed.clone(),
+            };
+            script
+                .send_async(ScriptTrigger {
+                    script: s,
+                    cause,
+                    reply: None,
+                })
+                .await?;
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) fn device(name: &str, reported: &[(&str, &str)]) -> Device {
+        let twins: Vec<_> = reported
+            .iter()
+            .map(|(k, v)| {
+                serde_json::json!({
+                    "propertyName": k,
+                    "desired": { "value": "" },
+                    "reported": { "value": v },
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "devices.kubeedge.io/v1alpha2",
+            "kind": "Device",
+            "metadata": { "name": name, "namespace": "default" },
+            "spec": {
+                "deviceModelRef": { "name": "model" },
+                "nodeSelector": { "nodeSelectorTerms": [] },
+            },
+            "status": { "twins": twins },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reported_changes() {
+        let old = device("dht11", &[("temperature", "20"), ("humidity", "40")]);
+        let new = device("dht11", &[("temperature", "21"), ("humidity", "40")]);
+        assert_eq!(
+            reported_changes(&old, &new),
+            BTreeSet::from(["temperature".to_owned()])
+        );
+        let new = device("dht11", &[("humidity", "40"), ("power", "on")]);
+        assert_eq!(
+            reported_changes(&old, &new),
+            BTreeSet::from(["temperature".to_owned(), "power".to_owned()])
+        );
+        assert!(reported_changes(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_merge_causes() {
+        let dev = |name: &str, changed: &[&str]| TriggerCause::Device {
+            name: name.to_owned(),
+            changed: changed.iter().map(|c| c.to_string()).collect(),
+        };
+        let mut causes = vec![dev("dht11", &["temperature"])];
+        merge_causes(
+            &mut causes,
+            [
+                dev("dht11", &["humidity"]),
+                TriggerCause::Webhook,
+                dev("switch", &["power"]),
+                TriggerCause::Webhook,
+            ],
+        );
+        assert_eq!(
+            causes,
+            vec![
+                dev("dht11", &["temperature", "humidity"]),
+                TriggerCause::Webhook,
+                dev("switch", &["power"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reflector_device_trigger() {
+        let reflector = Reflector::default();
+        assert_eq!(
+            reflector.add_device(&device("dht11", &[("temperature", "20")])),
+            None
+        );
+        // desired only change
+        let mut desired = device("dht11", &[("temperature", "20")]);
+        desired.status.as_mut().unwrap().twins[0].desired.value = "30".to_owned();
+        assert_eq!(reflector.add_device(&desired), Some(BTreeSet::new()));
+        // values reported over MQTT, only changed ones count
+        let idx = ResourceIndex::from(&desired);
+        let reported = |values: &[(&str, &str)]| {
+            let values = values.iter().map(|(k, v)| (k.to_string(), v.to_string()));
+            reflector.report_device(&idx, values.collect())
+        };
+        assert!(reported(&[("temperature", "20")]).is_empty());
+        assert_eq!(
+            reported(&[("temperature", "25"), ("humidity", "40")]),
+            BTreeSet::from(["humidity".to_owned(), "temperature".to_owned()])
+        );
+        assert!(reported(&[("temperature", "25")]).is_empty());
+        // the watch event of the reported values doesn't trigger again
+        let mut watched = device("dht11", &[("temperature", "25"), ("humidity", "40")]);
+        watched.status.as_mut().unwrap().twins[0].desired.value = "30".to_owned();
+        assert_eq!(reflector.add_device(&watched), Some(BTreeSet::new()));
+        reflector.add_device(&desired);
+        // resync ignores unchanged and unknown devices
+        let triggers = reflector.restart_device(
+            None,
+            &[
+                device("dht11", &[("temperature", "21")]),
+                device("switch", &[("power", "on")]),
+            ],
+        );
+        assert_eq!(
+            triggers,
+            vec![DeviceTrigger {
+                device: ResourceIndex {
+                    namespace: "default".to_owned(),
+                    name: "dht11".to_owned(),
+                    api: PhantomData,
+                },
+                changed: BTreeSet::from(["temperature".to_owned()]),
+            }]
+        );
+        // a re-list of another namespace keeps our devices, a re-list of ours drops vanished ones
+        assert!(reflector.restart_device(Some("other"), &[]).is_empty());
+        assert_eq!(reflector.device_store.len(), 2);
+        reflector.restart_device(Some("default"), &[device("switch", &[("power", "on")])]);
+        assert_eq!(reflector.device_store.len(), 1);
+    }
+}
@@ -1,15 +1,30 @@
 //! Publib tasks for rule engine controller
 
-use axum::extract::Extension;
+use axum::{
+    body::StreamBody,
+    extract::{Extension, Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use color_eyre::Result;
 use flume::Sender;
-use std::{net::SocketAddr, sync::Arc};
-use tracing::info;
+use proto::script_status::ScriptStatusCode;
+use serde::Deserialize;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::{broadcast::error::RecvError, watch};
+use tracing::{error, info};
 
 use crate::{
-    api::Script,
-    scheduler::{Reflector, ResourceIndex},
-    session::SessionManager,
+    auth::TlsConfig,
+    cancel::{CancelTarget, Cancels},
+    controller::Runs,
+    drain::{Drains, ExecutorSummary},
+    history::{RunHistory, RunRecord, RunResult},
+    logs::{LogEvent, ScriptLogs},
+    queue::{QueuedRun, RunQueue},
+    scheduler::{Reflector, ResourceIndex, ScriptTrigger},
+    session::{ExecutorState, SessionManager},
     trigger,
 };
 
@@ -18,17 +33,221 @@ async fn debug(Extension(state): Extension<Arc<Reflector>>) -> String {
     let mut result = String::new();
     result.push_str(&format!("Device: {:?}\n", state.device_store));
     result.push_str(&format!("Script: {:?}\n", state.script_store));
-    result.push_str(&format!("Map: {:?}\n", state.selector_map));
+    result.push_str(&format!("Map: {:?}\n", state.index()));
+    result.push_str(&format!("Stale Device: {:?}\n", state.stale_devices));
+    result.push_str(&format!("Stale Script: {:?}\n", state.stale_scripts));
     result
 }
 
+/// Liveness and readiness of this replica, standbys are healthy too
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Whether this replica is the leader
+async fn leader(Extension(leader): Extension<watch::Receiver<bool>>) -> StatusCode {
+    if *leader.borrow() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Pending runs in arrival order
+async fn queued_runs(Extension(queue): Extension<Arc<RunQueue>>) -> Json<Vec<QueuedRun>> {
+    Json(queue.list())
+}
+
+/// Cancel a pending run, it is reported as cancelled
+#[tracing::instrument(skip(cancels))]
+async fn cancel_queued_run(
+    Path(script_id): Path<u32>,
+    Extension(cancels): Extension<Cancels>,
+) -> StatusCode {
+    cancel(&cancels, CancelTarget::Queued(script_id)).await
+}
+
+/// Recorded runs of a Script, newest first
+async fn script_runs(
+    Path((namespace, name)): Path<(String, String)>,
+    Extension(history): Extension<Arc<RunHistory>>,
+) -> Json<Vec<RunRecord>> {
+    Json(history.list(&namespace, &name))
+}
+
+/// Value returned by main() in a run, null if it returned nothing.
+/// 202 while the run is queued or running, 422 if it didn't finish with `Ok`.
+async fn run_output(
+    Path(run_id): Path<String>,
+    Extension(history): Extension<Arc<RunHistory>>,
+    Extension(queue): Extension<Arc<RunQueue>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    match history.get(&run_id) {
+        Some(r) => match r.result {
+            RunResult::Done(ScriptStatusCode::Ok) => Ok(Json(r.output.unwrap_or_default())),
+            RunResult::Running => Err(StatusCode::ACCEPTED),
+            RunResult::Done(_) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+        },
+        None if queue.list().iter().any(|q| q.run_id == run_id) => Err(StatusCode::ACCEPTED),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Cancel the queued and running runs of a Script
+#[tracing::instrument(skip(cancels))]
+async fn cancel_script_runs(
+    Path((namespace, name)): Path<(String, String)>,
+    Extension(cancels): Extension<Cancels>,
+) -> StatusCode {
+    let target = CancelTarget::Script(ResourceIndex {
+        namespace,
+        name,
+        api: Default::default(),
+    });
+    cancel(&cancels, target).await
+}
+
+async fn run(
+    Path(run_id): Path<String>,
+    Extension(history): Extension<Arc<RunHistory>>,
+) -> Result<Json<RunRecord>, StatusCode> {
+    history.get(&run_id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Cancel a queued or running run
+#[tracing::instrument(skip(cancels))]
+async fn cancel_run(
+    Path(run_id): Path<String>,
+    Extension(cancels): Extension<Cancels>,
+) -> StatusCode {
+    cancel(&cancels, CancelTarget::Run(run_id)).await
+}
+
+/// Running runs are stopped by their executor later, so a cancellation is only accepted
+async fn cancel(cancels: &Cancels, target: CancelTarget) -> StatusCode {
+    match cancels
+        .cancel(target, "Cancelled by the api".to_owned())
+        .await
+    {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(e) => {
+            error!(error =? e, "Failed to cancel runs");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// Connected executors
+async fn executors(
+    Extension(drains): Extension<Drains>,
+) -> Result<Json<Vec<ExecutorSummary>>, StatusCode> {
+    drains.list().await.map(Json).map_err(|e| {
+        error!(error =? e, "Failed to list executors");
+        StatusCode::SERVICE_UNAVAILABLE
+    })
+}
+
+/// Stop dispatching runs to an executor, its running runs finish
+#[tracing::instrument(skip(drains))]
+async fn drain_executor(Path(id): Path<u32>, Extension(drains): Extension<Drains>) -> StatusCode {
+    match drains.pause(id, true).await {
+        Ok(Some(_)) => StatusCode::ACCEPTED,
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(error =? e, "Failed to drain executor");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// Dispatch runs to a paused executor again, an executor draining itself can't be resumed
+#[tracing::instrument(skip(drains))]
+async fn resume_executor(Path(id): Path<u32>, Extension(drains): Extension<Drains>) -> StatusCode {
+    match drains.pause(id, false).await {
+        Ok(Some(ExecutorState::Draining | ExecutorState::Disconnect)) => StatusCode::CONFLICT,
+        Ok(Some(_)) => StatusCode::OK,
+        Ok(None) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!(error =? e, "Failed to resume executor");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LogQuery {
+    /// keep streaming until the run finishes
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Console output of a run as text lines
+async fn run_logs(
+    Path(run_id): Path<String>,
+    Query(query): Query<LogQuery>,
+    Extension(logs): Extension<Arc<ScriptLogs>>,
+    Extension(history): Extension<Arc<RunHistory>>,
+) -> Response {
+    let running = |history: &RunHistory, logs: &ScriptLogs, run_id: &str| {
+        logs.is_open(run_id)
+            || history
+                .get(run_id)
+                .map_or(false, |r| r.result == RunResult::Running)
+    };
+    // subscribe before reading the kept lines, so no line is missed in between
+    let mut events = logs.subscribe();
+    let lines = logs.run(&run_id);
+    if lines.is_empty() && history.get(&run_id).is_none() && !logs.is_open(&run_id) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !query.follow || !running(&history, &logs, &run_id) {
+        let body: String = lines.iter().map(|l| format!("{}\n", l)).collect();
+        return body.into_response();
+    }
+    let stream = async_stream::stream! {
+        let mut last = 0;
+        for line in lines {
+            last = line.seq;
+            yield Ok::<_, Infallible>(format!("{}\n", line));
+        }
+        let mut check = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(LogEvent::Line(line)) if line.run_id == run_id && line.seq > last => {
+                        yield Ok(format!("{}\n", line));
+                    }
+                    Ok(LogEvent::Closed(closed)) if closed == run_id => break,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {
+                        yield Ok("... lines skipped\n".to_owned());
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = check.tick() => {
+                    if !running(&history, &logs, &run_id) {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+    StreamBody::new(stream).into_response()
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn web_server(
-    scheduler: Sender<ResourceIndex<Script>>,
+    scheduler: Sender<ScriptTrigger>,
     store: Arc<Reflector>,
+    runs: Runs,
+    leader_rx: watch::Receiver<bool>,
     addr: SocketAddr,
 ) -> Result<()> {
-    use axum::{routing::get, Router};
+    use axum::{
+        routing::{delete, get, post},
+        Router,
+    };
 
     let endpoint = Arc::new(scheduler);
 
@@ -36,7 +255,27 @@ pub async fn web_server(
         .route("/api/v1alpha/webhook", get(trigger::webhook::webhook))
         .layer(Extension(endpoint))
         .route("/api/v1alpha/debug", get(debug))
-        .layer(Extension(store));
+        .layer(Extension(store))
+        .route("/healthz", get(healthz))
+        .route("/api/v1alpha/leader", get(leader))
+        .layer(Extension(leader_rx))
+        .route("/api/v1alpha/queue", get(queued_runs))
+        .route("/api/v1alpha/queue/:id", delete(cancel_queued_run))
+        .route("/api/v1alpha/runs/:id/output", get(run_output))
+        .layer(Extension(runs.queue))
+        .route(
+            "/api/v1alpha/scripts/:namespace/:name/runs",
+            get(script_runs).delete(cancel_script_runs),
+        )
+        .route("/api/v1alpha/runs/:id", get(run).delete(cancel_run))
+        .route("/api/v1alpha/runs/:id/logs", get(run_logs))
+        .layer(Extension(runs.cancels))
+        .route("/api/v1alpha/executors", get(executors))
+        .route("/api/v1alpha/executors/:id/drain", post(drain_executor))
+        .route("/api/v1alpha/executors/:id/resume", post(resume_executor))
+        .layer(Extension(runs.drains))
+        .layer(Extension(runs.logs))
+        .layer(Extension(runs.history));
 
     info!("Rule engine webserver listening on {}", addr);
     axum::Server::bind(&addr)
@@ -46,12 +285,23 @@ pub async fn web_server(
 }
 
 #[tracing::instrument(skip_all)]
-pub async fn grpc_server(addr: SocketAddr, mgr: SessionManager) -> Result<()> {
+pub async fn grpc_server(
+    addr: SocketAddr,
+    mgr: SessionManager,
+    tls: Option<TlsConfig>,
+) -> Result<()> {
     use proto::controller_service_server::ControllerServiceServer;
     use tonic::transport::Server;
-    info!("Rule engine grpc server listening on {}", addr);
+    info!(
+        tls = tls.is_some(),
+        "Rule engine grpc server listening on {}", addr
+    );
 
-    Server::builder()
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls.server_config().await?)?;
+    }
+    builder
         .add_service(ControllerServiceServer::new(mgr))
         .serve(addr)
         .await?;
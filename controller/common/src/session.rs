@@ -1,99 +1,1059 @@
 //! This module implement ControllerService
 //!
 
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::{Duration, Instant};
 use std::{net::SocketAddr, sync::Arc};
 
-use crate::api::device::{DeviceStatus, Twin, TwinProperty};
 use crate::api::{self, Device, Script};
-use crate::controller::{wait_for_stop, ControllerState};
-use crate::id::{ExecutorID, ExecutorIDGenerator, ScriptID};
-use crate::scheduler::ManagerMsg;
+use crate::auth::{AuthConfig, Authenticator, Identity};
+use crate::cancel::{CancelRequest, Cancels};
+use crate::commit::{self, CommitConfig, Commits};
+use crate::controller::{wait_for_stop, ControllerState, Runs};
+use crate::drain::{DrainRequest, Drains, ExecutorSummary};
+use crate::history::{DeviceWrite, RunHistory};
+use crate::id::{ExecutorID, ExecutorIDGenerator, ScriptID, ScriptIDGenerator};
+use crate::leader::wait_for_leader;
+use crate::locality::{LocalityConfig, LocalityFallback, NodeInfo};
+use crate::logs::{LogLine, ScriptLogs};
+use crate::queue::{Dropped, RunQueue};
+use crate::quota::Quotas;
+use crate::scheduler::{ManagerMsg, Reflector, ResourceIndex, RunScriptLookup};
 use async_stream::stream;
 use color_eyre::Result;
-use dashmap::DashMap;
-use flume::Receiver;
+use dashmap::{DashMap, DashSet};
+use flume::{Receiver, Sender};
 use futures::stream::BoxStream;
 use futures::StreamExt;
+use k8s_openapi::chrono::Utc;
 use kube::api::{Patch, PatchParams};
 use kube::{Api, Client};
+use proto::script_status::ScriptStatusCode;
 use proto::server_message::disconnect::DisconnectReason;
+use proto::server_message::run_script::manifest::ScriptType;
 use proto::server_message::Msg;
-use proto::{client_message::ClientCode, controller_service_server::ControllerService};
-use proto::{ClientMessage, QosPolicy, ServerMessage};
+use proto::update_device_result::Outcome as Delivery;
+use proto::{
+    capability, ClientMessage, GetDeviceStatus, LogBatch, QosPolicy, ReadDevices, ServerMessage,
+    MAX_OUTPUT,
+};
+use proto::{
+    client_message::{ClientCode, ClientInfo, Load},
+    controller_service_server::ControllerService,
+};
+use proto::{UpdateDeviceResult, UpdateDevices, UpdateDevicesResult};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
 use tokio::sync::watch;
 use tonic::{async_trait, metadata::MetadataMap, Request, Response, Status, Streaming};
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 const RE_VERSION: &str = "re-version";
+/// protocol versions of executors the controller can serve
+const SUPPORTED_PROTOCOL: &str = ">=1.0.0, <2.0.0";
+/// executors released before protocol versioning send their package version,
+/// they speak protocol 1.0 without capabilities
+const LEGACY_VERSION: &str = "0.1.0";
+/// optional features announced to executors
+const CAPABILITIES: &[&str] = &[
+    capability::LOGS,
+    capability::DEVICE_STATUS,
+    capability::DEVICE_QOS,
+    capability::DEVICE_BATCH,
+    capability::DRAIN,
+];
+/// how often the reflector is checked for confirmed device writes
+const CONFIRM_POLL: Duration = Duration::from_millis(200);
+/// writes of a device tried again after it changed meanwhile
+const CONFLICT_RETRIES: usize = 3;
 const MANAGER: &str = "ruleengine";
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LeaseConfig {
+    /// seconds a run may go without a heartbeat of its executor before it is considered
+    /// lost, or take at all on executors without heartbeats. 0 for no deadline
+    pub duration_secs: u64,
+    /// times a run of an at-least-once Script is sent before it is given up
+    pub max_attempts: u32,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        LeaseConfig {
+            duration_secs: 600,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl LeaseConfig {
+    /// Deadline of a lease taken or renewed at `now`
+    fn deadline(&self, now: Instant) -> Option<Instant> {
+        (self.duration_secs > 0).then(|| now + Duration::from_secs(self.duration_secs))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// seconds between two heartbeats, 0 to disable
+    pub interval_secs: u64,
+    /// heartbeats an executor may miss before it is evicted
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval_secs: 10,
+            max_missed: 3,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    /// Whether an executor last heard of at `last` missed too many heartbeats
+    fn expired(&self, last: Instant, now: Instant) -> bool {
+        self.interval_secs > 0
+            && now.saturating_duration_since(last) > self.interval() * self.max_missed.max(1)
+    }
+}
+
+/// How runs are handed out to executors
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    pub locality: LocalityConfig,
+    pub lease: LeaseConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub auth: AuthConfig,
+    pub commit: CommitConfig,
+}
+
 pub struct SessionManager {
     scripts: Arc<DashMap<ScriptID, ScriptStatus>>,
     executors: Arc<DashMap<ExecutorID, ExecutorInfo>>,
+    /// executors paused through the web api by identity and node, they stay paused on reconnect
+    paused: Arc<DashSet<(Identity, String)>>,
     executor_idgen: Arc<ExecutorIDGenerator>,
     client: Client,
     pp: PatchParams,
+    store: Arc<Reflector>,
     scheduler: Receiver<ManagerMsg>,
+    queue: Arc<RunQueue>,
+    quotas: Arc<Quotas>,
+    history: Arc<RunHistory>,
+    logs: Arc<ScriptLogs>,
+    script_ids: Arc<ScriptIDGenerator>,
+    cancels: Cancels,
+    drains: Drains,
+    locality: LocalityConfig,
+    lease: LeaseConfig,
+    heartbeat: HeartbeatConfig,
+    auth: Arc<Authenticator>,
+    commits: Commits,
     state: watch::Receiver<ControllerState>,
+    leader: watch::Receiver<bool>,
 }
 
+/// A run leased to an executor
 #[derive(Debug)]
 struct ScriptStatus {
     name: String,
     namespace: String,
+    run_id: String,
     executor: ExecutorID,
+    /// who the executor authenticated as
+    identity: Identity,
+    /// the run is lost if the executor doesn't report before
+    deadline: Option<Instant>,
+    /// the run to send again if it is lost, only kept for at-least-once Scripts
+    retry: Option<ManagerMsg>,
 }
 
 #[derive(Debug)]
 struct ExecutorInfo {
     addr: SocketAddr,
+    /// script types the executor can run
+    script_types: Vec<ScriptType>,
+    /// protocol version of the executor
+    protocol: Version,
+    /// optional features of the executor
+    capabilities: Vec<String>,
+    /// job slots of the executor, 0 if unbounded
+    max_job: u32,
+    /// runs that can be dispatched before the executor returns credits
+    credits: u32,
+    /// node the executor runs on
+    node: NodeInfo,
+    /// who the executor authenticated as
+    identity: Identity,
+    state: ExecutorState,
+    /// when the last heartbeat was answered
+    last_heartbeat: Instant,
+    /// load reported with the last heartbeat
+    load: Option<Load>,
+    /// messages to the executor from outside its session, e.g. cancellations
+    outbox: Sender<ServerMessage>,
+}
+
+impl ExecutorInfo {
+    /// Whether the executor announced an optional feature
+    fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Protocol spoken by executors which send `LEGACY_VERSION`
+fn legacy_protocol() -> Version {
+    Version::new(1, 0, 0)
+}
+
+/// Idempotency key an only-once commit is deduplicated by, none if the executor sends none
+fn only_once_key(request: &proto::UpdateDevice, device_qos: bool) -> Option<&str> {
+    (device_qos && !request.idempotency_key.is_empty()).then(|| request.idempotency_key.as_str())
+}
+
+/// Add returned credits, an executor never holds more than its job slots
+fn grant(credits: u32, max_job: u32, returned: u32) -> u32 {
+    let credits = credits.saturating_add(returned);
+    if max_job == 0 {
+        credits
+    } else {
+        credits.min(max_job)
+    }
+}
+
+/// Whether any executor runs are dispatched to can run the script type
+fn capable(executors: &DashMap<ExecutorID, ExecutorInfo>, ty: ScriptType) -> bool {
+    executors.iter().any(|e| {
+        matches!(e.state, ExecutorState::Init | ExecutorState::Ready)
+            && e.script_types.contains(&ty)
+    })
+}
+
+/// Reject queued runs nobody is left to take
+async fn reject_incapable(
+    executors: &DashMap<ExecutorID, ExecutorInfo>,
+    queue: &RunQueue,
+    client: &Client,
+    pp: &PatchParams,
+    history: &RunHistory,
+) {
+    for ty in queue.script_types() {
+        if !capable(executors, ty) {
+            for msg in queue.drain(ty) {
+                no_executor(client, pp, history, msg).await;
+            }
+        }
+    }
+}
+
+/// Executors runs are dispatched to, collected before the queue is locked
+#[derive(Debug, Default)]
+struct Candidates {
+    /// node of the executor taking runs
+    own: Option<NodeInfo>,
+    /// executors in `Init` or `Ready`
+    active: Vec<Candidate>,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    node: NodeInfo,
+    script_types: Vec<ScriptType>,
+    credits: u32,
+}
+
+impl Candidates {
+    fn collect(executors: &DashMap<ExecutorID, ExecutorInfo>, own: Option<ExecutorID>) -> Self {
+        Candidates {
+            own: own.and_then(|id| Some(executors.get(&id)?.node.clone())),
+            active: executors
+                .iter()
+                .filter(|e| matches!(e.state, ExecutorState::Init | ExecutorState::Ready))
+                .map(|e| Candidate {
+                    node: e.node.clone(),
+                    script_types: e.script_types.clone(),
+                    credits: e.credits,
+                })
+                .collect(),
+        }
+    }
+
+    /// Active executors on the node of the run's devices
+    fn local<'a>(&'a self, msg: &'a ManagerMsg) -> impl Iterator<Item = &'a Candidate> {
+        let ty = msg.script_type();
+        self.active
+            .iter()
+            .filter(move |e| e.script_types.contains(&ty) && e.node.local_to(&msg.node_selectors))
+    }
+}
+
+/// Whether the executor of `candidates` should take a run that waited `age` in the queue.
+/// Executors on the node of the run's devices always can, others only as the fallback policy allows.
+fn eligible(
+    candidates: &Candidates,
+    locality: &LocalityConfig,
+    msg: &ManagerMsg,
+    age: Duration,
+) -> bool {
+    if msg.node_selectors.is_empty() {
+        return true;
+    }
+    let local = match &candidates.own {
+        Some(node) => node.local_to(&msg.node_selectors),
+        None => return false,
+    };
+    if local {
+        return true;
+    }
+    let mut local_executors = candidates.local(msg);
+    match locality.fallback {
+        LocalityFallback::Never => false,
+        LocalityFallback::Any => !local_executors.any(|e| e.credits > 0),
+        LocalityFallback::Wait => {
+            age >= Duration::from_secs(locality.wait_secs) || local_executors.next().is_none()
+        }
+    }
+}
+
+/// Runs executing now by namespace
+fn running_by_namespace(scripts: &DashMap<ScriptID, ScriptStatus>) -> HashMap<String, usize> {
+    let mut running = HashMap::new();
+    for run in scripts.iter() {
+        *running.entry(run.namespace.clone()).or_default() += 1;
+    }
+    running
+}
+
+/// Whether a run only local executors may take has waited long enough without one
+fn stranded(
+    candidates: &Candidates,
+    locality: &LocalityConfig,
+    msg: &ManagerMsg,
+    age: Duration,
+) -> bool {
+    locality.fallback == LocalityFallback::Never
+        && !msg.node_selectors.is_empty()
+        && age >= Duration::from_secs(locality.wait_secs)
+        && candidates.local(msg).next().is_none()
+}
+
+/// Extend the leases of the runs of an executor which answered a heartbeat
+fn renew(
+    scripts: &DashMap<ScriptID, ScriptStatus>,
+    executor: ExecutorID,
+    lease: &LeaseConfig,
+    now: Instant,
+) {
+    for mut run in scripts.iter_mut() {
+        if run.executor == executor {
+            run.deadline = lease.deadline(now);
+        }
+    }
+}
+
+/// Remove the leased runs matching `pred`
+fn take_runs(
+    scripts: &DashMap<ScriptID, ScriptStatus>,
+    pred: impl Fn(&ScriptStatus) -> bool,
+) -> Vec<(ScriptID, ScriptStatus)> {
+    let ids: Vec<ScriptID> = scripts
+        .iter()
+        .filter(|s| pred(s.value()))
+        .map(|s| *s.key())
+        .collect();
+    ids.into_iter()
+        .filter_map(|id| scripts.remove_if(&id, |_, s| pred(s)))
+        .collect()
+}
+
+/// Reports lost runs and sends runs of at-least-once Scripts again
+#[derive(Clone)]
+struct Reclaimer {
+    client: Client,
+    pp: PatchParams,
+    queue: Arc<RunQueue>,
+    history: Arc<RunHistory>,
+    script_ids: Arc<ScriptIDGenerator>,
+    lease: LeaseConfig,
+}
+
+impl Reclaimer {
+    async fn lost(&self, id: ScriptID, run: ScriptStatus, message: &str) {
+        warn!(id =? id, run_id = %run.run_id, name = %run.name, namespace = %run.namespace, executor =? run.executor, message, "Run lost");
+        let status = api::script::ScriptStatus {
+            last_run: Utc::now().timestamp_millis(),
+            elapsed_time: 0,
+            status: ScriptStatusCode::Unknown as i32,
+            message: message.to_owned(),
+            output: None,
+        };
+        self.history
+            .finished(&run.namespace, &run.name, id.into(), &status);
+        if let Err(e) =
+            patch_script_status(&self.client, &self.pp, &run.namespace, &run.name, &status).await
+        {
+            error!(error =? e, "Failed to update status of Script");
+        }
+        let mut msg = match run.retry {
+            Some(msg) if msg.attempts < self.lease.max_attempts => msg,
+            Some(msg) => {
+                warn!(run_id = %run.run_id, attempts = msg.attempts, "Give up lost run");
+                return;
+            }
+            None => return,
+        };
+        // a new attempt, a late report of the lost one must not complete it
+        self.history.retried(&run.namespace, &run.name, id.into());
+        msg.run.script_id = self.script_ids.gen().into();
+        info!(run_id = %run.run_id, attempts = msg.attempts, "Dispatch lost run again");
+        if let Some((dropped, reason)) = self.queue.push(msg).await {
+            dropped_run(&self.client, &self.pp, &self.history, dropped, reason).await;
+        }
+    }
+}
+
+/// Removes an executor when its stream ends, also when the stream is dropped because the
+/// connection broke. Runs still leased to the executor are lost.
+struct Session {
+    id: ExecutorID,
+    executors: Arc<DashMap<ExecutorID, ExecutorInfo>>,
+    scripts: Arc<DashMap<ScriptID, ScriptStatus>>,
+    reclaimer: Reclaimer,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        match self.executors.remove(&self.id) {
+            Some((id, info)) => {
+                trace!(id =? id, info =? info, "Executor disconnected")
+            }
+            None => {
+                error!(id =? self.id, "Unknown executor disconnected")
+            }
+        }
+        let id = self.id;
+        let orphans = take_runs(&self.scripts, |s| s.executor == id);
+        let executors = self.executors.clone();
+        let reclaimer = self.reclaimer.clone();
+        tokio::spawn(async move {
+            for (id, run) in orphans {
+                reclaimer
+                    .lost(id, run, "Executor disconnected during the run")
+                    .await;
+            }
+            let Reclaimer {
+                client,
+                pp,
+                queue,
+                history,
+                ..
+            } = reclaimer;
+            // runs waiting for this executor may go elsewhere now
+            queue.wake();
+            reject_incapable(&executors, &queue, &client, &pp, &history).await;
+        });
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutorState {
+    /// connected, waiting for the first heartbeat
     Init,
+    /// answering heartbeats, runs are dispatched to it
     Ready,
+    /// paused through the web api, running runs finish but no run is dispatched
     Pause,
+    /// the executor is exiting, running runs finish but no run is dispatched
+    Draining,
+    /// disconnecting or evicted
     Disconnect,
 }
 
+impl ExecutorState {
+    /// State after the web api pauses or resumes the executor, a draining executor stays so
+    fn pause(self, paused: bool) -> Self {
+        match (self, paused) {
+            (ExecutorState::Init | ExecutorState::Ready, true) => ExecutorState::Pause,
+            (ExecutorState::Pause, false) => ExecutorState::Ready,
+            (state, _) => state,
+        }
+    }
+}
+
 impl SessionManager {
     pub fn new(
         client: Client,
+        store: Arc<Reflector>,
         scheduler: Receiver<ManagerMsg>,
+        runs: Runs,
+        config: SessionConfig,
         state: watch::Receiver<ControllerState>,
+        leader: watch::Receiver<bool>,
     ) -> Self {
+        let Runs {
+            queue,
+            quotas,
+            history,
+            logs,
+            script_ids,
+            cancels,
+            drains,
+        } = runs;
+        let SessionConfig {
+            locality,
+            lease,
+            heartbeat,
+            auth,
+            commit,
+        } = config;
+        let auth = Authenticator::new(auth, client.clone());
         Self {
             scripts: Default::default(),
             executors: Default::default(),
+            paused: Default::default(),
             executor_idgen: Default::default(),
             client,
             pp: PatchParams::apply(MANAGER),
+            store,
             scheduler,
+            queue,
+            quotas,
+            history,
+            logs,
+            script_ids,
+            cancels,
+            drains,
+            locality,
+            lease,
+            heartbeat,
+            auth: Arc::new(auth),
+            commits: Commits::new(commit),
             state,
+            leader,
+        }
+    }
+
+    fn reclaimer(&self) -> Reclaimer {
+        Reclaimer {
+            client: self.client.clone(),
+            pp: self.pp.clone(),
+            queue: self.queue.clone(),
+            history: self.history.clone(),
+            script_ids: self.script_ids.clone(),
+            lease: self.lease.clone(),
+        }
+    }
+
+    /// Reclaim runs whose lease expired, their executors are asked to stop them
+    pub fn reclaim_expired(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let scripts = self.scripts.clone();
+        let executors = self.executors.clone();
+        let reclaimer = self.reclaimer();
+        let mut state = self.state.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let now = Instant::now();
+                        let expired = take_runs(&scripts, |s| s.deadline.map_or(false, |d| d <= now));
+                        for (id, run) in expired {
+                            const EXPIRED: &str = "Run lease expired";
+                            if let Some(executor) = executors.get(&run.executor) {
+                                if executor.has(capability::CANCEL) {
+                                    let _ = executor.outbox.send(message::cancel(id, EXPIRED));
+                                }
+                            }
+                            reclaimer.lost(id, run, EXPIRED).await;
+                        }
+                    }
+                    _ = wait_for_stop(&mut state) => break Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Reject runs with `LocalityFallback::Never` which waited `wait_secs` while no executor
+    /// was active on the node of their devices
+    pub fn expire_stranded(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let queue = self.queue.clone();
+        let executors = self.executors.clone();
+        let locality = self.locality.clone();
+        let client = self.client.clone();
+        let pp = self.pp.clone();
+        let history = self.history.clone();
+        let mut state = self.state.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tokio::select! {
+                    _ = interval.tick(), if locality.fallback == LocalityFallback::Never => {
+                        let candidates = Candidates::collect(&executors, None);
+                        let expired = queue.expire_where(|msg, age| stranded(&candidates, &locality, msg, age));
+                        for msg in expired {
+                            warn!(name = %msg.name, namespace = %msg.namespace, "No executor on the node of the devices");
+                            let message = "No connected executor on the node of the devices".to_owned();
+                            reject(&client, &pp, &history, msg, ScriptStatusCode::NoExecutor, message).await;
+                        }
+                    }
+                    _ = wait_for_stop(&mut state) => break Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Move runs from the scheduler into the queue of their script type.
+    /// Runs no connected executor can execute are rejected at once,
+    /// runs dropped by the queue are reported on their Script.
+    pub fn dispatch(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let scheduler = self.scheduler.clone();
+        let queue = self.queue.clone();
+        let executors = self.executors.clone();
+        let client = self.client.clone();
+        let pp = self.pp.clone();
+        let history = self.history.clone();
+        let mut state = self.state.clone();
+        async move {
+            loop {
+                tokio::select! {
+                    msg = scheduler.recv_async() => {
+                        let msg = msg?;
+                        if !capable(&executors, msg.script_type()) {
+                            no_executor(&client, &pp, &history, msg).await;
+                            continue;
+                        }
+                        // a full queue may block, stopping doesn't wait for space
+                        let dropped = tokio::select! {
+                            dropped = queue.push(msg) => dropped,
+                            _ = wait_for_stop(&mut state) => break Ok(()),
+                        };
+                        if let Some((dropped, reason)) = dropped {
+                            dropped_run(&client, &pp, &history, dropped, reason).await;
+                        }
+                    }
+                    _ = wait_for_stop(&mut state) => break Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Cancel runs asked for with `Cancels`.
+    /// Queued runs are reported as cancelled at once, running runs are reported by their
+    /// executor when it stopped them.
+    pub fn cancel(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let requests = self.cancels.requests();
+        let queue = self.queue.clone();
+        let scripts = self.scripts.clone();
+        let executors = self.executors.clone();
+        let client = self.client.clone();
+        let pp = self.pp.clone();
+        let history = self.history.clone();
+        let mut state = self.state.clone();
+        async move {
+            loop {
+                tokio::select! {
+                    request = requests.recv_async() => {
+                        let CancelRequest { target, reason, reply } = request?;
+                        let queued = queue.cancel_where(|m| target.matches_queued(m));
+                        let mut cancelled = queued.len();
+                        for msg in queued {
+                            info!(run_id = %msg.run.run_id, name = %msg.name, namespace = %msg.namespace, reason = %reason, "Queued run cancelled");
+                            reject(&client, &pp, &history, msg, ScriptStatusCode::Cancelled, reason.clone()).await;
+                        }
+                        for mut run in scripts.iter_mut() {
+                            if !target.matches(&run.run_id, &run.namespace, &run.name) {
+                                continue;
+                            }
+                            // a cancelled run is not sent again if its executor is lost
+                            run.retry = None;
+                            let id = *run.key();
+                            info!(id =? id, run_id = %run.run_id, executor =? run.executor, reason = %reason, "Cancel running run");
+                            match executors.get(&run.executor) {
+                                Some(executor) if executor.has(capability::CANCEL) => {
+                                    let _ = executor.outbox.send(message::cancel(id, &reason));
+                                    cancelled += 1;
+                                }
+                                Some(_) => warn!(id =? id, executor =? run.executor, "Executor can't cancel runs"),
+                                None => {}
+                            }
+                        }
+                        if let Some(reply) = reply {
+                            let _ = reply.send(cancelled);
+                        }
+                    }
+                    _ = wait_for_stop(&mut state) => break Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Serve `Drains`, executors are paused by their session when it takes the message.
+    /// Queued runs no executor is left for are rejected after a pause.
+    pub fn drain(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        let requests = self.drains.requests();
+        let executors = self.executors.clone();
+        let paused = self.paused.clone();
+        let scripts = self.scripts.clone();
+        let queue = self.queue.clone();
+        let client = self.client.clone();
+        let pp = self.pp.clone();
+        let history = self.history.clone();
+        let mut state = self.state.clone();
+        async move {
+            loop {
+                tokio::select! {
+                    request = requests.recv_async() => match request? {
+                        DrainRequest::List(reply) => {
+                            let mut list: Vec<ExecutorSummary> = executors
+                                .iter()
+                                .map(|e| ExecutorSummary {
+                                    id: (*e.key()).into(),
+                                    addr: e.addr.to_string(),
+                                    identity: e.identity.to_string(),
+                                    node: e.node.name.clone(),
+                                    protocol: e.protocol.to_string(),
+                                    state: format!("{:?}", e.state),
+                                    running: scripts.iter().filter(|s| s.executor == *e.key()).count(),
+                                    credits: e.credits,
+                                    max_job: e.max_job,
+                                })
+                                .collect();
+                            list.sort_by_key(|e| e.id);
+                            let _ = reply.send(list);
+                        }
+                        DrainRequest::Pause { executor, paused: pause, reply } => {
+                            let found = executors.get_mut(&ExecutorID::from(executor)).map(|mut info| {
+                                info!(id = executor, paused = pause, state =? info.state, "Pause executor");
+                                let key = (info.identity.clone(), info.node.name.clone());
+                                if pause {
+                                    paused.insert(key);
+                                } else {
+                                    paused.remove(&key);
+                                }
+                                let _ = info.outbox.send(message::pause(pause));
+                                // the session keeps it when it takes the message, seen by `capable` at once
+                                let before = info.state;
+                                info.state = before.pause(pause);
+                                before
+                            });
+                            let _ = reply.send(found);
+                            if pause && found.is_some() {
+                                reject_incapable(&executors, &queue, &client, &pp, &history).await;
+                            }
+                        }
+                    },
+                    _ = wait_for_stop(&mut state) => break Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Whether the run was leased to an executor of `identity`
+    fn owns(&self, run: &ScriptStatus, identity: &Identity) -> bool {
+        &run.identity == identity
+    }
+
+    /// (namespace, name, run id) of a dispatched run
+    fn run_of(
+        &self,
+        id: ScriptID,
+        identity: &Identity,
+    ) -> Result<(String, String, String), Status> {
+        if let Some(run) = self.scripts.get(&id) {
+            if !self.owns(&run, identity) {
+                return Err(Status::permission_denied(
+                    "Run is leased to another executor",
+                ));
+            }
+            return Ok((run.namespace.clone(), run.name.clone(), run.run_id.clone()));
+        }
+        // output of a run given up as lost
+        match self.history.attempt(id.into()) {
+            Some(record) if record.identity.as_ref() != Some(identity) => Err(
+                Status::permission_denied("Run was leased to another executor"),
+            ),
+            Some(record) => Ok((record.namespace, record.name, record.run_id.into())),
+            None => Err(Status::invalid_argument("Script isn't running")),
+        }
+    }
+
+    async fn patch_desired(
+        &self,
+        namespace: &str,
+        device: &str,
+        desired: &HashMap<String, String>,
+    ) -> Result<(), Status> {
+        self.update_twins(namespace, device, |dev| commit::set_desired(dev, desired))
+            .await
+            .map_err(|e| {
+                error!(error =? e, "Failed to update status of Device");
+                Status::internal("Failed to update status of Device")
+            })
+    }
+
+    /// Read the twins of a device, change and write them back. The resourceVersion makes
+    /// the write fail if the device changed meanwhile, it is read again then.
+    async fn update_twins(
+        &self,
+        namespace: &str,
+        device: &str,
+        update: impl Fn(&mut Device),
+    ) -> kube::Result<()> {
+        let api: Api<Device> = Api::namespaced(self.client.clone(), namespace);
+        let mut conflicts = 0;
+        loop {
+            let mut dev = api.get(device).await?;
+            update(&mut dev);
+            let patch = serde_json::json!({
+                "metadata": { "resourceVersion": dev.metadata.resource_version },
+                "status": dev.status,
+            });
+            match api.patch(device, &self.pp, &Patch::Merge(&patch)).await {
+                Err(kube::Error::Api(e)) if e.code == 409 && conflicts < CONFLICT_RETRIES => {
+                    trace!(device, "Device changed meanwhile, write again");
+                    conflicts += 1;
+                }
+                result => return result.map(|_| ()),
+            }
         }
     }
 
-    fn validate_metadata(meta: &MetadataMap) -> Result<(), Status> {
+    /// Write desired values of a device for a run of the Script `namespace/name`
+    async fn write_desired(
+        &self,
+        namespace: &str,
+        name: &str,
+        id: ScriptID,
+        request: &proto::UpdateDevice,
+    ) -> Result<(), Status> {
+        if !self.writable(namespace, name)?.contains(&request.name) {
+            return Err(Status::permission_denied(format!(
+                "Device {} isn't writable",
+                request.name
+            )));
+        }
+        self.patch_desired(namespace, &request.name, &request.desired)
+            .await?;
+        self.history.write(
+            namespace,
+            name,
+            id.into(),
+            DeviceWrite {
+                device: request.name.clone(),
+                desired: request.desired.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Devices selected by the `writeSelector` of a Script
+    fn writable(&self, namespace: &str, name: &str) -> Result<HashSet<String>, Status> {
+        let script = ResourceIndex::<Script> {
+            namespace: namespace.to_owned(),
+            name: name.to_owned(),
+            api: Default::default(),
+        };
+        self.store
+            .script_store
+            .get(&script)
+            .map(|s| {
+                s.spec
+                    .write_selector
+                    .match_names
+                    .iter()
+                    .flat_map(|names| names.values().cloned())
+                    .collect()
+            })
+            .ok_or_else(|| Status::not_found("Script not found"))
+    }
+
+    /// Validate a batch, write all its devices and wait for them as its qos asks
+    async fn commit_batch(
+        &self,
+        namespace: &str,
+        name: &str,
+        id: ScriptID,
+        request: &UpdateDevices,
+    ) -> Result<UpdateDevicesResult, Status> {
+        let device_index = |device: &str| ResourceIndex::<Device> {
+            namespace: namespace.to_owned(),
+            name: device.to_owned(),
+            api: Default::default(),
+        };
+        let selected = self.writable(namespace, name)?;
+        let writable = selected.iter().map(String::as_str).collect();
+        commit::validate(&request.devices, &writable, |device| {
+            self.store.device_store.contains_key(&device_index(device))
+        })?;
+
+        // desired values before the batch, restored if a later write fails
+        let mut written = Vec::with_capacity(request.devices.len());
+        for device in &request.devices {
+            let previous = commit::previous_desired(
+                self.store
+                    .device_store
+                    .get(&device_index(&device.name))
+                    .as_deref(),
+                device.desired.keys().cloned(),
+            );
+            if self
+                .patch_desired(namespace, &device.name, &device.desired)
+                .await
+                .is_err()
+            {
+                let failed = self.rollback(namespace, written).await;
+                if !failed.is_empty() {
+                    return Err(Status::internal(format!(
+                        "Failed to write device {}, devices {} couldn't be restored",
+                        device.name,
+                        failed.join(", ")
+                    )));
+                }
+                return Err(Status::aborted(format!(
+                    "Failed to write device {}, the batch is rolled back",
+                    device.name
+                )));
+            }
+            written.push((device.name.as_str(), previous));
+        }
+        for device in &request.devices {
+            self.history.write(
+                namespace,
+                name,
+                id.into(),
+                DeviceWrite {
+                    device: device.name.clone(),
+                    desired: device.desired.clone(),
+                },
+            );
+        }
+
+        let retry = match request.qos() {
+            QosPolicy::AtMostOnce => {
+                let devices = request
+                    .devices
+                    .iter()
+                    .map(|d| (d.name.clone(), commit::sent()))
+                    .collect();
+                return Ok(UpdateDevicesResult { devices });
+            }
+            QosPolicy::AtLeastOnce => true,
+            QosPolicy::OnlyOnce => false,
+        };
+        let confirmed = futures::future::try_join_all(
+            request
+                .devices
+                .iter()
+                .map(|d| self.confirm_desired(namespace, &d.name, &d.desired, retry)),
+        )
+        .await?;
+        let devices = request
+            .devices
+            .iter()
+            .map(|d| d.name.clone())
+            .zip(confirmed)
+            .collect();
+        Ok(UpdateDevicesResult { devices })
+    }
+
+    /// Restore the desired values written by a failed batch, latest device first.
+    /// Returns the devices which couldn't be restored.
+    async fn rollback(
+        &self,
+        namespace: &str,
+        written: Vec<(&str, HashMap<String, Option<String>>)>,
+    ) -> Vec<String> {
+        let mut failed = Vec::new();
+        for (device, previous) in written.into_iter().rev() {
+            let restore = |dev: &mut Device| commit::restore_desired(dev, &previous);
+            if let Err(e) = self.update_twins(namespace, device, restore).await {
+                error!(error =? e, device, "Failed to restore status of Device");
+                failed.push(device.to_owned());
+            }
+        }
+        failed
+    }
+
+    /// Wait until the device reports the desired values, or `confirm_secs` pass.
+    /// With `retry` the values are written again every `retry_secs` meanwhile.
+    async fn confirm_desired(
+        &self,
+        namespace: &str,
+        device: &str,
+        desired: &HashMap<String, String>,
+        retry: bool,
+    ) -> Result<UpdateDeviceResult, Status> {
+        let config = self.commits.config();
+        let idx = ResourceIndex {
+            namespace: namespace.to_owned(),
+            name: device.to_owned(),
+            api: Default::default(),
+        };
+        let deadline = Instant::now() + config.confirm();
+        let mut written = Instant::now();
+        let mut poll = tokio::time::interval(CONFIRM_POLL);
+        loop {
+            poll.tick().await;
+            let pending = commit::pending(self.store.device_store.get(&idx).as_deref(), desired);
+            if pending.is_empty() {
+                return Ok(UpdateDeviceResult {
+                    outcome: Delivery::Confirmed as i32,
+                    pending,
+                });
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(device =? idx, pending =? pending, "Device didn't report the desired values");
+                return Ok(UpdateDeviceResult {
+                    outcome: Delivery::Timeout as i32,
+                    pending,
+                });
+            }
+            if retry && now.saturating_duration_since(written) >= config.retry() {
+                info!(device =? idx, pending =? pending, "Desired values not reported, write again");
+                // a failed write is tried again with the next retry
+                let _ = self.patch_desired(namespace, device, desired).await;
+                written = now;
+            }
+        }
+    }
+
+    /// Protocol version of the executor, it must be in `SUPPORTED_PROTOCOL`
+    fn validate_metadata(meta: &MetadataMap) -> Result<Version, Status> {
         let version = meta
             .get(RE_VERSION)
             .and_then(|v| v.to_str().ok())
             .unwrap_or("");
-        const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
-        if version != SERVER_VERSION {
-            error!(version, "Unaccept version");
-            return Err(Status::invalid_argument(format!(
-                "Version mismatch, server: {}, client: {}",
-                SERVER_VERSION, version
-            )));
+        if version == LEGACY_VERSION {
+            return Ok(legacy_protocol());
+        }
+        let supported = VersionReq::parse(SUPPORTED_PROTOCOL).expect("Invalid SUPPORTED_PROTOCOL");
+        match Version::parse(version) {
+            Ok(parsed) if supported.matches(&parsed) => Ok(parsed),
+            _ => {
+                error!(version, "Unaccept version");
+                Err(Status::failed_precondition(format!(
+                    "Unsupported protocol version {}, server: {} supports {}",
+                    version,
+                    proto::PROTOCOL_VERSION,
+                    SUPPORTED_PROTOCOL
+                )))
+            }
         }
-        Ok(())
     }
 
     fn handle_first_message(
-        &self,
         msg: Result<Option<ClientMessage>, Status>,
-    ) -> Result<(), Status> {
+        protocol: &Version,
+    ) -> Result<(ClientInfo, u32), Status> {
         match msg {
             Ok(Some(m)) => {
                 if m.code() != ClientCode::Connect {
@@ -109,8 +1069,28 @@ impl SessionManager {
                                 "Connect message don't have connection field",
                             ))
                         }
-                        // TODO: client info ?
-                        Some(_info) => Ok(()),
+                        // legacy executors don't announce script types, they get every run
+                        Some(mut info)
+                            if info.script_types.is_empty() && *protocol == legacy_protocol() =>
+                        {
+                            info.script_types = [
+                                ScriptType::Wasm,
+                                ScriptType::Js,
+                                ScriptType::Native,
+                                ScriptType::Standalone,
+                            ]
+                            .into_iter()
+                            .map(|t| t as i32)
+                            .collect();
+                            Ok((info, m.credits))
+                        }
+                        Some(info) if info.script_types.is_empty() => {
+                            trace!(info =? info, "Executor doesn't support any script type");
+                            Err(Status::invalid_argument(
+                                "Executor doesn't support any script type",
+                            ))
+                        }
+                        Some(info) => Ok((info, m.credits)),
                     }
                 }
             }
@@ -136,43 +1116,111 @@ impl ControllerService for SessionManager {
         request: Request<Streaming<ClientMessage>>,
     ) -> Result<Response<Self::runStream>, Status> {
         // Header check
-        Self::validate_metadata(request.metadata())?;
+        let protocol = Self::validate_metadata(request.metadata())?;
+        // a streaming request isn't Sync, don't borrow it across the await
+        let authenticated = self.auth.authenticate(&request);
+        let identity = authenticated.await?;
+        // Only the leader serves executors, they reconnect through the Service
+        if !*self.leader.borrow() {
+            return Err(Status::unavailable("Controller is not the leader"));
+        }
         let addr = request.remote_addr().unwrap();
         let mut stream = request.into_inner();
         // connect message handle
-        self.handle_first_message(stream.message().await)?;
-        info!(addr =? addr, "New executor connection");
-        let exeinfo = ExecutorInfo { addr };
+        let (info, credits) = Self::handle_first_message(stream.message().await, &protocol)?;
+        let script_types: Vec<ScriptType> = info.script_types().collect();
+        let max_job = info.max_job;
+        let mut credits = grant(0, max_job, credits);
+        let node = NodeInfo {
+            name: info.node_name,
+            labels: info.node_labels,
+        };
+        let capabilities = info.capabilities;
+        info!(addr =? addr, identity = %identity, protocol = %protocol, capabilities =? capabilities, script_types =? script_types, runtimes =? info.runtimes, max_job, credits, node =? node, "New executor connection");
+        // executors which don't answer heartbeats are never evicted for missing them
+        let heartbeats = self.heartbeat.interval_secs > 0
+            && capabilities.iter().any(|c| c == capability::HEARTBEAT);
+        // older executors drop the connection on messages they don't know
+        let pausable = capabilities.iter().any(|c| c == capability::PAUSE);
+        // a pause outlives the connection, the executor reports it after a leader change
+        let paused = info.paused || self.paused.contains(&(identity.clone(), node.name.clone()));
+        // without heartbeats an executor is ready at once
+        let initial_state = if paused {
+            ExecutorState::Pause
+        } else if heartbeats {
+            ExecutorState::Init
+        } else {
+            ExecutorState::Ready
+        };
+        let (outbox, outbox_rx) = flume::unbounded();
+        let exeinfo = ExecutorInfo {
+            addr,
+            script_types: script_types.clone(),
+            protocol,
+            capabilities,
+            max_job,
+            credits,
+            node,
+            identity: identity.clone(),
+            state: initial_state,
+            last_heartbeat: Instant::now(),
+            load: None,
+            outbox,
+        };
         let executor_id = self.executor_idgen.gen();
-        let scheduler = self.scheduler.clone();
+        let queue = self.queue.clone();
+        let locality = self.locality.clone();
+        let quotas = self.quotas.clone();
+        let history = self.history.clone();
+        let lease = self.lease.clone();
+        let heartbeat = self.heartbeat.clone();
         let mut state = self.state.clone();
+        let mut leader = self.leader.clone();
         self.executors.insert(executor_id, exeinfo);
         let executors = self.executors.clone();
         let scripts = self.scripts.clone();
+        let session = Session {
+            id: executor_id,
+            executors: executors.clone(),
+            scripts: scripts.clone(),
+            reclaimer: self.reclaimer(),
+        };
         let s = stream! {
+            // cleans up when the stream ends or is dropped
+            let _session = session;
             // connect message response
             yield Ok(message::connected(executor_id));
+            if paused && pausable {
+                yield Ok(message::pause(true));
+            }
 
+            // the first tick is at once, the executor becomes ready when it answers
+            let mut ticks = tokio::time::interval(heartbeat.interval().max(Duration::from_secs(1)));
+            let mut executor_state = initial_state;
             // main loop
             loop {
                 tokio::select! {
                     msg = stream.next() => match msg {
                         Some(Ok(msg)) => match msg.code() {
-                            ClientCode::Continue => match scheduler.recv_async().await {
-                                Err(e) => {
-                                    error!(error =? e, "Scheduler is down!");
-                                    yield Err(Status::internal("Scheduler is down"));
-                                    break;
+                            ClientCode::Heartbeat => {
+                                trace!(id =? executor_id, load =? msg.load, "Heartbeat");
+                                if executor_state == ExecutorState::Init {
+                                    info!(id =? executor_id, "Executor is ready");
+                                    executor_state = ExecutorState::Ready;
                                 }
-                                Ok(task) => {
-                                    scripts.insert(task.run.script_id.into(), ScriptStatus {
-                                        name: task.name,
-                                        namespace: task.namespace,
-                                        executor: executor_id
-                                    });
-                                    yield Ok(ServerMessage {
-                                        msg: Some(Msg::Script(task.run))
-                                    })
+                                if let Some(mut info) = executors.get_mut(&executor_id) {
+                                    info.state = executor_state;
+                                    info.last_heartbeat = Instant::now();
+                                    info.load = msg.load;
+                                }
+                                // runs of a live executor keep their lease
+                                renew(&scripts, executor_id, &lease, Instant::now());
+                            },
+                            ClientCode::Continue => {
+                                credits = grant(credits, max_job, msg.credits.max(1));
+                                trace!(id =? executor_id, credits, "Executor returned credits");
+                                if let Some(mut info) = executors.get_mut(&executor_id) {
+                                    info.credits = credits;
                                 }
                             },
                             ClientCode::Connect => {
@@ -180,8 +1228,22 @@ impl ControllerService for SessionManager {
                                 yield Err(Status::invalid_argument("Got unexpect Connect message"));
                                 break;
                             },
+                            ClientCode::Drain => {
+                                info!(id =? executor_id, "Executor drains");
+                                executor_state = ExecutorState::Draining;
+                                if let Some(mut info) = executors.get_mut(&executor_id) {
+                                    info.state = executor_state;
+                                }
+                                // runs dispatched before are ahead of it in the stream
+                                if pausable {
+                                    yield Ok(message::pause(true));
+                                }
+                            },
                             ClientCode::Disconnect => {
                                 trace!("Client disconnected");
+                                if let Some(mut info) = executors.get_mut(&executor_id) {
+                                    info.state = ExecutorState::Disconnect;
+                                }
                                 yield Ok(message::disconnect(DisconnectReason::ClientExit));
                                 break
                             },
@@ -196,23 +1258,75 @@ impl ControllerService for SessionManager {
                             break;
                         }
                     },
+                    // `pop` takes a run only when it returns, so it can be cancelled
+                    // shared maps are read before the queue is locked
+                    task = queue.pop_with(&script_types, || {
+                        let running = running_by_namespace(&scripts);
+                        let candidates = Candidates::collect(&executors, Some(executor_id));
+                        let (quotas, locality) = (&quotas, &locality);
+                        move |msg: &ManagerMsg, age| {
+                            quotas.may_start(&msg.namespace, running.get(&msg.namespace).copied().unwrap_or_default())
+                                && eligible(&candidates, locality, msg, age)
+                        }
+                    }), if credits > 0 && executor_state == ExecutorState::Ready => {
+                        credits -= 1;
+                        if let Some(mut info) = executors.get_mut(&executor_id) {
+                            info.credits = credits;
+                        }
+                        let mut task = task;
+                        task.attempts += 1;
+                        history.dispatched(&task, executor_id, &identity);
+                        let deadline = lease.deadline(Instant::now());
+                        let retry = (task.run.default_qos() == QosPolicy::AtLeastOnce).then(|| task.clone());
+                        scripts.insert(task.run.script_id.into(), ScriptStatus {
+                            name: task.name,
+                            namespace: task.namespace,
+                            run_id: task.run.run_id.clone(),
+                            executor: executor_id,
+                            identity: identity.clone(),
+                            deadline,
+                            retry,
+                        });
+                        yield Ok(ServerMessage {
+                            msg: Some(Msg::Script(task.run))
+                        })
+                    },
+                    Ok(msg) = outbox_rx.recv_async() => {
+                        if let Some(Msg::Pause(pause)) = &msg.msg {
+                            executor_state = executor_state.pause(pause.paused);
+                            if let Some(mut info) = executors.get_mut(&executor_id) {
+                                info.state = executor_state;
+                            }
+                            if !pausable {
+                                continue;
+                            }
+                        }
+                        yield Ok(msg)
+                    },
+                    _ = ticks.tick(), if heartbeats => {
+                        let last = executors.get(&executor_id).map(|info| info.last_heartbeat);
+                        if last.map_or(true, |last| heartbeat.expired(last, Instant::now())) {
+                            warn!(id =? executor_id, "Executor missed heartbeats, evict it");
+                            if let Some(mut info) = executors.get_mut(&executor_id) {
+                                info.state = ExecutorState::Disconnect;
+                            }
+                            yield Ok(message::disconnect(DisconnectReason::HeartbeatTimeout));
+                            break
+                        }
+                        yield Ok(message::heartbeat(heartbeat.interval_secs as u32))
+                    },
                     _ = wait_for_stop(&mut state) => {
                         yield Ok(message::disconnect(DisconnectReason::ServerExit));
                         break
                     },
+                    _ = wait_for_leader(&mut leader, false) => {
+                        info!(id =? executor_id, "Lost leadership, disconnect executor");
+                        yield Ok(message::disconnect(DisconnectReason::LostLeadership));
+                        break
+                    },
                     else => break,
                 };
             }
-
-            // Disconnect
-            match executors.remove(&executor_id) {
-                Some((id, info)) => {
-                    trace!(id =? id, info =? info, "Executor disconnected")
-                }
-                None => {
-                    error!(id =? executor_id, "Unknown executor disconnected")
-                }
-            }
         }
         .boxed();
         Ok(Response::new(s))
@@ -223,46 +1337,73 @@ impl ControllerService for SessionManager {
         &self,
         status: Request<proto::ScriptStatus>,
     ) -> Result<Response<()>, Status> {
+        let identity = self.auth.authenticate(&status).await?;
         let id = ScriptID::from(status.get_ref().script_id);
         info!(id =? id, "Script exit");
-        match self.scripts.remove(&id) {
+        let last_run = status
+            .get_ref()
+            .start
+            .as_ref()
+            .map(|t| t.seconds * 1000 + t.nanos as i64 / 1_000_000)
+            .unwrap_or_default();
+        let elapsed_time = status
+            .get_ref()
+            .duration
+            .as_ref()
+            .map(|d| (d.seconds * 1_000_000 + d.nanos as i64 / 1000) as u32)
+            .unwrap_or_default();
+        let api_status = api::script::ScriptStatus {
+            last_run,
+            elapsed_time,
+            status: status.get_ref().code,
+            message: status.get_ref().message.clone(),
+            output: run_output(&status.get_ref().output),
+        };
+        let (namespace, name) = match self.scripts.remove_if(&id, |_, s| self.owns(s, &identity)) {
             Some((_, sess_status)) => {
                 info!(status =? sess_status, "Script exit");
-                let client = self.client.clone();
-                let api: Api<Script> = Api::namespaced(client, &sess_status.namespace);
-                let last_run = status
-                    .get_ref()
-                    .start
-                    .as_ref()
-                    .map(|t| t.seconds * 1000 + t.nanos as i64 / 1_000_000)
-                    .unwrap_or_default();
-                let elapsed_time = status
-                    .get_ref()
-                    .duration
-                    .as_ref()
-                    .map(|d| (d.seconds * 1_000_000 + d.nanos as i64 / 1000) as u32)
-                    .unwrap_or_default();
-                let api_status = api::script::ScriptStatus {
-                    last_run,
-                    elapsed_time,
-                    status: status.get_ref().code,
-                    message: status.into_inner().message,
-                };
-                let patch = serde_json::json!({ "status": api_status });
-                let patch = Patch::Merge(&patch);
-                match api.patch_status(&sess_status.name, &self.pp, &patch).await {
-                    Ok(_) => Ok(Response::new(())),
-                    Err(e) => {
-                        error!(error =? e, "Failed to update status of Script");
-                        Err(Status::internal("Failed to update status of Script"))
-                    }
-                }
+                // runs held back by the concurrency quota may start now
+                self.queue.wake();
+                (sess_status.namespace, sess_status.name)
             }
-            None => {
-                error!(request =? status, "Got message of updating script status， but script isn't running");
-                Err(Status::invalid_argument(
-                    "Got message of updating script status， but script isn't running",
-                ))
+            None if self.scripts.contains_key(&id) => {
+                warn!(id =? id, identity = %identity, "Status reported by another executor");
+                return Err(Status::permission_denied(
+                    "Run is leased to another executor",
+                ));
+            }
+            None => match self.history.attempt(id.into()) {
+                Some(record) if record.identity.as_ref() != Some(&identity) => {
+                    warn!(id =? id, identity = %identity, "Late status reported by another executor");
+                    return Err(Status::permission_denied(
+                        "Run was leased to another executor",
+                    ));
+                }
+                // the run was sent again, that attempt reports its status
+                Some(record) if record.retried => {
+                    warn!(id =? id, run_id = %record.run_id, "Drop late status of a lost attempt");
+                    return Ok(Response::new(()));
+                }
+                // the run was given up as lost, but it did finish
+                Some(record) => {
+                    warn!(id =? id, run_id = %record.run_id, "Late status of a lost run");
+                    (record.namespace, record.name)
+                }
+                None => {
+                    error!(request =? status, "Got message of updating script status， but script isn't running");
+                    return Err(Status::invalid_argument(
+                        "Got message of updating script status， but script isn't running",
+                    ));
+                }
+            },
+        };
+        self.history
+            .finished(&namespace, &name, id.into(), &api_status);
+        match patch_script_status(&self.client, &self.pp, &namespace, &name, &api_status).await {
+            Ok(_) => Ok(Response::new(())),
+            Err(e) => {
+                error!(error =? e, "Failed to update status of Script");
+                Err(Status::internal("Failed to update status of Script"))
             }
         }
     }
@@ -271,49 +1412,313 @@ impl ControllerService for SessionManager {
     async fn update_device_desired(
         &self,
         device: Request<proto::UpdateDevice>,
-    ) -> Result<Response<()>, Status> {
+    ) -> Result<Response<UpdateDeviceResult>, Status> {
+        let identity = self.auth.authenticate(&device).await?;
         let id = ScriptID::from(device.get_ref().script_id);
         info!(id =? id, "Script update device");
-        match self.scripts.get(&id) {
-            Some(sess_script) => {
-                let client = self.client.clone();
-                let api: Api<Device> = Api::namespaced(client, &sess_script.namespace);
-                let mut twins = Vec::new();
-                for (k, v) in device.get_ref().desired.iter() {
-                    twins.push(Twin {
-                        property_name: k.to_owned(),
-                        desired: TwinProperty::new(v.to_owned()),
-                        reported: None,
-                    })
-                }
-                let api_status = DeviceStatus { twins };
-                let patch = serde_json::json!({ "status": api_status });
-                let patch = Patch::Merge(&patch);
-                if let Err(e) = api.patch(&device.get_ref().name, &self.pp, &patch).await {
-                    error!(error =? e, "Failed to update status of Device");
-                    return Err(Status::internal("Failed to update status of Device"));
-                }
-                // FIXME: Qos
-                match device.get_ref().qos() {
-                    QosPolicy::AtMostOnce => Ok(Response::new(())),
-                    QosPolicy::AtLeastOnce => Err(Status::internal("Not implemented")),
-                    QosPolicy::OnlyOnce => Err(Status::internal("Not implemented")),
-                }
+        // the lease isn't held while waiting for the device
+        let (namespace, name, run_id, executor) = match self.scripts.get(&id) {
+            Some(sess_script) if !self.owns(&sess_script, &identity) => {
+                warn!(id =? id, identity = %identity, "Device written by another executor");
+                return Err(Status::permission_denied(
+                    "Run is leased to another executor",
+                ));
             }
+            Some(sess_script) => (
+                sess_script.namespace.clone(),
+                sess_script.name.clone(),
+                sess_script.run_id.clone(),
+                sess_script.executor,
+            ),
             None => {
                 error!(request =? device, "Got message of updating device desired, but script isn't running");
-                Err(Status::invalid_argument(
+                return Err(Status::invalid_argument(
                     "Got message of updating device desired, but script isn't running",
+                ));
+            }
+        };
+        let request = device.into_inner();
+        let result = match request.qos() {
+            QosPolicy::AtMostOnce => {
+                self.write_desired(&namespace, &name, id, &request).await?;
+                commit::sent()
+            }
+            QosPolicy::AtLeastOnce => {
+                self.write_desired(&namespace, &name, id, &request).await?;
+                self.confirm_desired(&namespace, &request.name, &request.desired, true)
+                    .await?
+            }
+            QosPolicy::OnlyOnce => {
+                let device_qos = self
+                    .executors
+                    .get(&executor)
+                    .map_or(false, |e| e.has(capability::DEVICE_QOS));
+                let key = match only_once_key(&request, device_qos) {
+                    Some(key) => key,
+                    None => {
+                        // older executors send no key, the commit is written once without dedup
+                        warn!(id =? id, "OnlyOnce commit without idempotency key, it isn't deduplicated");
+                        self.write_desired(&namespace, &name, id, &request).await?;
+                        let result = self
+                            .confirm_desired(&namespace, &request.name, &request.desired, false)
+                            .await?;
+                        return Ok(Response::new(result));
+                    }
+                };
+                let outcome = self.commits.once(&run_id, key);
+                if let Some(result) = outcome.get() {
+                    info!(id =? id, key, "Commit applied before, return its outcome");
+                    result.clone()
+                } else {
+                    // a retry arriving meanwhile waits for this commit
+                    outcome
+                        .get_or_try_init(|| async {
+                            self.write_desired(&namespace, &name, id, &request).await?;
+                            self.confirm_desired(&namespace, &request.name, &request.desired, false)
+                                .await
+                        })
+                        .await?
+                        .clone()
+                }
+            }
+        };
+        Ok(Response::new(result))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn update_devices_desired(
+        &self,
+        request: Request<UpdateDevices>,
+    ) -> Result<Response<UpdateDevicesResult>, Status> {
+        let identity = self.auth.authenticate(&request).await?;
+        let id = ScriptID::from(request.get_ref().script_id);
+        info!(id =? id, "Script update devices");
+        let (namespace, name, run_id) = match self.scripts.get(&id) {
+            Some(run) if self.owns(&run, &identity) => {
+                (run.namespace.clone(), run.name.clone(), run.run_id.clone())
+            }
+            Some(_) => {
+                return Err(Status::permission_denied(
+                    "Run is leased to another executor",
                 ))
             }
+            None => return Err(Status::invalid_argument("Script isn't running")),
+        };
+        let request = request.into_inner();
+        if request.qos() != QosPolicy::OnlyOnce {
+            let result = self.commit_batch(&namespace, &name, id, &request).await?;
+            return Ok(Response::new(result));
+        }
+        if request.idempotency_key.is_empty() {
+            return Err(Status::invalid_argument(
+                "OnlyOnce commit without idempotency key",
+            ));
+        }
+        let outcome = self.commits.once_batch(&run_id, &request.idempotency_key);
+        if let Some(result) = outcome.get() {
+            info!(id =? id, key = %request.idempotency_key, "Batch applied before, return its outcome");
+            return Ok(Response::new(result.clone()));
         }
+        let result = outcome
+            .get_or_try_init(|| self.commit_batch(&namespace, &name, id, &request))
+            .await?;
+        Ok(Response::new(result.clone()))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn push_logs(
+        &self,
+        request: Request<Streaming<LogBatch>>,
+    ) -> Result<Response<()>, Status> {
+        let authenticated = self.auth.authenticate(&request);
+        let identity = authenticated.await?;
+        let mut stream = request.into_inner();
+        // a stream carries the output of one run
+        let mut run: Option<(ScriptID, String, String, String)> = None;
+        let result = loop {
+            let batch = match stream.message().await {
+                Ok(Some(batch)) => batch,
+                Ok(None) => break Ok(Response::new(())),
+                Err(e) => break Err(e),
+            };
+            let id = ScriptID::from(batch.script_id);
+            if run.as_ref().map_or(true, |(script, ..)| *script != id) {
+                let (namespace, name, run_id) = match self.run_of(id, &identity) {
+                    Ok(found) => found,
+                    Err(e) => break Err(e),
+                };
+                if let Some((_, run_id, ..)) = &run {
+                    self.logs.close(run_id);
+                }
+                self.logs.open(&run_id);
+                run = Some((id, run_id, namespace, name));
+            }
+            let (_, run_id, namespace, name) = run.as_ref().unwrap();
+            for entry in batch.entries {
+                let line = LogLine {
+                    seq: 0,
+                    run_id: run_id.clone(),
+                    script_id: batch.script_id,
+                    time: entry.time,
+                    level: entry.level,
+                    message: entry.message,
+                };
+                self.logs.push(namespace, name, line);
+            }
+        };
+        if let Some((_, run_id, ..)) = &run {
+            self.logs.close(run_id);
+        }
+        result
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_device_status(
+        &self,
+        request: Request<GetDeviceStatus>,
+    ) -> Result<Response<ReadDevices>, Status> {
+        let identity = self.auth.authenticate(&request).await?;
+        let id = ScriptID::from(request.get_ref().script_id);
+        let (namespace, name) = match self.scripts.get(&id) {
+            Some(run) if self.owns(&run, &identity) => (run.namespace.clone(), run.name.clone()),
+            Some(_) => {
+                return Err(Status::permission_denied(
+                    "Run is leased to another executor",
+                ))
+            }
+            None => return Err(Status::invalid_argument("Script isn't running")),
+        };
+        let mut store = self.store.clone();
+        let idx = ResourceIndex {
+            namespace,
+            name,
+            api: Default::default(),
+        };
+        // the readable set follows the Script, as when the run was built
+        let (script, mut readable) = store
+            .lookup_script(&idx)
+            .and_then(|script| {
+                let readable = store.lookup_readable(&script)?;
+                Ok((script, readable))
+            })
+            .map_err(|e| {
+                warn!(error =? e, script =? idx, "Failed to look up readable devices");
+                Status::not_found("Script not found")
+            })?;
+        let selected = script.spec.read_selector.match_names.unwrap_or_default();
+        let wanted = &request.get_ref().devices;
+        if let Some(device) = wanted.iter().find(|d| !selected.contains_key(d.as_str())) {
+            warn!(device = %device, script =? idx, "Script read a device it can't read");
+            return Err(Status::permission_denied(format!(
+                "Device {} isn't readable",
+                device
+            )));
+        }
+        if !wanted.is_empty() {
+            readable.retain(|alias, _| wanted.contains(alias));
+        }
+        Ok(Response::new(ReadDevices { devices: readable }))
+    }
+}
+
+async fn patch_script_status(
+    client: &Client,
+    pp: &PatchParams,
+    namespace: &str,
+    name: &str,
+    status: &api::script::ScriptStatus,
+) -> kube::Result<()> {
+    let api: Api<Script> = Api::namespaced(client.clone(), namespace);
+    let patch = serde_json::json!({ "status": status });
+    let patch = Patch::Merge(&patch);
+    api.patch_status(name, pp, &patch).await?;
+    Ok(())
+}
+
+/// Output reported by an executor, if it is JSON within `MAX_OUTPUT`
+fn run_output(output: &str) -> Option<String> {
+    if output.is_empty() {
+        return None;
+    }
+    if output.len() > MAX_OUTPUT {
+        warn!(len = output.len(), "Output of run is too large, dropped");
+        return None;
+    }
+    match serde_json::from_str::<serde_json::Value>(output) {
+        Ok(_) => Some(output.to_owned()),
+        Err(e) => {
+            warn!(error =? e, "Output of run isn't JSON, dropped");
+            None
+        }
+    }
+}
+
+/// Report a run that never reached an executor on the Script status
+async fn reject(
+    client: &Client,
+    pp: &PatchParams,
+    history: &RunHistory,
+    msg: ManagerMsg,
+    code: ScriptStatusCode,
+    message: String,
+) {
+    history.rejected(&msg, code, message.clone());
+    let status = api::script::ScriptStatus {
+        last_run: Utc::now().timestamp_millis(),
+        elapsed_time: 0,
+        status: code as i32,
+        message,
+        output: None,
+    };
+    if let Err(e) = patch_script_status(client, pp, &msg.namespace, &msg.name, &status).await {
+        error!(error =? e, "Failed to update status of Script");
     }
 }
 
+/// Report a run the queue dropped
+async fn dropped_run(
+    client: &Client,
+    pp: &PatchParams,
+    history: &RunHistory,
+    msg: ManagerMsg,
+    reason: Dropped,
+) {
+    info!(name = %msg.name, namespace = %msg.namespace, priority =? msg.priority, reason =? reason, "Queued run dropped");
+    let (code, message) = match reason {
+        Dropped::Preempted => (
+            ScriptStatusCode::Preempted,
+            "Preempted by a higher priority run",
+        ),
+        Dropped::Overflow => (ScriptStatusCode::Overflow, "Run queue is full"),
+    };
+    reject(client, pp, history, msg, code, message.to_owned()).await
+}
+
+async fn no_executor(client: &Client, pp: &PatchParams, history: &RunHistory, msg: ManagerMsg) {
+    let script_type = msg.script_type();
+    warn!(name = %msg.name, namespace = %msg.namespace, script_type =? script_type, "No executor supports the script type");
+    let message = format!(
+        "No connected executor supports script type {:?}",
+        script_type
+    );
+    reject(
+        client,
+        pp,
+        history,
+        msg,
+        ScriptStatusCode::NoExecutor,
+        message,
+    )
+    .await
+}
+
 mod message {
-    use crate::id::ExecutorID;
+    use crate::id::{ExecutorID, ScriptID};
     use proto::{
-        server_message::{disconnect::DisconnectReason, Connected, Disconnect, Msg},
+        server_message::{
+            disconnect::DisconnectReason, CancelScript, Connected, Disconnect, Heartbeat, Msg,
+            Pause,
+        },
         ServerMessage,
     };
 
@@ -321,6 +1726,8 @@ mod message {
         ServerMessage {
             msg: Some(Msg::Connected(Connected {
                 executor_id: executor_id.into(),
+                protocol_version: proto::PROTOCOL_VERSION.to_owned(),
+                capabilities: super::CAPABILITIES.iter().map(|c| c.to_string()).collect(),
             })),
         }
     }
@@ -332,13 +1739,276 @@ mod message {
             })),
         }
     }
+
+    pub(super) fn heartbeat(interval_secs: u32) -> ServerMessage {
+        ServerMessage {
+            msg: Some(Msg::Heartbeat(Heartbeat { interval_secs })),
+        }
+    }
+
+    pub(super) fn pause(paused: bool) -> ServerMessage {
+        ServerMessage {
+            msg: Some(Msg::Pause(Pause { paused })),
+        }
+    }
+
+    pub(super) fn cancel(script_id: ScriptID, reason: &str) -> ServerMessage {
+        ServerMessage {
+            msg: Some(Msg::Cancel(CancelScript {
+                script_id: script_id.into(),
+                reason: reason.to_owned(),
+            })),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::api::device::{DeviceStatus, Twin, TwinProperty};
     use tracing::Level;
     use tracing_subscriber::{filter::Targets, prelude::*};
+
+    #[test]
+    fn test_grant_credits() {
+        assert_eq!(grant(0, 4, 4), 4);
+        assert_eq!(grant(3, 4, 2), 4);
+        assert_eq!(grant(0, 0, 1), 1);
+        assert_eq!(grant(u32::MAX, 0, 1), u32::MAX);
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let validate = |version: &str| {
+            let mut meta = MetadataMap::new();
+            meta.insert(RE_VERSION, version.parse().unwrap());
+            SessionManager::validate_metadata(&meta)
+        };
+        assert!(validate(proto::PROTOCOL_VERSION).is_ok());
+        // newer minor versions only add capabilities
+        assert!(validate("1.3.1").is_ok());
+        assert!(validate("2.0.0").is_err());
+        assert!(validate("0.2.0").is_err());
+        assert!(validate("latest").is_err());
+        assert!(SessionManager::validate_metadata(&MetadataMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_legacy_executor() {
+        // an executor from before protocol versioning, without capabilities
+        let mut meta = MetadataMap::new();
+        meta.insert(RE_VERSION, LEGACY_VERSION.parse().unwrap());
+        let protocol = SessionManager::validate_metadata(&meta).unwrap();
+        assert_eq!(protocol, Version::new(1, 0, 0));
+        // its connect message only carries max_job
+        let connect = ClientMessage {
+            code: ClientCode::Connect as i32,
+            info: Some(ClientInfo {
+                max_job: 0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let (info, credits) =
+            SessionManager::handle_first_message(Ok(Some(connect.clone())), &protocol).unwrap();
+        assert!(info.capabilities.is_empty());
+        assert!(info.script_types().any(|t| t == ScriptType::Js));
+        // each Continue grants one run
+        assert_eq!(grant(grant(0, info.max_job, credits), info.max_job, 1), 1);
+        // current executors have to announce their script types
+        assert!(SessionManager::handle_first_message(
+            Ok(Some(connect)),
+            &Version::parse(proto::PROTOCOL_VERSION).unwrap()
+        )
+        .is_err());
+        assert!(SessionManager::validate_metadata(&MetadataMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_executor_pause() {
+        assert_eq!(ExecutorState::Ready.pause(true), ExecutorState::Pause);
+        assert_eq!(ExecutorState::Pause.pause(true), ExecutorState::Pause);
+        assert_eq!(ExecutorState::Pause.pause(false), ExecutorState::Ready);
+        assert_eq!(ExecutorState::Ready.pause(false), ExecutorState::Ready);
+        assert_eq!(
+            ExecutorState::Draining.pause(false),
+            ExecutorState::Draining
+        );
+        assert_eq!(
+            ExecutorState::Disconnect.pause(true),
+            ExecutorState::Disconnect
+        );
+    }
+
+    #[test]
+    fn test_only_once_key() {
+        let mut request = proto::UpdateDevice {
+            qos: QosPolicy::OnlyOnce as i32,
+            ..Default::default()
+        };
+        // legacy executors send neither the capability nor a key
+        assert_eq!(only_once_key(&request, false), None);
+        assert_eq!(only_once_key(&request, true), None);
+        request.idempotency_key = "run-1/0".to_owned();
+        assert_eq!(only_once_key(&request, false), None);
+        assert_eq!(only_once_key(&request, true), Some("run-1/0"));
+    }
+
+    #[test]
+    fn test_capable() {
+        let executors = DashMap::new();
+        let (outbox, _) = flume::unbounded();
+        for (id, state) in [(1, ExecutorState::Pause), (2, ExecutorState::Draining)] {
+            executors.insert(
+                ExecutorID::from(id),
+                ExecutorInfo {
+                    addr: "127.0.0.1:8000".parse().unwrap(),
+                    script_types: vec![ScriptType::Js],
+                    protocol: Version::parse(proto::PROTOCOL_VERSION).unwrap(),
+                    capabilities: Vec::new(),
+                    max_job: 1,
+                    credits: 1,
+                    node: NodeInfo::default(),
+                    identity: Identity::Anonymous,
+                    state,
+                    last_heartbeat: Instant::now(),
+                    load: None,
+                    outbox: outbox.clone(),
+                },
+            );
+        }
+        // runs wait for none of them
+        assert!(!capable(&executors, ScriptType::Js));
+        executors.get_mut(&ExecutorID::from(1)).unwrap().state = ExecutorState::Init;
+        assert!(capable(&executors, ScriptType::Js));
+        assert!(!capable(&executors, ScriptType::Wasm));
+    }
+
+    #[test]
+    fn test_stranded() {
+        let node = |name: &str| NodeInfo {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        let mut msg = crate::queue::test::msg("a", ScriptType::Js);
+        msg.node_selectors = vec![serde_json::from_value(serde_json::json!({
+            "nodeSelectorTerms": [{
+                "matchExpressions": [{ "key": "", "operator": "In", "values": ["edge-1"] }]
+            }]
+        }))
+        .unwrap()];
+        let locality = LocalityConfig {
+            fallback: LocalityFallback::Never,
+            wait_secs: 5,
+        };
+        let remote = Candidates {
+            own: Some(node("edge-2")),
+            active: vec![Candidate {
+                node: node("edge-2"),
+                script_types: vec![ScriptType::Js],
+                credits: 1,
+            }],
+        };
+        let waited = Duration::from_secs(5);
+        assert!(!eligible(&remote, &locality, &msg, waited));
+        assert!(!stranded(&remote, &locality, &msg, Duration::from_secs(1)));
+        assert!(stranded(&remote, &locality, &msg, waited));
+        // a busy local executor keeps the run queued
+        let local = Candidates {
+            own: Some(node("edge-1")),
+            active: vec![Candidate {
+                node: node("edge-1"),
+                script_types: vec![ScriptType::Js],
+                credits: 0,
+            }],
+        };
+        assert!(eligible(&local, &locality, &msg, waited));
+        assert!(!stranded(&local, &locality, &msg, waited));
+        let wait = LocalityConfig {
+            fallback: LocalityFallback::Wait,
+            ..locality
+        };
+        assert!(!stranded(&remote, &wait, &msg, waited));
+    }
+
+    #[test]
+    fn test_run_output() {
+        assert_eq!(run_output(""), None);
+        assert_eq!(
+            run_output(r#"{"level":3}"#).as_deref(),
+            Some(r#"{"level":3}"#)
+        );
+        assert_eq!(run_output("{level"), None);
+        assert_eq!(run_output(&format!("\"{}\"", "a".repeat(MAX_OUTPUT))), None);
+    }
+
+    #[test]
+    fn test_heartbeat_expired() {
+        let config = HeartbeatConfig::default();
+        let last = Instant::now();
+        assert!(!config.expired(last, last + Duration::from_secs(30)));
+        assert!(config.expired(last, last + Duration::from_secs(31)));
+        let disabled = HeartbeatConfig {
+            interval_secs: 0,
+            ..Default::default()
+        };
+        assert!(!disabled.expired(last, last + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_take_runs() {
+        let scripts = DashMap::new();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        for (id, deadline) in [(1, Some(now)), (2, Some(later)), (3, None)] {
+            scripts.insert(
+                ScriptID::from(id),
+                ScriptStatus {
+                    name: format!("script-{}", id),
+                    namespace: "default".to_owned(),
+                    run_id: id.to_string(),
+                    executor: ExecutorID::default(),
+                    identity: Identity::Anonymous,
+                    deadline,
+                    retry: None,
+                },
+            );
+        }
+        let mut expired: Vec<u32> = take_runs(&scripts, |s| s.deadline.map_or(false, |d| d <= now))
+            .into_iter()
+            .map(|(id, _)| id.into())
+            .collect();
+        expired.sort_unstable();
+        assert_eq!(expired, vec![1]);
+        assert_eq!(scripts.len(), 2);
+    }
+
+    #[test]
+    fn test_renew() {
+        let scripts = DashMap::new();
+        let now = Instant::now();
+        for (id, executor) in [(1, 1), (2, 2)] {
+            scripts.insert(
+                ScriptID::from(id),
+                ScriptStatus {
+                    name: format!("script-{}", id),
+                    namespace: "default".to_owned(),
+                    run_id: id.to_string(),
+                    executor: ExecutorID::from(executor),
+                    identity: Identity::Anonymous,
+                    deadline: Some(now),
+                    retry: None,
+                },
+            );
+        }
+        let lease = LeaseConfig::default();
+        let later = now + Duration::from_secs(5);
+        renew(&scripts, ExecutorID::from(1), &lease, later);
+        let deadline = |id: u32| scripts.get(&ScriptID::from(id)).unwrap().deadline;
+        assert_eq!(deadline(1), Some(later + Duration::from_secs(600)));
+        assert_eq!(deadline(2), Some(now));
+    }
+
     #[tokio::test]
     async fn patch_device() {
         tracing_subscriber::registry()
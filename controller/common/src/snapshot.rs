@@ -0,0 +1,194 @@
+//! Reflector snapshot for warm restart
+//!
+//! The Reflector is periodically written to local disk and loaded at startup,
+//! so triggers and the debug api work before the watches finish listing.
+//! Loaded entries are stale until the watch confirms them.
+
+use crate::api::device::Twin;
+use crate::api::{Device, Script};
+use crate::scheduler::{Reflector, ResourceIndex};
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, trace};
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SnapshotConfig {
+    /// path of the snapshot file
+    pub path: PathBuf,
+    /// interval between two snapshots in seconds
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub devices: Vec<Device>,
+    pub scripts: Vec<Script>,
+    /// devices hold the values learned over MQTT, these are the twins the watch saw
+    #[serde(default)]
+    pub mqtt_reported: Vec<MqttReported>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MqttReported {
+    pub namespace: String,
+    pub name: String,
+    /// twin the watch saw by property, none if it had none
+    pub watched: HashMap<String, Option<Twin>>,
+}
+
+impl Reflector {
+    /// Take a compact snapshot, managed fields are dropped
+    pub fn snapshot(&self) -> Snapshot {
+        let devices = self
+            .device_store
+            .iter()
+            .map(|d| {
+                let mut d = d.value().clone();
+                d.metadata.managed_fields = None;
+                d
+            })
+            .collect();
+        let scripts = self
+            .script_store
+            .iter()
+            .map(|s| {
+                let mut s = s.value().clone();
+                s.metadata.managed_fields = None;
+                s
+            })
+            .collect();
+        let mqtt_reported = self
+            .mqtt_reported
+            .iter()
+            .map(|r| MqttReported {
+                namespace: r.key().namespace.clone(),
+                name: r.key().name.clone(),
+                watched: r.value().clone(),
+            })
+            .collect();
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            devices,
+            scripts,
+            mqtt_reported,
+        }
+    }
+
+    /// Load a snapshot, every loaded entry is marked as stale
+    pub fn load_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(eyre!("Unknown snapshot version {}", snapshot.version));
+        }
+        for d in &snapshot.devices {
+            self.add_device(d);
+            self.stale_devices.insert(ResourceIndex::from(d));
+        }
+        for s in &snapshot.scripts {
+            self.add_script(s);
+            self.stale_scripts.insert(ResourceIndex::from(s));
+        }
+        for r in snapshot.mqtt_reported {
+            let idx = ResourceIndex {
+                namespace: r.namespace,
+                name: r.name,
+                api: Default::default(),
+            };
+            self.mqtt_reported.insert(idx, r.watched);
+        }
+        info!(
+            devices = snapshot.devices.len(),
+            scripts = snapshot.scripts.len(),
+            "Reflector snapshot loaded"
+        );
+        Ok(())
+    }
+}
+
+pub fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let data = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Write a snapshot atomically
+pub async fn write_snapshot(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let data = serde_json::to_vec(snapshot)?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Periodically persist the reflector
+#[tracing::instrument(skip_all)]
+pub async fn persist_snapshot(store: Arc<Reflector>, config: SnapshotConfig) -> Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        let snapshot = store.snapshot();
+        match write_snapshot(&config.path, &snapshot).await {
+            Ok(_) => trace!(path =? config.path, "Reflector snapshot written"),
+            Err(e) => error!(error =? e, "Failed to write Reflector snapshot"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::index::test::script;
+    use crate::scheduler::test::device;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let store = Reflector::default();
+        let dht11 = device("dht11", &[("temperature", "20")]);
+        store.add_device(&dht11);
+        store.add_script(&script("a", &["dht11"], &["switch"]));
+        // values learned over MQTT are kept
+        let reported = HashMap::from([
+            ("temperature".to_owned(), "21".to_owned()),
+            ("humidity".to_owned(), "50".to_owned()),
+        ]);
+        assert_eq!(store.report_device(&(&dht11).into(), reported).len(), 2);
+        write_snapshot(&path, &store.snapshot()).await.unwrap();
+
+        let warm = Reflector::default();
+        let snapshot = read_snapshot(&path).unwrap();
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!(snapshot.devices[0].reported()["humidity"], "50");
+        assert_eq!(snapshot.mqtt_reported.len(), 1);
+        warm.load_snapshot(snapshot).unwrap();
+        assert_eq!(warm.device_store.len(), 1);
+        assert_eq!(*warm.index(), *store.index());
+        assert_eq!(warm.stale_devices.len(), 1);
+        assert_eq!(warm.stale_scripts.len(), 1);
+
+        // the watch confirms the entries, its older values are not a change
+        assert!(warm.restart_device(None, &[dht11.clone()]).is_empty());
+        let reported = |r: &Reflector| r.device_store.get(&(&dht11).into()).unwrap().reported();
+        assert_eq!(reported(&warm)["temperature"], "21");
+        // a new value from the watch replaces the one from MQTT
+        let triggers = warm.restart_device(None, &[device("dht11", &[("temperature", "22")])]);
+        assert_eq!(triggers.len(), 1);
+        assert_eq!(
+            triggers[0].changed,
+            ["temperature".to_owned()].into_iter().collect()
+        );
+        assert_eq!(reported(&warm)["temperature"], "22");
+        assert_eq!(reported(&warm)["humidity"], "50");
+        warm.restart_script(None, &[]);
+        assert!(warm.stale_devices.is_empty());
+        assert!(warm.stale_scripts.is_empty());
+        assert!(warm.script_store.is_empty());
+    }
+}
@@ -1,15 +1,18 @@
 use crate::{
     api::Device,
     api::Script,
-    scheduler::{Reflector, ResourceIndex},
+    cancel::{CancelTarget, Cancels},
+    quota::Quotas,
+    scheduler::{DeviceTrigger, Reflector, ResourceIndex},
 };
 use color_eyre::{eyre::eyre, Report, Result};
 use flume::{Receiver, Sender};
 use futures::StreamExt;
-use kube::{api::ListParams, Api, Resource};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{api::ListParams, Api, Client, Resource};
 use kube_runtime::watcher::{watcher, Event};
 use serde::de::DeserializeOwned;
-use std::{fmt::Debug, hash::Hash, marker::PhantomData, sync::Arc};
+use std::{fmt::Debug, hash::Hash, sync::Arc};
 use tracing::error;
 
 pub type AsyncHook<K> = Sender<Arc<Event<K>>>;
@@ -56,6 +59,25 @@ where
     }
 }
 
+/// Api of a single namespace, or of all namespaces if `namespace` is `None`
+pub fn scoped_api<K>(client: Client, namespace: Option<&str>) -> Api<K>
+where
+    K: Resource<DynamicType = ()>,
+{
+    match namespace {
+        Some(ns) => Api::namespaced(client, ns),
+        None => Api::all(client),
+    }
+}
+
+/// List parameters with an optional label selector
+pub fn list_params(label_selector: Option<&str>) -> ListParams {
+    match label_selector {
+        Some(selector) => ListParams::default().labels(selector),
+        None => ListParams::default(),
+    }
+}
+
 pub fn logger_hook<K>() -> SyncHook<K>
 where
     K: Resource + 'static + Clone + Debug + Send + Sync + DeserializeOwned,
@@ -77,61 +99,111 @@ where
     Box::new(logger)
 }
 
-#[tracing::instrument(skip_all)]
-pub async fn trigger_hook(
-    rx: Receiver<Arc<Event<Device>>>,
-    scheduler: Sender<ResourceIndex<Device>>,
-) -> Result<()> {
-    let dev_to_idx = |dev: &Device| ResourceIndex {
-        name: dev.meta().name.clone().unwrap(),
-        namespace: dev.meta().namespace.clone().unwrap(),
-        api: PhantomData,
-    };
-    loop {
-        let ev = rx.recv_async().await?;
-        match ev.as_ref() {
-            Event::Applied(dev) => {
-                let idx = dev_to_idx(dev);
-                tracing::trace!(dev = ?dev, "Got new device applied");
-                scheduler.send_async(idx).await?
-            }
-            Event::Restarted(devs) => {
-                for dev in devs {
-                    tracing::trace!(dev = ?dev, "Got new device restarted");
-                    scheduler.send_async(dev_to_idx(dev)).await?
-                }
-            }
-            Event::Deleted(_) => {}
+/// Keep namespace quotas in sync with their ConfigMaps.
+/// `namespace` is the scope of the watch, a re-list only replaces quotas in this scope.
+pub fn quota_hook(quotas: Arc<Quotas>, namespace: Option<String>) -> SyncHook<ConfigMap> {
+    let hook = move |ev: &Event<ConfigMap>| {
+        use kube::ResourceExt;
+        match ev {
+            Event::Applied(cm) => quotas.apply(cm),
+            Event::Restarted(cms) => quotas.restart(namespace.as_deref(), cms),
+            Event::Deleted(cm) => quotas.remove(&cm.namespace().unwrap_or_default()),
         }
-    }
+        Ok(())
+    };
+    Box::new(hook)
 }
 
+/// Keep devices in the reflector, and send a trigger to `scheduler` for every real change
+/// of reported twin values.
+/// Desired-only changes (e.g. our own `update_device_desired`) and devices first seen in a
+/// re-list don't trigger.
+/// `namespace` is the scope of the watch, a re-list only replaces devices in this scope.
 #[tracing::instrument(skip_all)]
 pub async fn device_hook(
     rx: Receiver<Arc<Event<Device>>>,
     reflector: Arc<Reflector>,
+    namespace: Option<String>,
+    scheduler: Option<Sender<DeviceTrigger>>,
 ) -> Result<()> {
     loop {
         let dev = rx.recv_async().await?;
-        match dev.as_ref() {
-            Event::Applied(dev) => reflector.add_device(dev),
-            Event::Restarted(devs) => reflector.restart_device(devs),
-            Event::Deleted(dev) => reflector.remove_device(dev),
+        let triggers = match dev.as_ref() {
+            Event::Applied(dev) => {
+                let changed = reflector
+                    .add_device(dev)
+                    .unwrap_or_else(|| dev.reported().into_keys().collect());
+                let idx = ResourceIndex::from(dev);
+                tracing::trace!(dev =? idx, changed =? changed, "Got new device applied");
+                if changed.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![DeviceTrigger {
+                        device: idx,
+                        changed,
+                    }]
+                }
+            }
+            Event::Restarted(devs) => reflector.restart_device(namespace.as_deref(), devs),
+            Event::Deleted(dev) => {
+                reflector.remove_device(dev);
+                Vec::new()
+            }
+        };
+        if let Some(scheduler) = &scheduler {
+            for t in triggers {
+                scheduler.send_async(t).await?
+            }
         }
     }
 }
 
+/// Keep scripts in the reflector, and cancel the runs of deleted scripts or scripts
+/// that were just suspended.
+/// `namespace` is the scope of the watch, a re-list only replaces scripts in this scope.
 #[tracing::instrument(skip_all)]
 pub async fn script_hook(
     rx: Receiver<Arc<Event<Script>>>,
     reflector: Arc<Reflector>,
+    namespace: Option<String>,
+    cancels: Cancels,
 ) -> Result<()> {
+    let suspend = |script: &Script| {
+        let target = CancelTarget::Script(script.into());
+        cancels.issue(target, "Script is suspended".to_owned());
+    };
     loop {
         let dev = rx.recv_async().await?;
         match dev.as_ref() {
-            Event::Applied(script) => reflector.add_script(script),
-            Event::Restarted(scripts) => reflector.restart_script(scripts),
-            Event::Deleted(script) => reflector.remove_script(script),
+            Event::Applied(script) => {
+                let suspended = newly_suspended(&reflector, script);
+                reflector.add_script(script);
+                if suspended {
+                    suspend(script);
+                }
+            }
+            Event::Restarted(scripts) => {
+                let suspended: Vec<&Script> = scripts
+                    .iter()
+                    .filter(|s| newly_suspended(&reflector, s))
+                    .collect();
+                reflector.restart_script(namespace.as_deref(), scripts);
+                suspended.into_iter().for_each(suspend);
+            }
+            Event::Deleted(script) => {
+                reflector.remove_script(script);
+                let target = CancelTarget::Script(script.into());
+                cancels.issue(target, "Script is deleted".to_owned());
+            }
         }
     }
 }
+
+/// Whether the script is suspended and wasn't before, its runs are cancelled only then
+fn newly_suspended(reflector: &Reflector, script: &Script) -> bool {
+    script.spec.suspend
+        && !reflector
+            .script_store
+            .get(&script.into())
+            .map_or(false, |known| known.spec.suspend)
+}
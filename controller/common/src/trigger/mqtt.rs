@@ -1,17 +1,13 @@
-use std::marker::PhantomData;
 use std::sync::Arc;
 
-use crate::api::{
-    mqtt::{DEVICE_ETPREFIX, TWIN_ETUPDATE_RESULT_SUFFIX},
-    Device,
-};
-use crate::scheduler::ResourceIndex;
+use crate::api::mqtt::{DeviceTwinUpdate, DEVICE_ETPREFIX, TWIN_ETUPDATE_RESULT_SUFFIX};
+use crate::scheduler::{DeviceTrigger, Reflector};
 use color_eyre::{eyre::eyre, Result};
 use flume::Sender;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
-use tracing::{error, info, log::trace};
+use tracing::{error, info, log::trace, warn};
 
 pub type AsyncHook = Sender<Arc<Publish>>;
 pub type SyncHook = Box<dyn FnMut(&Publish) -> Result<()> + Sync + Send + 'static>;
@@ -75,18 +71,36 @@ const DEVICE_UPDATE_RESULT_REGEX: Lazy<Regex> = Lazy::new(|| {
     .unwrap()
 });
 
-pub fn trigger_hook(scheduler: Sender<ResourceIndex<Device>>) -> SyncHook {
+/// Keep reported values from twin update results in the reflector, and trigger the
+/// readers of the device when one of them changed.
+/// The topic only carries the device name, so it is resolved across the watched namespaces
+/// and an update for a name used in several of them is ignored.
+pub fn trigger_hook(store: Arc<Reflector>, scheduler: Sender<DeviceTrigger>) -> SyncHook {
     let triger = move |msg: &Publish| {
         let name = match DEVICE_UPDATE_RESULT_REGEX.captures(&msg.topic) {
             Some(cap) => cap[1].to_owned(),
             None => return Ok::<_, color_eyre::Report>(()),
         };
+        let update: DeviceTwinUpdate = serde_json::from_slice(&msg.payload)?;
+        let mut devices = store.devices_named(&name);
+        let device = match devices.len() {
+            1 => devices.remove(0),
+            0 => {
+                trace!("Twin update result of unknown device {}", name);
+                return Ok(());
+            }
+            _ => {
+                warn!(devices =? devices, "Twin update result of an ambiguous device name, ignored");
+                return Ok(());
+            }
+        };
+        let changed = store.report_device(&device, update.reported_values());
+        if changed.is_empty() {
+            trace!("Twin update result of {:?} changed nothing", device);
+            return Ok(());
+        }
         scheduler
-            .send(ResourceIndex {
-                namespace: "default".to_owned(), // FIXME: where is namespace ???
-                name,
-                api: PhantomData,
-            })
+            .send(DeviceTrigger { device, changed })
             .map_err(|_| eyre!("Scheduler is down!"))
     };
     Box::new(triger)
@@ -101,8 +115,9 @@ pub fn logger_hook() -> SyncHook {
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::scheduler::test::device;
 
     const HOST: &str = "127.0.0.1";
     const PORT: u16 = 1883;
@@ -112,8 +127,10 @@ mod test {
         const DEVICE_NAME: &str = "test_name";
         const DEVICE_NAMESPACE: &str = "default";
 
+        let store = Arc::new(Reflector::default());
+        store.add_device(&device(DEVICE_NAME, &[("temperature", "20")]));
         let (tx, rx) = flume::bounded(3);
-        let sync_hooks = vec![trigger_hook(tx), logger_hook()];
+        let sync_hooks = vec![trigger_hook(store, tx), logger_hook()];
         let async_hooks = Vec::new();
         tokio::spawn(
             async move { mqtt_client(HOST.to_owned(), PORT, async_hooks, sync_hooks).await },
@@ -131,13 +148,57 @@ mod test {
                 format!("{DEVICE_ETPREFIX}{DEVICE_NAME}{TWIN_ETUPDATE_RESULT_SUFFIX}"),
                 QoS::AtMostOnce,
                 false,
-                "",
+                twin_update("temperature", "21"),
             )
             .await
             .unwrap();
 
-        let ri = rx.recv_async().await.unwrap();
-        assert_eq!(ri.name, DEVICE_NAME);
-        assert_eq!(ri.namespace, DEVICE_NAMESPACE);
+        let trigger = rx.recv_async().await.unwrap();
+        assert_eq!(trigger.device.name, DEVICE_NAME);
+        assert_eq!(trigger.device.namespace, DEVICE_NAMESPACE);
+        assert_eq!(trigger.changed, ["temperature".to_owned()].into());
+    }
+
+    #[test]
+    fn test_trigger_hook_namespace() {
+        let in_namespace = |name, namespace: &str| {
+            let mut d = device(name, &[("temperature", "20")]);
+            d.metadata.namespace = Some(namespace.to_owned());
+            d
+        };
+        let store = Arc::new(Reflector::default());
+        store.add_device(&in_namespace("a", "edge"));
+        store.add_device(&in_namespace("b", "edge"));
+        store.add_device(&in_namespace("b", "other"));
+        let (tx, rx) = flume::bounded(3);
+        let mut hook = trigger_hook(store, tx);
+        let update = |name: &str| {
+            Publish::new(
+                format!("{DEVICE_ETPREFIX}{name}{TWIN_ETUPDATE_RESULT_SUFFIX}"),
+                QoS::AtMostOnce,
+                twin_update("temperature", "21"),
+            )
+        };
+
+        // resolved in the only namespace having the device
+        hook(&update("a")).unwrap();
+        let trigger = rx.try_recv().unwrap();
+        assert_eq!(trigger.device.namespace, "edge");
+        assert_eq!(trigger.device.name, "a");
+
+        // ambiguous and unknown names trigger nothing
+        hook(&update("b")).unwrap();
+        hook(&update("c")).unwrap();
+        assert!(rx.is_empty());
+    }
+
+    /// Payload of a twin update result reporting one value
+    pub(crate) fn twin_update(property: &str, value: &str) -> String {
+        serde_json::json!({
+            "event_id": "",
+            "timestamp": 0,
+            "twin": { property: { "actual": { "value": value } } },
+        })
+        .to_string()
     }
 }
@@ -1,46 +1,83 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
     extract::{Extension, Query},
     http::StatusCode,
+    Json,
 };
 use flume::Sender;
+use tokio::sync::watch;
 
 use crate::api::Script;
-use crate::scheduler::ResourceIndex;
+use crate::scheduler::{ResourceIndex, ScriptTrigger, TriggerCause};
 
-#[tracing::instrument]
+/// how long a trigger may wait for the scheduler and the queue to take it
+const WAIT: Duration = Duration::from_secs(30);
+
+/// Trigger a Script and return the id of the run serving it, its output is read by the id.
+/// A standby replica answers 503 so the caller retries on the leader, a trigger which is
+/// throttled, suspended or dropped by the queue gets 422, and one not taken within WAIT
+/// (e.g. a full queue that blocks) gets 503.
+#[tracing::instrument(skip(leader))]
 pub async fn webhook(
     Query(arg): Query<ResourceIndex<Script>>,
-    Extension(state): Extension<Arc<Sender<ResourceIndex<Script>>>>,
-) -> StatusCode {
-    if state.send(arg).is_err() {
-        StatusCode::INTERNAL_SERVER_ERROR
-    } else {
-        StatusCode::OK
+    Extension(state): Extension<Arc<Sender<ScriptTrigger>>>,
+    Extension(leader): Extension<watch::Receiver<bool>>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if !*leader.borrow() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
     }
+    let run_id = wait_run(&state, arg, WAIT).await?;
+    Ok(Json(serde_json::json!({ "runId": run_id })))
+}
+
+async fn wait_run(
+    state: &Sender<ScriptTrigger>,
+    script: ResourceIndex<Script>,
+    wait: Duration,
+) -> Result<String, StatusCode> {
+    let (reply, run_id) = flume::bounded(1);
+    let trigger = ScriptTrigger {
+        script,
+        cause: TriggerCause::Webhook,
+        reply: Some(reply),
+    };
+    let run = async {
+        if state.send_async(trigger).await.is_err() {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        run_id
+            .recv_async()
+            .await
+            .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)
+    };
+    tokio::time::timeout(wait, run)
+        .await
+        .unwrap_or(Err(StatusCode::SERVICE_UNAVAILABLE))
 }
 
 #[cfg(test)]
 mod test {
-    use crate::api::Script;
-    use crate::scheduler::ResourceIndex;
-    use axum::{routing::get, Extension, Router};
-    use std::{net::SocketAddr, str::FromStr, sync::Arc};
-    use tokio::process::Command;
+    use crate::scheduler::{ResourceIndex, ScriptTrigger, TriggerCause};
+    use axum::{http::StatusCode, routing::get, Extension, Router};
+    use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+    use tokio::{process::Command, sync::watch};
 
     #[tokio::test]
     async fn test_webhook() {
         const DEVICE_NAME: &str = "test_name";
         const DEVICE_NAMESPACE: &str = "test_namespace";
 
-        let (tx, rx) = flume::bounded::<ResourceIndex<Script>>(3);
+        let (tx, rx) = flume::bounded::<ScriptTrigger>(3);
+        let (leader_tx, leader_rx) = watch::channel(false);
         tokio::spawn(async move {
             let endpoint = Arc::new(tx);
 
             let app = Router::new()
                 .route("/api/v1alpha/webhook", get(super::webhook))
-                .layer(Extension(endpoint));
+                .layer(Extension(endpoint))
+                .layer(Extension(leader_rx));
             let addr = SocketAddr::from_str("127.0.0.1:10080").unwrap();
             axum::Server::bind(&addr)
                 .serve(app.into_make_service())
@@ -48,15 +85,60 @@ mod test {
                 .unwrap()
         });
 
-        let status = Command::new("curl")
-            .arg(format!("http://127.0.0.1:10080/api/v1alpha/webhook?name={DEVICE_NAME}&namespace={DEVICE_NAMESPACE}"))
-            .spawn()
-            .unwrap()
-            .wait().await
-            .unwrap();
-        assert!(status.success());
-        let ri = rx.recv_async().await.unwrap();
-        assert_eq!(ri.name, DEVICE_NAME);
-        assert_eq!(ri.namespace, DEVICE_NAMESPACE);
+        let curl = || {
+            Command::new("curl")
+                .arg("--fail")
+                .arg(format!("http://127.0.0.1:10080/api/v1alpha/webhook?name={DEVICE_NAME}&namespace={DEVICE_NAMESPACE}"))
+                .output()
+        };
+        // a standby doesn't take the trigger
+        assert!(!curl().await.unwrap().status.success());
+        assert!(rx.is_empty());
+
+        leader_tx.send_replace(true);
+        let response = tokio::spawn(curl());
+        let trigger = rx.recv_async().await.unwrap();
+        assert_eq!(trigger.script.name, DEVICE_NAME);
+        assert_eq!(trigger.script.namespace, DEVICE_NAMESPACE);
+        assert_eq!(trigger.cause, TriggerCause::Webhook);
+        trigger.reply.unwrap().send("1-1".to_owned()).unwrap();
+        let response = response.await.unwrap().unwrap();
+        assert!(response.status.success());
+        assert_eq!(response.stdout, br#"{"runId":"1-1"}"#);
+
+        // a rejected trigger
+        let response = tokio::spawn(curl());
+        drop(rx.recv_async().await.unwrap());
+        assert!(!response.await.unwrap().unwrap().status.success());
+    }
+
+    #[tokio::test]
+    async fn test_wait_run_timeout() {
+        let (tx, rx) = flume::bounded::<ScriptTrigger>(1);
+        let script = || ResourceIndex {
+            namespace: "test_namespace".to_owned(),
+            name: "test_name".to_owned(),
+            api: Default::default(),
+        };
+        let wait = Duration::from_millis(50);
+
+        // the trigger is taken but no run is queued in time
+        let waiting = tokio::spawn(async move {
+            let result = super::wait_run(&tx, script(), wait).await;
+            (tx, result)
+        });
+        let _held = rx.recv_async().await.unwrap();
+        let (tx, result) = waiting.await.unwrap();
+        assert_eq!(result, Err(StatusCode::SERVICE_UNAVAILABLE));
+
+        // the scheduler doesn't take the trigger
+        tx.send(ScriptTrigger {
+            script: script(),
+            cause: TriggerCause::Webhook,
+            reply: None,
+        })
+        .unwrap();
+        let result = super::wait_run(&tx, script(), wait).await;
+        assert_eq!(result, Err(StatusCode::SERVICE_UNAVAILABLE));
     }
 }
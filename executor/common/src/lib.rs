@@ -5,39 +5,377 @@ use flume::{Receiver, Sender};
 use futures::StreamExt;
 use proto::server_message::Msg;
 use proto::{
-    client_message::{ClientCode, ClientInfo},
+    capability,
+    client_message::{ClientCode, ClientInfo, Load},
     controller_service_client::ControllerServiceClient,
-    server_message::RunScript,
-    ClientMessage, ServerMessage,
+    server_message::{CancelScript, Connected, Pause, RunScript},
+    BearerToken, ClientMessage, ControllerClient, ServerMessage, PROTOCOL_VERSION,
 };
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::task::JoinHandle;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
 use tonic::Streaming;
-use tonic::{metadata::MetadataValue, transport::channel::Channel, Request, Status};
-use tracing::{error, info};
+use tonic::{metadata::MetadataValue, Request, Status};
+use tracing::{error, info, warn};
 
 const RE_VERSION: &str = "re-version";
-const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// heartbeats of the controller missed before reconnecting
+const HEARTBEAT_MISSES: u32 = 3;
+
+/// How the executor authenticates to the controller
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuth {
+    /// PEM CA bundle of the controller certificate, connect over TLS if set
+    pub ca: Option<PathBuf>,
+    /// PEM client certificate and key for mutual TLS
+    pub identity: Option<(PathBuf, PathBuf)>,
+    /// name in the controller certificate, the host of the url if not set
+    pub domain: Option<String>,
+    /// file of the bearer token sent on every request
+    pub token_file: Option<PathBuf>,
+}
+
+impl ClientAuth {
+    async fn tls_config(&self) -> Result<Option<ClientTlsConfig>> {
+        let ca = match &self.ca {
+            Some(ca) => tokio::fs::read(ca)
+                .await
+                .wrap_err_with(|| format!("Failed to read CA {}", ca.display()))?,
+            None => return Ok(None),
+        };
+        let mut tls = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca));
+        if let Some((cert, key)) = &self.identity {
+            let cert = tokio::fs::read(cert)
+                .await
+                .wrap_err_with(|| format!("Failed to read certificate {}", cert.display()))?;
+            let key = tokio::fs::read(key)
+                .await
+                .wrap_err_with(|| format!("Failed to read key {}", key.display()))?;
+            tls = tls.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(domain) = &self.domain {
+            tls = tls.domain_name(domain);
+        }
+        Ok(Some(tls))
+    }
+
+    /// Connect a client to the controller at `url`
+    pub async fn connect(&self, url: String) -> Result<ControllerClient> {
+        let mut endpoint = Endpoint::from_shared(url)?;
+        if let Some(tls) = self.tls_config().await? {
+            endpoint = endpoint.tls_config(tls)?;
+        }
+        let channel = endpoint.connect().await?;
+        Ok(ControllerServiceClient::with_interceptor(
+            channel,
+            BearerToken::new(self.token_file.clone()),
+        ))
+    }
+}
 
 pub struct Client {
-    pub client: ControllerServiceClient<Channel>,
+    pub client: ControllerClient,
     pub tasks: Vec<JoinHandle<()>>,
     pub id: u32,
     pub rx: Receiver<RunScript>,
+    /// runs the controller asked to stop
+    pub cancels: Receiver<CancelScript>,
+    pub credits: Credits,
+    /// optional features of the controller
+    pub server: ServerCapabilities,
+    pub drain: Drain,
+}
+
+/// Capabilities of the connected controller, replaced on every (re)connect
+/// since a rolling upgrade may move the executor to another version
+#[derive(Debug, Clone, Default)]
+pub struct ServerCapabilities {
+    capabilities: Arc<RwLock<HashSet<String>>>,
+}
+
+impl ServerCapabilities {
+    pub fn has(&self, capability: &str) -> bool {
+        self.read().contains(capability)
+    }
+
+    /// Capabilities of the current connection
+    pub fn snapshot(&self) -> HashSet<String> {
+        self.read().clone()
+    }
+
+    fn set(&self, connected: &Connected) {
+        info!(protocol = %connected.protocol_version, capabilities =? connected.capabilities, "Controller capabilities");
+        *self
+            .capabilities
+            .write()
+            .expect("ServerCapabilities poisoned") =
+            connected.capabilities.iter().cloned().collect();
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, HashSet<String>> {
+        self.capabilities
+            .read()
+            .expect("ServerCapabilities poisoned")
+    }
+}
+
+/// Draining of the executor before it exits, the controller stops dispatching runs to it.
+/// The drain is sent again on every reconnect.
+#[derive(Debug, Clone)]
+pub struct Drain {
+    draining: Arc<AtomicBool>,
+    /// the controller answered the drain of the current connection
+    confirmed: Arc<AtomicBool>,
+    /// the last pause of the controller, announced again on reconnect
+    paused: Arc<AtomicBool>,
+    tx: Sender<()>,
+    rx: Receiver<()>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        let (tx, rx) = flume::unbounded();
+        Drain {
+            draining: Default::default(),
+            confirmed: Default::default(),
+            paused: Default::default(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl Drain {
+    /// Ask the controller to stop dispatching runs
+    pub fn start(&self) {
+        if !self.draining.swap(true, Ordering::AcqRel) {
+            let _ = self.tx.send(());
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Whether no more runs will be received
+    pub fn confirmed(&self) -> bool {
+        self.confirmed.load(Ordering::Acquire)
+    }
+
+    /// Send the drain on the current connection
+    fn send(&self, control: &Sender<ClientCode>, server: &ServerCapabilities, credits: &Credits) {
+        if server.has(capability::DRAIN) {
+            info!("Drain");
+            self.confirmed.store(false, Ordering::Release);
+            let _ = control.send(ClientCode::Drain);
+        } else {
+            // the controller may still use the credits it holds, no more are returned
+            warn!(
+                held = credits.held(),
+                "Controller can't drain executors, wait for the runs it may still dispatch"
+            );
+            credits.hold();
+            self.received(credits);
+        }
+    }
+
+    /// Confirm a drain the controller can't answer once it can't dispatch any more runs
+    fn received(&self, credits: &Credits) {
+        if credits.is_held() && credits.held() == 0 {
+            self.confirmed.store(true, Ordering::Release);
+        }
+    }
+
+    fn paused(&self, pause: &Pause) {
+        info!(paused = pause.paused, "Pause");
+        self.paused.store(pause.paused, Ordering::Release);
+        if pause.paused && self.is_draining() {
+            self.confirmed.store(true, Ordering::Release);
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+}
+
+/// Job slots of the executor.
+/// A slot taken by a run is returned to the controller as a credit when the run finishes.
+#[derive(Debug, Clone)]
+pub struct Credits {
+    max_job: u32,
+    running: Arc<AtomicU32>,
+    /// credits the controller holds, runs it can dispatch without hearing from us
+    held: Arc<AtomicU32>,
+    /// finished runs are not returned, set while draining against a controller without drain
+    holding: Arc<AtomicBool>,
+    tx: Sender<()>,
+    rx: Receiver<()>,
+}
+
+impl Credits {
+    pub fn new(max_job: u32) -> Self {
+        let (tx, rx) = flume::unbounded();
+        Credits {
+            max_job,
+            running: Default::default(),
+            held: Default::default(),
+            holding: Default::default(),
+            tx,
+            rx,
+        }
+    }
+
+    /// Free slots announced on (re)connect, returns not sent yet are included
+    fn free(&self) -> u32 {
+        self.rx.drain();
+        let free = if self.is_held() {
+            0
+        } else {
+            self.max_job
+                .saturating_sub(self.running.load(Ordering::Acquire))
+        };
+        self.held.store(free, Ordering::Release);
+        free
+    }
+
+    fn acquire(&self) {
+        self.running.fetch_add(1, Ordering::AcqRel);
+        let _ = self
+            .held
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |h| h.checked_sub(1));
+    }
+
+    /// A credit was sent to the controller
+    fn returned(&self) {
+        let max_job = self.max_job;
+        let _ = self
+            .held
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |h| {
+                Some((h + 1).min(max_job))
+            });
+    }
+
+    fn held(&self) -> u32 {
+        self.held.load(Ordering::Acquire)
+    }
+
+    /// Stop returning credits
+    fn hold(&self) {
+        self.holding.store(true, Ordering::Release);
+    }
+
+    fn is_held(&self) -> bool {
+        self.holding.load(Ordering::Acquire)
+    }
+
+    /// Runs received and not finished yet
+    pub fn running(&self) -> u32 {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Return the slot of a finished run
+    pub fn release(&self) {
+        self.running.fetch_sub(1, Ordering::AcqRel);
+        if !self.is_held() {
+            let _ = self.tx.send(());
+        }
+    }
+
+    /// Load reported with heartbeats
+    fn load(&self) -> Load {
+        let load_average = std::fs::read_to_string("/proc/loadavg")
+            .ok()
+            .and_then(|s| s.split_whitespace().next()?.parse().ok())
+            .unwrap_or_default();
+        let memory_available = std::fs::read_to_string("/proc/meminfo")
+            .ok()
+            .and_then(|s| {
+                let kb = s.lines().find_map(|l| l.strip_prefix("MemAvailable:"))?;
+                kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok()
+            })
+            .map(|kb| kb * 1024)
+            .unwrap_or_default();
+        Load {
+            running: self.running.load(Ordering::Acquire),
+            load_average,
+            memory_available,
+        }
+    }
 }
 
 impl Client {
-    pub async fn try_connect(url: String) -> Result<Client> {
+    /// Connect to the controller, `info` advertises what this executor can run
+    pub async fn try_connect(
+        url: String,
+        mut info: ClientInfo,
+        auth: &ClientAuth,
+    ) -> Result<Client> {
+        if info.max_job == 0 {
+            return Err(eyre!("Executor must have at least one job slot"));
+        }
+        // heartbeats and pauses are handled here for every executor
+        for handled in [capability::HEARTBEAT, capability::PAUSE] {
+            if !info.capabilities.iter().any(|c| c == handled) {
+                info.capabilities.push(handled.to_owned());
+            }
+        }
         info!("Connecting to server {}", url);
-        let client = ControllerServiceClient::connect(url).await?;
+        let client = auth.connect(url).await?;
         let main_client = client.clone();
         let mut tasks = Vec::new();
-        let (tx, rx) = flume::bounded(10);
-        let (id, stream) = connect(main_client).await?;
+        let (tx, rx) = flume::bounded(info.max_job.max(1) as usize);
+        let (cancel_tx, cancels) = flume::unbounded();
+        let credits = Credits::new(info.max_job);
+        let server = ServerCapabilities::default();
+        let drain = Drain::default();
+        let (connected, stream, control) =
+            connect(main_client.clone(), info.clone(), credits.clone()).await?;
         info!("Connected!");
+        server.set(&connected);
+        let id = connected.executor_id;
+        let task_credits = credits.clone();
+        let task_server = server.clone();
+        let task_drain = drain.clone();
         let handle = tokio::spawn(async move {
-            if let Err(e) = run(stream, tx).await {
-                error!(error =? e, "Connection to controller get a error");
+            let credits = task_credits;
+            let mut stream = stream;
+            let mut control = control;
+            loop {
+                let conn = Connection {
+                    control,
+                    server: &task_server,
+                    drain: &task_drain,
+                };
+                if let Err(e) = run(stream, tx.clone(), &cancel_tx, &credits, conn).await {
+                    error!(error =? e, "Connection to controller get a error");
+                }
+                if tx.is_disconnected() {
+                    break;
+                }
+                // a pause outlives the connection, even if another leader takes us
+                let info = ClientInfo {
+                    paused: task_drain.is_paused(),
+                    ..info.clone()
+                };
+                // the controller Service routes us to the current leader
+                (stream, control) = loop {
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    match connect(main_client.clone(), info.clone(), credits.clone()).await {
+                        Ok((connected, stream, control)) => {
+                            info!(id = connected.executor_id, "Reconnected!");
+                            task_server.set(&connected);
+                            break (stream, control);
+                        }
+                        Err(e) => error!(error =? e, "Failed to reconnect to controller"),
+                    }
+                };
             }
         });
         tasks.push(handle);
@@ -46,31 +384,70 @@ impl Client {
             tasks,
             id,
             rx,
+            cancels,
+            credits,
+            server,
+            drain,
         })
     }
 }
 
 async fn connect(
-    mut client: ControllerServiceClient<Channel>,
-) -> Result<(u32, Streaming<ServerMessage>)> {
+    mut client: ControllerClient,
+    info: ClientInfo,
+    credits: Credits,
+) -> Result<(Connected, Streaming<ServerMessage>, Sender<ClientCode>)> {
+    // `run` asks for a heartbeat answer on every server heartbeat, and for drains
+    let (control, control_rx) = flume::unbounded();
     let client_stream = stream! {
         yield ClientMessage {
             code: ClientCode::Connect as i32,
-            info: Some(ClientInfo {
-                max_job: 0
-            })
+            info: Some(info),
+            credits: credits.free(),
+            load: None,
         };
         loop {
-            yield ClientMessage {
-                code: ClientCode::Continue as i32,
-                info: None
+            tokio::select! {
+                // one credit per finished run
+                returned = credits.rx.recv_async() => match returned {
+                    Ok(()) if !credits.is_held() => {
+                        credits.returned();
+                        yield ClientMessage {
+                            code: ClientCode::Continue as i32,
+                            info: None,
+                            credits: 1,
+                            load: None,
+                        }
+                    }
+                    Ok(()) => {}
+                    Err(_) => break,
+                },
+                asked = control_rx.recv_async() => match asked {
+                    Ok(ClientCode::Heartbeat) => {
+                        yield ClientMessage {
+                            code: ClientCode::Heartbeat as i32,
+                            info: None,
+                            credits: 0,
+                            load: Some(credits.load()),
+                        }
+                    }
+                    Ok(code) => {
+                        yield ClientMessage {
+                            code: code as i32,
+                            info: None,
+                            credits: 0,
+                            load: None,
+                        }
+                    }
+                    Err(_) => break,
+                },
             }
         }
     };
     let mut request = Request::new(client_stream);
     request
         .metadata_mut()
-        .insert(RE_VERSION, MetadataValue::from_static(CLIENT_VERSION));
+        .insert(RE_VERSION, MetadataValue::from_static(PROTOCOL_VERSION));
 
     let mut stream = client
         .run(request)
@@ -78,11 +455,11 @@ async fn connect(
         .wrap_err("Got error from server")?
         .into_inner();
     let msg = stream.next().await;
-    let executor_id = handle_first_message(msg)?;
-    Ok((executor_id, stream))
+    let connected = handle_first_message(msg)?;
+    Ok((connected, stream, control))
 }
 
-fn handle_first_message(msg: Option<StdResult<ServerMessage, Status>>) -> Result<u32> {
+fn handle_first_message(msg: Option<StdResult<ServerMessage, Status>>) -> Result<Connected> {
     match msg {
         None => Err(eyre!("Got None on first message")),
         Some(msg) => {
@@ -91,17 +468,59 @@ fn handle_first_message(msg: Option<StdResult<ServerMessage, Status>>) -> Result
                 .msg
                 .ok_or_else(|| eyre!("Got None on ServerMessage"))?;
             match msg {
-                Msg::Connected(c) => Ok(c.executor_id),
+                Msg::Connected(c) => Ok(c),
                 Msg::Disconnect(d) => Err(eyre!("Got Disconnect on first message: {:?}", d)),
                 Msg::Script(s) => Err(eyre!("Got Script on first message: {:?}", s)),
+                Msg::Heartbeat(_) => Err(eyre!("Got Heartbeat on first message")),
+                Msg::Cancel(c) => Err(eyre!("Got Cancel on first message: {:?}", c)),
+                Msg::Pause(p) => Err(eyre!("Got Pause on first message: {:?}", p)),
             }
         }
     }
 }
 
-async fn run(mut stream: Streaming<ServerMessage>, tx: Sender<RunScript>) -> Result<()> {
+/// State of one connection to the controller
+struct Connection<'a> {
+    /// messages to send besides credits
+    control: Sender<ClientCode>,
+    server: &'a ServerCapabilities,
+    drain: &'a Drain,
+}
+
+async fn run(
+    mut stream: Streaming<ServerMessage>,
+    tx: Sender<RunScript>,
+    cancels: &Sender<CancelScript>,
+    credits: &Credits,
+    conn: Connection<'_>,
+) -> Result<()> {
+    let Connection {
+        control,
+        server,
+        drain,
+    } = conn;
+    if drain.is_draining() {
+        drain.send(&control, server, credits);
+    }
+    // the controller is considered gone after missing this long since its last heartbeat
+    let mut silence: Option<Duration> = None;
     loop {
-        match stream.next().await {
+        let received = async {
+            match silence {
+                Some(silence) => tokio::time::timeout(silence, stream.next())
+                    .await
+                    .map_err(|_| eyre!("Controller missed heartbeats")),
+                None => Ok(stream.next().await),
+            }
+        };
+        let next = tokio::select! {
+            next = received => next?,
+            Ok(()) = drain.rx.recv_async() => {
+                drain.send(&control, server, credits);
+                continue;
+            }
+        };
+        match next {
             Some(msg) => {
                 let msg = msg
                     .wrap_err("Got error when receive message")?
@@ -114,8 +533,21 @@ async fn run(mut stream: Streaming<ServerMessage>, tx: Sender<RunScript>) -> Res
                         break Ok(());
                     }
                     Msg::Script(r) => {
+                        credits.acquire();
+                        drain.received(credits);
                         tx.send_async(r).await?;
                     }
+                    Msg::Heartbeat(h) => {
+                        silence = (h.interval_secs > 0).then(|| {
+                            Duration::from_secs(h.interval_secs as u64) * HEARTBEAT_MISSES
+                        });
+                        control.send(ClientCode::Heartbeat)?;
+                    }
+                    Msg::Cancel(c) => {
+                        info!(script_id = c.script_id, reason = %c.reason, "Cancel");
+                        cancels.send(c)?;
+                    }
+                    Msg::Pause(p) => drain.paused(&p),
                 }
             }
             None => return Err(eyre!("Unexpect disconnect")),
@@ -1,16 +1,24 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, future::Future, rc::Rc, time::Duration};
 
 use deno_core::{
     error::AnyError,
     error::{generic_error, range_error, resource_unavailable},
     include_js_files, op, Extension, OpState,
 };
-use proto::{controller_service_client::ControllerServiceClient, QosPolicy, UpdateDevice};
-use serde::Deserialize;
-use tonic::transport::Channel;
-use tracing::debug;
+use proto::{
+    capability, server_message::run_script::trigger::Source, update_device_result::Outcome,
+    update_devices::DeviceDesired, ControllerClient, GetDeviceStatus, QosPolicy, UpdateDevice,
+    UpdateDeviceResult, UpdateDevices,
+};
+use serde::{Deserialize, Serialize};
+use tonic::Code;
+use tracing::{debug, warn};
+
+use crate::{Capabilities, ReadableDevices, Rule, Triggers, WritableDevices};
 
-use crate::{ReadableDevices, Rule, WritableDevices};
+/// times a failed AtLeastOnce or OnlyOnce commit is sent again
+const COMMIT_RETRIES: u32 = 3;
+const COMMIT_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 pub fn init() -> Extension {
     Extension::builder()
@@ -22,8 +30,11 @@ pub fn init() -> Extension {
             op_list_readable_devices::decl(),
             op_list_writable_devices::decl(),
             op_get_device_status::decl(),
+            op_refresh_devices::decl(),
             op_update_device_desired::decl(),
             op_commit_device::decl(),
+            op_commit_all::decl(),
+            op_list_triggers::decl(),
         ])
         .build()
 }
@@ -67,6 +78,74 @@ pub fn op_get_device_status(
     Ok(value)
 }
 
+/// Replace the snapshot of readable devices with their current status,
+/// all readable devices if `names` is empty
+#[op]
+pub async fn op_refresh_devices(
+    state: Rc<RefCell<OpState>>,
+    names: Vec<String>,
+    _: (),
+) -> Result<(), AnyError> {
+    let (mut client, request) = {
+        let op_state = state.try_borrow().map_err(|_| resource_unavailable())?;
+        let capabilities: &Capabilities = op_state.borrow();
+        if !capabilities.has(capability::DEVICE_STATUS) {
+            return Err(generic_error("Controller can't read device status"));
+        }
+        let rule: &Rc<Rule> = op_state.borrow();
+        let client: &ControllerClient = op_state.borrow();
+        let request = GetDeviceStatus {
+            script_id: rule.script_id,
+            devices: names,
+        };
+        (client.clone(), request)
+    };
+    let fresh = client.get_device_status(request).await?.into_inner();
+    debug!(devices =? fresh.devices, "refresh devices");
+    let mut op_state = state.try_borrow_mut().map_err(|_| resource_unavailable())?;
+    let readable: &mut ReadableDevices = op_state.borrow_mut();
+    readable.devices.extend(fresh.devices);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "source", rename_all = "camelCase")]
+pub enum TriggerInfo {
+    Device {
+        /// name of the device in the script
+        device: String,
+        changed: Vec<String>,
+    },
+    Webhook,
+}
+
+#[op]
+pub fn op_list_triggers(state: &mut OpState, _: (), _: ()) -> Result<Vec<TriggerInfo>, AnyError> {
+    let readable: &ReadableDevices = state.borrow();
+    let triggers: &Triggers = state.borrow();
+    let list = triggers
+        .triggers
+        .iter()
+        .map(|t| match t.source() {
+            Source::Device => {
+                // triggers name the device resource, scripts know it by its alias
+                let device = readable
+                    .devices
+                    .iter()
+                    .find(|(_, d)| d.name == t.device)
+                    .map(|(alias, _)| alias.clone())
+                    .unwrap_or_else(|| t.device.clone());
+                TriggerInfo::Device {
+                    device,
+                    changed: t.changed.clone(),
+                }
+            }
+            Source::Webhook => TriggerInfo::Webhook,
+        })
+        .collect();
+    Ok(list)
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UpdateDeviceDesired {
     name: String,
@@ -92,43 +171,231 @@ pub fn op_update_device_desired(
     r
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitResult {
+    /// "sent", "confirmed" or "timeout"
+    outcome: String,
+    /// properties the device didn't report when the commit timed out
+    pending: Vec<String>,
+}
+
+impl From<UpdateDeviceResult> for CommitResult {
+    fn from(result: UpdateDeviceResult) -> Self {
+        let outcome = match result.outcome() {
+            Outcome::Sent => "sent",
+            Outcome::Confirmed => "confirmed",
+            Outcome::Timeout => "timeout",
+        };
+        CommitResult {
+            outcome: outcome.to_owned(),
+            pending: result.pending,
+        }
+    }
+}
+
+/// Qos of a commit, the default of the Script if not given
+fn commit_qos(state: &OpState, qos: Option<i32>) -> Result<QosPolicy, AnyError> {
+    let rule: &Rc<Rule> = state.borrow();
+    let qos = match qos {
+        Some(qos) => QosPolicy::from_i32(qos).ok_or_else(|| range_error("Invalid Qos value"))?,
+        None => rule.qos,
+    };
+    let capabilities: &Capabilities = state.borrow();
+    if qos != QosPolicy::AtMostOnce && !capabilities.has(capability::DEVICE_QOS) {
+        warn!(qos =? qos, "Controller can't confirm device writes, commit AtMostOnce");
+        return Ok(QosPolicy::AtMostOnce);
+    }
+    Ok(qos)
+}
+
+/// Retries of a commit carry the same key, the controller applies it once
+fn idempotency_key(rule: &Rule, qos: QosPolicy, seq: u64) -> String {
+    if qos == QosPolicy::OnlyOnce {
+        format!("{}-{}", rule.run_id, seq)
+    } else {
+        String::new()
+    }
+}
+
+/// Failures which may not have reached the controller, or may pass on a retry.
+/// An Internal failure may have left a batch partly written, it isn't sent again.
+fn retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::DeadlineExceeded
+    )
+}
+
+/// Failures of a batch the controller rejected or rolled back before returning, so no
+/// device was written. Anything else may have written some of them.
+fn not_written(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::InvalidArgument
+            | Code::NotFound
+            | Code::PermissionDenied
+            | Code::FailedPrecondition
+            | Code::Aborted
+    )
+}
+
+/// Send a commit, an AtLeastOnce or OnlyOnce commit is sent again on retryable failures
+async fn send_commit<T, F, Fut>(qos: QosPolicy, mut send: F) -> Result<T, tonic::Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+{
+    let retries = if qos == QosPolicy::AtMostOnce {
+        0
+    } else {
+        COMMIT_RETRIES
+    };
+    let mut attempt = 0;
+    loop {
+        match send().await {
+            Ok(result) => return Ok(result.into_inner()),
+            Err(e) if attempt < retries && retryable(&e) => {
+                attempt += 1;
+                warn!(error =? e, attempt, "Commit failed, retry");
+                tokio::time::sleep(COMMIT_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[op]
 pub async fn op_commit_device(
     state: Rc<RefCell<OpState>>,
     name: String,
     qos: Option<i32>,
-) -> Result<(), AnyError> {
-    let (commits, resource_name) = {
+) -> Result<CommitResult, AnyError> {
+    let (commits, resource_name, seq) = {
         let mut op_state = state.try_borrow_mut().map_err(|_| resource_unavailable())?;
         let writable: &mut WritableDevices = op_state.borrow_mut();
+        writable.commit_seq += 1;
+        let seq = writable.commit_seq;
         if let Some(d) = writable.devices.get_mut(&name) {
-            (std::mem::take(&mut d.commits), d.name.clone())
+            (std::mem::take(&mut d.commits), d.name.clone(), seq)
         } else {
             return Err(generic_error("Device not found"));
         }
     };
     debug!(commits =? commits, name =? name, qos =? qos, "commit device");
-    let (mut client, request) = {
+    let (client, request) = {
         let op_state = state.try_borrow().map_err(|_| resource_unavailable())?;
         let rule: &Rc<Rule> = op_state.borrow();
-        let qos = match qos {
-            Some(qos) => {
-                QosPolicy::from_i32(qos).ok_or_else(|| range_error("Invalid Qos value"))? as i32
-            }
-            None => rule.qos as i32,
-        };
+        let qos = commit_qos(&op_state, qos)?;
         let request = UpdateDevice {
             script_id: rule.script_id,
             name: resource_name,
             desired: commits,
-            qos,
+            qos: qos as i32,
+            idempotency_key: idempotency_key(rule, qos, seq),
         };
-        let client: &ControllerServiceClient<Channel> = op_state.borrow();
+        let client: &ControllerClient = op_state.borrow();
         (client.clone(), request)
     };
     debug!(request =? request, "commit device requset");
-    // FIXME: Qos handle
-    client.update_device_desired(request).await?;
-    debug!("requset finished");
-    Ok(())
+    let result = send_commit(request.qos(), || {
+        let mut client = client.clone();
+        let request = request.clone();
+        async move { client.update_device_desired(request).await }
+    })
+    .await?;
+    debug!(result =? result, "requset finished");
+    Ok(result.into())
+}
+
+/// Commit the changes of every writable device together, the controller writes all of
+/// them or none. Results are keyed by device name in the script.
+#[op]
+pub async fn op_commit_all(
+    state: Rc<RefCell<OpState>>,
+    qos: Option<i32>,
+    _: (),
+) -> Result<HashMap<String, CommitResult>, AnyError> {
+    // (name in the script, resource name, changes)
+    let (taken, seq) = {
+        let mut op_state = state.try_borrow_mut().map_err(|_| resource_unavailable())?;
+        let capabilities: &Capabilities = op_state.borrow();
+        if !capabilities.has(capability::DEVICE_BATCH) {
+            return Err(generic_error("Controller can't commit devices together"));
+        }
+        let writable: &mut WritableDevices = op_state.borrow_mut();
+        writable.commit_seq += 1;
+        let taken: Vec<(String, String, HashMap<String, String>)> = writable
+            .devices
+            .iter_mut()
+            .filter(|(_, d)| !d.commits.is_empty())
+            .map(|(alias, d)| {
+                (
+                    alias.clone(),
+                    d.name.clone(),
+                    std::mem::take(&mut d.commits),
+                )
+            })
+            .collect();
+        (taken, writable.commit_seq)
+    };
+    if taken.is_empty() {
+        return Ok(HashMap::new());
+    }
+    debug!(commits =? taken, qos =? qos, "commit all devices");
+    let (client, request) = {
+        let op_state = state.try_borrow().map_err(|_| resource_unavailable())?;
+        let rule: &Rc<Rule> = op_state.borrow();
+        let qos = commit_qos(&op_state, qos)?;
+        let devices = taken
+            .iter()
+            .map(|(_, name, desired)| DeviceDesired {
+                name: name.clone(),
+                desired: desired.clone(),
+            })
+            .collect();
+        let request = UpdateDevices {
+            script_id: rule.script_id,
+            devices,
+            qos: qos as i32,
+            idempotency_key: idempotency_key(rule, qos, seq),
+        };
+        let client: &ControllerClient = op_state.borrow();
+        (client.clone(), request)
+    };
+    let result = send_commit(request.qos(), || {
+        let mut client = client.clone();
+        let request = request.clone();
+        async move { client.update_devices_desired(request).await }
+    })
+    .await;
+    let mut result = match result {
+        Ok(result) => result,
+        Err(e) if !not_written(&e) => {
+            warn!(error =? e, "Batch commit failed, devices may be partly written");
+            return Err(e.into());
+        }
+        Err(e) => {
+            // nothing was written, keep the changes so they can be committed again
+            let mut op_state = state.try_borrow_mut().map_err(|_| resource_unavailable())?;
+            let writable: &mut WritableDevices = op_state.borrow_mut();
+            for (alias, _, desired) in taken {
+                if let Some(d) = writable.devices.get_mut(&alias) {
+                    for (property, value) in desired {
+                        d.commits.entry(property).or_insert(value);
+                    }
+                }
+            }
+            return Err(e.into());
+        }
+    };
+    debug!(result =? result, "requset finished");
+    let list = taken
+        .into_iter()
+        .filter_map(|(alias, name, _)| {
+            let device = result.devices.remove(&name)?;
+            Some((alias, device.into()))
+        })
+        .collect();
+    Ok(list)
 }
@@ -17,6 +17,16 @@
         return core.opSync("op_get_device_status", device, property)
     }
 
+    // fetch the current status of readable devices, all of them if none is named
+    async function refresh(...devices) {
+        return await core.opAsync("op_refresh_devices", devices)
+    }
+
+    async function fetchDeviceStatus(device, property) {
+        await refresh(device)
+        return getDeviceStatus(device, property)
+    }
+
     function setDeviceStatus(device, property, value) {
         return core.opSync("op_update_device_desired", {
             "name": device,
@@ -29,11 +39,23 @@
         return await core.opAsync("op_commit_device", device, qos)
     }
 
+    async function commitAll(qos) {
+        return await core.opAsync("op_commit_all", qos)
+    }
+
+    function listTriggers() {
+        return core.opSync("op_list_triggers")
+    }
+
     window.__bootstrap.devices = {
         listReadableDevices,
         listWritableDevices,
         getDeviceStatus,
+        fetchDeviceStatus,
+        refresh,
         setDeviceStatus,
-        commitDevice
+        commitDevice,
+        commitAll,
+        listTriggers
     };
 })(this);
\ No newline at end of file
@@ -4,14 +4,19 @@ pub mod devices;
 pub mod log;
 
 use deno_core::{url::Url, Extension};
-use proto::{server_message::run_script::ReadDevice, QosPolicy};
-use std::collections::HashMap;
+use proto::{
+    server_message::run_script::{ReadDevice, Trigger},
+    QosPolicy,
+};
+use std::collections::{HashMap, HashSet};
 use time::OffsetDateTime;
 
 /// All inmutable information of this rule and session
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub script_id: u32,
+    /// unique id of this run
+    pub run_id: String,
     pub start_time: OffsetDateTime,
     pub name: String,
     pub version: String,
@@ -27,6 +32,26 @@ pub struct ReadableDevices {
 #[derive(Debug)]
 pub struct WritableDevices {
     pub devices: HashMap<String, DeviceSnapshot>,
+    /// commits made by this run, numbers the idempotency keys
+    pub commit_seq: u64,
+}
+
+/// Why the script runs
+#[derive(Debug)]
+pub struct Triggers {
+    pub triggers: Vec<Trigger>,
+}
+
+/// Optional features of the controller the run came from
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    pub capabilities: HashSet<String>,
+}
+
+impl Capabilities {
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
 }
 
 pub struct Envvar {
@@ -1,9 +1,27 @@
 use std::{rc::Rc, vec};
 
 use deno_core::{error::AnyError, include_js_files, op, Extension, OpState};
+use proto::log_batch::Entry;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
 
 use crate::Rule;
 
+/// Console lines buffered before they are sent to the controller
+const LOG_BUFFER: usize = 1024;
+
+/// Console output of the run, shipped to the controller by the worker
+pub struct LogSink {
+    tx: mpsc::Sender<Entry>,
+}
+
+impl LogSink {
+    pub fn new() -> (LogSink, mpsc::Receiver<Entry>) {
+        let (tx, rx) = mpsc::channel(LOG_BUFFER);
+        (LogSink { tx }, rx)
+    }
+}
+
 macro_rules! event {
     ($level:expr, $($args:tt)*) => {{
         use ::tracing::Level;
@@ -37,6 +55,15 @@ pub fn init() -> Extension {
 pub fn op_log(state: &mut OpState, level: u8, msg: String) -> Result<(), AnyError> {
     let rule: &Rc<Rule> = state.borrow();
     let id: u32 = rule.script_id.into();
-    event!(level, msg = %msg, script_id = %id, name = ?rule.name, version = ?rule.version, register = ?rule.register);
+    event!(level, msg = %msg, script_id = %id, run_id = %rule.run_id, name = ?rule.name, version = ?rule.version, register = ?rule.register);
+    if let Some(sink) = state.try_borrow::<LogSink>() {
+        let entry = Entry {
+            time: (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64,
+            level: level as u32,
+            message: msg,
+        };
+        // a full buffer drops the line, it is still in the executor log
+        let _ = sink.tx.try_send(entry);
+    }
     Ok(())
 }
@@ -6,11 +6,20 @@ use anyhow::Result;
 use clap::Parser;
 use deno_executor::{
     loader::{FsLoader, RegisterLoader},
-    worker::{DenoWorker, GlobalOption},
+    worker::{DenoWorker, GlobalOption, Runs},
 };
-use executor::Client;
-use proto::controller_service_client::ControllerServiceClient;
+use executor::{Client, ClientAuth};
+use proto::{
+    capability, client_message::ClientInfo, server_message::run_script::manifest::ScriptType,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tracing::{info, Level};
+
+/// How often a draining executor checks whether its runs finished
+const DRAIN_POLL: Duration = Duration::from_millis(200);
+
 #[derive(Debug, Parser)]
 struct Args {
     /// Set the default register
@@ -20,6 +29,30 @@ struct Args {
         default_value = "/Users/han/Project/rule_engine/config/new_register"
     )]
     register: String,
+    /// Max number of scripts running at the same time
+    #[clap(short, long, default_value = "4")]
+    max_job: u32,
+    /// Name of the node the executor runs on, read from NODE_NAME if not set
+    #[clap(long)]
+    node_name: Option<String>,
+    /// Label of the node as key=value, can be repeated
+    #[clap(long)]
+    node_label: Vec<String>,
+    /// CA certificate of the controller, connect over TLS if set
+    #[clap(long)]
+    tls_ca: Option<PathBuf>,
+    /// Client certificate for mutual TLS
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// Key of the client certificate
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Name in the controller certificate, the host of the server url if not set
+    #[clap(long)]
+    tls_domain: Option<String>,
+    /// Bearer token sent to the controller, e.g. /var/run/secrets/kubernetes.io/serviceaccount/token
+    #[clap(long)]
+    token_file: Option<PathBuf>,
     server: String,
 }
 
@@ -47,18 +80,85 @@ async fn main() -> Result<()> {
         module_loader: RegisterLoader::new(),
     };
     let url = args.server;
+    let node_name = args
+        .node_name
+        .or_else(|| std::env::var("NODE_NAME").ok())
+        .unwrap_or_default();
+    let mut node_labels = HashMap::new();
+    for label in args.node_label {
+        match label.split_once('=') {
+            Some((k, v)) => node_labels.insert(k.to_owned(), v.to_owned()),
+            None => anyhow::bail!("Node label {} is not key=value", label),
+        };
+    }
+    let info = ClientInfo {
+        max_job: args.max_job,
+        script_types: vec![ScriptType::Js as i32],
+        capabilities: vec![capability::CANCEL.to_owned()],
+        runtimes: HashMap::from([
+            ("v8".to_owned(), deno_core::v8_version().to_owned()),
+            (
+                "deno_executor".to_owned(),
+                env!("CARGO_PKG_VERSION").to_owned(),
+            ),
+        ]),
+        node_name,
+        node_labels,
+        paused: false,
+    };
+    let auth = ClientAuth {
+        ca: args.tls_ca,
+        identity: args.tls_cert.zip(args.tls_key),
+        domain: args.tls_domain,
+        token_file: args.token_file,
+    };
     let Client {
         client,
         tasks,
         id,
         rx,
-    } = Client::try_connect(url.clone()).await.unwrap();
+        cancels,
+        credits,
+        server,
+        drain,
+    } = Client::try_connect(url, info, &auth).await.unwrap();
+    let runs = Runs::default();
+    // a rolling restart sends SIGTERM, the executor exits once its runs finished
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut drain_poll = tokio::time::interval(DRAIN_POLL);
     loop {
-        let run = rx.recv_async().await.unwrap();
+        // a run is received before it can be cancelled, so runs are taken first
+        let run = tokio::select! {
+            biased;
+            run = rx.recv_async() => run.unwrap(),
+            cancel = cancels.recv_async() => {
+                let cancel = cancel.unwrap();
+                runs.cancel(cancel.script_id, cancel.reason);
+                continue;
+            }
+            _ = terminate.recv(), if !drain.is_draining() => {
+                info!(running = credits.running(), "Terminated, drain before exit");
+                drain.start();
+                continue;
+            }
+            _ = drain_poll.tick(), if drain.is_draining() => {
+                if drain.confirmed() && credits.running() == 0 && rx.is_empty() {
+                    info!("Drained, exit");
+                    break;
+                }
+                continue;
+            }
+        };
         let global = global_option.clone();
-        let url = url.clone();
+        let client = client.clone();
+        let credits = credits.clone();
+        let runs = runs.clone();
+        let capabilities = server.snapshot();
+        let script_id = run.script_id;
+        runs.received(script_id);
         info!("New script to run: {:?}", run.manifest);
         thread::spawn(move || {
+            let _guard = runs.guard(script_id, credits);
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
                 .thread_name(format!(
@@ -68,9 +168,10 @@ async fn main() -> Result<()> {
                 .build()
                 .unwrap();
             rt.block_on(async move {
-                let client = ControllerServiceClient::connect(url).await.unwrap();
-                let worker = DenoWorker::new(run, global, client);
-                worker.run().await;
+                let mut worker = DenoWorker::new(run, global, client, capabilities);
+                let isolate = worker.rt.v8_isolate().thread_safe_handle();
+                let cancel = runs.start(script_id, isolate);
+                worker.run(cancel).await;
             });
         });
     }
@@ -1,27 +1,136 @@
 use anyhow::anyhow;
 use anyhow::Result;
-use deno_core::{located_script_name, JsRuntime, ModuleLoader, RuntimeOptions, Snapshot};
+use deno_core::v8::IsolateHandle;
+use deno_core::{
+    located_script_name, serde_v8, v8, JsRuntime, ModuleLoader, RuntimeOptions, Snapshot,
+};
+use executor::Credits;
 use executor_ops as ops;
 use prost_types::{Duration, Timestamp};
 use proto::{
-    controller_service_client::ControllerServiceClient,
+    capability,
+    log_batch::Entry,
     script_status::ScriptStatusCode,
     server_message::{run_script::ReadDevice, RunScript},
-    QosPolicy, ScriptStatus,
+    ControllerClient, LogBatch, QosPolicy, ScriptStatus, MAX_OUTPUT,
 };
 use reqwest::{Client, ClientBuilder};
-use std::{collections::HashMap, rc::Rc};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    rc::Rc,
+};
 use time::OffsetDateTime;
-use tonic::transport::Channel;
+use tokio::sync::{mpsc, oneshot};
 use tracing::warn;
 use tracing::{error, info};
 
 pub static SNAPSHOT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/SNAPSHOT.bin"));
+/// Console lines sent in one batch
+const LOG_BATCH: usize = 100;
+/// How long a console line may wait for more lines before it is sent
+const LOG_FLUSH: std::time::Duration = std::time::Duration::from_millis(200);
 
 pub struct DenoWorker {
     pub rt: JsRuntime,
+    /// console output, taken by the log shipper when the run starts
+    logs: Option<mpsc::Receiver<Entry>>,
+}
+
+/// Runs of this executor, a run is cancelled by terminating its isolate
+#[derive(Clone, Default)]
+pub struct Runs {
+    runs: Arc<Mutex<HashMap<u32, RunHandle>>>,
+}
+
+enum RunHandle {
+    /// received, the worker is not started yet
+    Pending(Option<String>),
+    Running(IsolateHandle, Option<oneshot::Sender<String>>),
+}
+
+impl Runs {
+    /// Track a received run, so it can be cancelled before its worker starts
+    pub fn received(&self, script_id: u32) {
+        self.lock().insert(script_id, RunHandle::Pending(None));
+    }
+
+    /// Track the isolate of a started run, the receiver gets the reason of a cancellation
+    pub fn start(&self, script_id: u32, isolate: IsolateHandle) -> oneshot::Receiver<String> {
+        let (tx, rx) = oneshot::channel();
+        let mut runs = self.lock();
+        match runs.remove(&script_id) {
+            Some(RunHandle::Pending(Some(reason))) => {
+                let _ = tx.send(reason);
+                isolate.terminate_execution();
+            }
+            _ => {
+                runs.insert(script_id, RunHandle::Running(isolate, Some(tx)));
+            }
+        }
+        rx
+    }
+
+    /// Stop a run, unknown or finished runs are ignored
+    pub fn cancel(&self, script_id: u32, reason: String) {
+        match self.lock().get_mut(&script_id) {
+            Some(RunHandle::Pending(cancelled)) => *cancelled = Some(reason),
+            Some(RunHandle::Running(isolate, cancel)) => {
+                // the reason is sent first, the worker reads it when the isolate stops
+                if let Some(cancel) = cancel.take() {
+                    let _ = cancel.send(reason);
+                }
+                isolate.terminate_execution();
+            }
+            None => warn!(script_id, "Cancel an unknown run"),
+        }
+    }
+
+    pub fn finish(&self, script_id: u32) {
+        self.lock().remove(&script_id);
+    }
+
+    /// Finish the run and return its slot when the guard is dropped, even if the worker panics
+    pub fn guard(&self, script_id: u32, credits: Credits) -> RunGuard {
+        RunGuard {
+            runs: self.clone(),
+            credits,
+            script_id,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u32, RunHandle>> {
+        self.runs.lock().expect("Runs poisoned")
+    }
+}
+
+/// Bookkeeping of a run on its worker thread
+pub struct RunGuard {
+    runs: Runs,
+    credits: Credits,
+    script_id: u32,
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        self.runs.finish(self.script_id);
+        self.credits.release();
+    }
+}
+
+/// A run stopped by the controller
+#[derive(Debug)]
+struct Cancelled(String);
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled: {}", self.0)
+    }
 }
 
+impl std::error::Error for Cancelled {}
+
 #[derive(Clone)]
 pub struct GlobalOption<M: ModuleLoader> {
     pub default_register: String,
@@ -32,7 +141,8 @@ impl DenoWorker {
     pub fn new<M: ModuleLoader + 'static>(
         run: RunScript,
         global: GlobalOption<M>,
-        client: ControllerServiceClient<Channel>,
+        client: ControllerClient,
+        capabilities: HashSet<String>,
     ) -> DenoWorker {
         let GlobalOption {
             default_register,
@@ -46,6 +156,7 @@ impl DenoWorker {
         }
         let state = Rc::new(ops::Rule {
             script_id: run.script_id,
+            run_id: run.run_id,
             start_time: OffsetDateTime::now_utc(),
             name: manifest.package_name,
             version: manifest.package_version,
@@ -66,9 +177,23 @@ impl DenoWorker {
                     },
                 );
             }
-            ops::WritableDevices { devices }
+            ops::WritableDevices {
+                devices,
+                commit_seq: 0,
+            }
         };
         let envvar = ops::Envvar { env: run.env };
+        let capabilities = ops::Capabilities { capabilities };
+        // console output stays in the executor log if the controller can't take it
+        let (log_sink, logs) = if capabilities.has(capability::LOGS) {
+            let (log_sink, logs) = ops::log::LogSink::new();
+            (Some(log_sink), Some(logs))
+        } else {
+            (None, None)
+        };
+        let triggers = ops::Triggers {
+            triggers: run.triggers,
+        };
         let http_client = ClientBuilder::new()
             .gzip(true)
             .brotli(true)
@@ -88,24 +213,74 @@ impl DenoWorker {
         op_state.put(readable);
         op_state.put(writeable);
         op_state.put(envvar);
+        op_state.put(triggers);
         op_state.put(client);
         op_state.put(http_client);
-        DenoWorker { rt }
+        op_state.put(capabilities);
+        if let Some(log_sink) = log_sink {
+            op_state.put(log_sink);
+        }
+        DenoWorker { rt, logs }
     }
 
-    pub async fn run(mut self) {
-        let res = self.run_inner().await;
+    /// Run the script, it is stopped when `cancel` receives a reason
+    pub async fn run(mut self, mut cancel: oneshot::Receiver<String>) {
+        let shipper = {
+            let op_state = self.rt.op_state();
+            let op_state = op_state.borrow();
+            let state: &Rc<ops::Rule> = op_state.borrow();
+            let client: &ControllerClient = op_state.borrow();
+            self.logs.take().map(|logs| {
+                tokio::spawn(ship_logs(
+                    client.clone(),
+                    state.script_id,
+                    state.run_id.clone(),
+                    logs,
+                ))
+            })
+        };
+        let res = tokio::select! {
+            res = self.run_inner() => res.map_err(|e| match cancel.try_recv() {
+                // the isolate was terminated
+                Ok(reason) => Cancelled(reason).into(),
+                Err(_) => e,
+            }),
+            Ok(reason) = &mut cancel => Err(Cancelled(reason).into()),
+        };
         let op_state = self.rt.op_state();
         let mut op_state = op_state.borrow_mut();
+        // the output is complete before the status is reported
+        op_state.try_take::<ops::log::LogSink>();
+        if let Some(shipper) = shipper {
+            let _ = shipper.await;
+        }
         let state: &Rc<ops::Rule> = op_state.borrow();
-        let (code, message) = if let Err(e) = res {
-            error!(
-                "Script {}({}) crashed: {:?}",
-                state.name, state.script_id, e
-            );
-            (ScriptStatusCode::Crash, format!("{:?}", e))
-        } else {
-            (ScriptStatusCode::Ok, String::new())
+        let (code, message, output) = match res.map_err(|e| e.downcast::<Cancelled>()) {
+            Ok(None) => (ScriptStatusCode::Ok, String::new(), String::new()),
+            Ok(Some(output)) if output.len() > MAX_OUTPUT => {
+                warn!(
+                    len = output.len(),
+                    "Script's main() returned a value too large to report"
+                );
+                let message = format!(
+                    "Output of {} bytes is dropped, the limit is {} bytes",
+                    output.len(),
+                    MAX_OUTPUT
+                );
+                (ScriptStatusCode::Ok, message, String::new())
+            }
+            Ok(Some(output)) => (ScriptStatusCode::Ok, String::new(), output),
+            Err(Ok(Cancelled(reason))) => {
+                info!(reason = %reason, "Script {}({}) cancelled", state.name, state.script_id);
+                (ScriptStatusCode::Cancelled, reason, String::new())
+            }
+            Err(Err(e)) => {
+                error!(
+                    "Script {}({}) crashed: {:?}",
+                    state.name, state.script_id, e
+                );
+                (ScriptStatusCode::Crash, format!("{:?}", e), String::new())
+            }
         };
         let start = Some(Timestamp {
             seconds: state.start_time.unix_timestamp(),
@@ -123,15 +298,18 @@ impl DenoWorker {
             duration,
             code: code as i32,
             message,
+            run_id: state.run_id.clone(),
+            output,
         };
-        let client: &mut ControllerServiceClient<Channel> = op_state.borrow_mut();
+        let client: &mut ControllerClient = op_state.borrow_mut();
         if let Err(e) = client.update_script_status(request.clone()).await {
             error!(error =? e, "Failed to update script status");
         }
         info!(status =? request, "Script exit");
     }
 
-    async fn run_inner(&mut self) -> Result<()> {
+    /// Returns the value `main()` resolved to as JSON text, if any
+    async fn run_inner(&mut self) -> Result<Option<String>> {
         self.bootstrap();
         let res = {
             let op_state = self.rt.op_state();
@@ -151,12 +329,19 @@ impl DenoWorker {
         self.rt.execute_script(&located_script_name!(), &code)?;
         let result = self.rt.execute_script(&located_script_name!(), "main()")?;
         let result = self.rt.resolve_value(result).await?;
-        let result = result.open(self.rt.v8_isolate());
-        if !result.is_null_or_undefined() {
-            let res = result.to_rust_string_lossy(&mut self.rt.handle_scope());
-            warn!("Script's main() return a value which is ignored: {:?}", res)
+        let scope = &mut self.rt.handle_scope();
+        let result = v8::Local::new(scope, result);
+        if result.is_null_or_undefined() {
+            return Ok(None);
+        }
+        match serde_v8::from_v8::<serde_json::Value>(scope, result) {
+            Ok(value) => Ok(Some(value.to_string())),
+            Err(e) => {
+                let res = result.to_rust_string_lossy(scope);
+                warn!(error = %e, "Script's main() returned a value which isn't JSON: {:?}", res);
+                Ok(None)
+            }
         }
-        Ok(())
     }
 
     // TODO: use this when add back module import support
@@ -208,6 +393,42 @@ impl DenoWorker {
     }
 }
 
+/// Send the console output of a run to the controller in batches,
+/// returns when the `LogSink` of the run is dropped
+async fn ship_logs(
+    mut client: ControllerClient,
+    script_id: u32,
+    run_id: String,
+    logs: mpsc::Receiver<Entry>,
+) {
+    let batches = futures::stream::unfold(logs, move |mut logs| {
+        let run_id = run_id.clone();
+        async move {
+            let mut entries = vec![logs.recv().await?];
+            let flush = tokio::time::sleep(LOG_FLUSH);
+            tokio::pin!(flush);
+            while entries.len() < LOG_BATCH {
+                tokio::select! {
+                    entry = logs.recv() => match entry {
+                        Some(entry) => entries.push(entry),
+                        None => break,
+                    },
+                    _ = &mut flush => break,
+                }
+            }
+            let batch = LogBatch {
+                script_id,
+                run_id,
+                entries,
+            };
+            Some((batch, logs))
+        }
+    });
+    if let Err(e) = client.push_logs(batches).await {
+        warn!(error =? e, script_id, "Failed to send console output");
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -254,6 +475,8 @@ mod test {
             writable: HashMap::new(),
             env: HashMap::new(),
             default_qos: 0,
+            triggers: Vec::new(),
+            run_id: String::new(),
         }
     }
 
@@ -279,6 +502,7 @@ mod test {
 
         let rule = ops::Rule {
             script_id: 0,
+            run_id: String::new(),
             start_time: OffsetDateTime::now_utc(),
             name: "my_script".to_string(),
             version: "0.1_beta1".to_string(),
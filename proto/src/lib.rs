@@ -1,3 +1,71 @@
 #![allow(non_camel_case_types)]
 
 tonic::include_proto!("rule");
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use tonic::service::{interceptor::InterceptedService, Interceptor};
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+/// Version of the controller-executor protocol.
+/// The major version changes with incompatible changes, the minor version with additions
+/// that are announced as capabilities.
+pub const PROTOCOL_VERSION: &str = "1.3.0";
+
+/// Largest `ScriptStatus.output` in bytes, larger outputs are dropped
+pub const MAX_OUTPUT: usize = 32 * 1024;
+
+/// Optional features announced in `ClientInfo.capabilities` and `Connected.capabilities`
+pub mod capability {
+    /// executor answers heartbeats
+    pub const HEARTBEAT: &str = "heartbeat";
+    /// executor stops runs on `CancelScript`
+    pub const CANCEL: &str = "cancel";
+    /// controller accepts console output with `push_logs`
+    pub const LOGS: &str = "logs";
+    /// controller serves `get_device_status`
+    pub const DEVICE_STATUS: &str = "device-status";
+    /// controller confirms AtLeastOnce and OnlyOnce device writes
+    pub const DEVICE_QOS: &str = "device-qos";
+    /// controller serves `update_devices_desired`
+    pub const DEVICE_BATCH: &str = "device-batch";
+    /// controller takes `ClientCode::Drain`
+    pub const DRAIN: &str = "drain";
+    /// executor takes `ServerMessage.Pause`
+    pub const PAUSE: &str = "pause";
+}
+
+/// Client of the controller, requests carry the executor's bearer token
+pub type ControllerClient =
+    controller_service_client::ControllerServiceClient<InterceptedService<Channel, BearerToken>>;
+
+/// Sends the token in `file` as `authorization: Bearer` on every request.
+/// The file is read each time, so rotated ServiceAccount tokens are picked up.
+#[derive(Debug, Clone, Default)]
+pub struct BearerToken {
+    file: Option<Arc<PathBuf>>,
+}
+
+impl BearerToken {
+    pub fn new(file: Option<PathBuf>) -> Self {
+        BearerToken {
+            file: file.map(Arc::new),
+        }
+    }
+}
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(file) = &self.file {
+            let token = std::fs::read_to_string(file.as_ref()).map_err(|e| {
+                Status::unauthenticated(format!("Failed to read token {}: {}", file.display(), e))
+            })?;
+            let value = format!("Bearer {}", token.trim())
+                .parse()
+                .map_err(|_| Status::unauthenticated("Token is not a valid header value"))?;
+            request.metadata_mut().insert("authorization", value);
+        }
+        Ok(request)
+    }
+}